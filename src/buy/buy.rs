@@ -1,10 +1,8 @@
 use super::raydium_sdk;
 use super::utils;
 use super::service;
-use solana_client::nonblocking::rpc_client::RpcClient;
 use raydium_sdk::LiquidityPoolKeys;
 use solana_sdk::instruction::Instruction;
-use solana_sdk::signature::Keypair;
 use std::sync::Arc;
 use spl_token_client::token::Token;
 use spl_token_client::client::{ ProgramClient, ProgramRpcClient, ProgramRpcClientSendTransaction };
@@ -12,58 +10,52 @@ use solana_sdk::signature::Signer;
 use raydium_contract_instructions::amm_instruction as amm;
 use solana_client::rpc_config::RpcSendTransactionConfig;
 use solana_sdk::program_error::ProgramError;
-use utils::get_or_create_ata_for_token_in_and_out_with_balance;
 use helius::types::*;
-use helius::Helius;
-use service::save_buy_details;
+use crate::context::AppContext;
+use crate::wallet::Wallet;
+use crate::paper;
+use crate::simulation::{ self, SimulationError };
+use service::{ save_buy_details, store_buy, BuyFill };
 use service::TokenVaults;
-use std::str::FromStr;
-use solana_sdk::pubkey::Pubkey;
 use solana_client::client_error::ClientError;
-use std::time::Duration;
-use spl_associated_token_account::instruction;
+use spl_associated_token_account::instruction::create_associated_token_account_idempotent;
 
 #[derive(Debug, thiserror::Error)]
 pub enum SwapError {
     #[error("Transaction error: {0}")] TransactionError(String),
     #[error("Token error: {0}")] TokenError(String),
+    #[error("Quote error: {0}")] QuoteError(String),
     #[error("Invalid transaction data")]
     InvalidTransactionData,
     #[error("Program error: {0}")] ProgramError(#[from] ProgramError),
     #[error("Client error: {0}")] ClientError(#[from] ClientError),
+    #[error("{0}")] Simulation(#[from] SimulationError),
 }
 
+/// Default for `trading.buy_slippage`, in percent
+pub const DEFAULT_BUY_SLIPPAGE: f64 = 30.0;
+
 pub async fn buy_swap(
+    ctx: &AppContext,
+    wallet: &Wallet,
     key_z: LiquidityPoolKeys,
     lp_decimals: u8,
     sol_amount: f64,
+    slippage: f64,
     group_title: String,
     user_name: String
 ) -> Result<String, SwapError> {
-    let api_key: String = std::env
-        ::var("HELIUS_API_KEY")
-        .expect("You must set the HELIUS_API_KEY environment variable!");
-    let cluster: Cluster = Cluster::MainnetBeta;
-    let helius: Helius = Helius::new(&api_key, cluster).unwrap();
-
-    let private_key = std::env
-        ::var("PRIVATE_KEY")
-        .expect("You must set the PRIVATE_KEY environment variable!");
-    let keypair = Keypair::from_base58_string(&private_key);
+    let helius = &ctx.helius;
 
-    let user = Pubkey::from_str("4VZscJb8n2iiV4VyUSeaXPFw53mqqwH55BX6JRFaveia").unwrap();
-
-    let rpc_endpoint = std::env
-        ::var("RPC_URL")
-        .expect("You must set the RPC_URL environment variable!");
-    let client: Arc<RpcClient> = Arc::new(RpcClient::new(rpc_endpoint.to_string()));
+    let client = ctx.rpc_client.clone();
 
     let program_client: Arc<dyn ProgramClient<ProgramRpcClientSendTransaction>> = Arc::new(
         ProgramRpcClient::new(client.clone(), ProgramRpcClientSendTransaction)
     );
     dbg!("Køber nu");
 
-    let keypair_arc = Arc::new(keypair);
+    let keypair_arc = wallet.keypair.clone();
+    let user = keypair_arc.pubkey();
 
     let amount_in: u64 = (sol_amount * 1_000_000_000.0) as u64;
 
@@ -96,152 +88,31 @@ pub async fn buy_swap(
         keypair_arc.clone()
     );
 
-    // Get the user's ATA or create it if it does not exist
     let user_in_token_account = token_in.get_associated_token_address(&user);
+    let user_out_token_account = token_out.get_associated_token_address(&user);
     dbg!("User input-tokens ATA={}", user_in_token_account);
 
-    // Check if the user's token account exists
-    let user_in_acct = match token_in.get_account_info(&user_in_token_account).await {
-        Ok(account_info) => account_info,
-        Err(_) => {
-            // Create the user's token account if it does not exist
-            dbg!("Creating user's input-tokens ATA");
-            let create_account_instruction = instruction::create_associated_token_account(
-                &keypair_arc.pubkey(),
-                &user,
-                &token_in_mint,
-                &spl_token::id()
-            );
-
-            let config = SmartTransactionConfig {
-                create_config: CreateSmartTransactionConfig {
-                    instructions: [create_account_instruction].to_vec(),
-                    signers: vec![&keypair_arc],
-                    lookup_tables: None,
-                    fee_payer: None,
-                },
-                send_options: RpcSendTransactionConfig {
-                    skip_preflight: true,
-                    preflight_commitment: None,
-                    encoding: None,
-                    max_retries: None,
-                    min_context_slot: None,
-                },
-            };
-
-            match helius.send_smart_transaction_with_tip(config, Some(600000), Some("NY")).await {
-                Ok(signature) => {
-                    dbg!("Transaction sent successfully: {}", signature);
-                    tokio::time::sleep(Duration::from_secs(10)).await;
-                    // Retry fetching the user's token account info
-                    token_in
-                        .get_account_info(&user_in_token_account).await
-                        .map_err(|err| {
-                            SwapError::TransactionError(
-                                format!("Failed to fetch user's input-tokens ATA after creation: {}", err)
-                            )
-                        })?
-                }
-                Err(e) => {
-                    return Err(SwapError::TransactionError(e.to_string()));
-                }
-            }
-        }
-    };
-
-    // TODO: If input tokens is the native mint(wSOL) and the balance is inadequate, attempt to
-    // convert SOL to wSOL.
-    let balance = user_in_acct.base.amount;
-    dbg!("User input-tokens ATA balance={}", balance);
-    if token_in.is_native() && balance < amount_in {
-        let transfer_amt = amount_in - balance;
-
-        let transfer_instruction = solana_sdk::system_instruction::transfer(
-            &user,
-            &user_in_token_account,
-            transfer_amt
-        );
-        let sync_instruction = spl_token::instruction::sync_native(
-            &spl_token::ID,
-            &user_in_token_account
-        )?;
-
-        let intructions_set = vec![transfer_instruction, sync_instruction];
-        // Create the SmartTransactionConfig
-        let config = SmartTransactionConfig {
-            create_config: CreateSmartTransactionConfig {
-                instructions: intructions_set,
-                signers: vec![&keypair_arc],
-                lookup_tables: None,
-                fee_payer: None,
-            },
-            send_options: RpcSendTransactionConfig {
-                skip_preflight: true,
-                preflight_commitment: None,
-                encoding: None,
-                max_retries: None,
-                min_context_slot: None,
-            },
-        };
-
-        match helius.send_smart_transaction_with_tip(config, Some(600000), Some("NY")).await {
-            Ok(signature) => {
-                dbg!("Transaction sent successfully: {}", signature);
-            }
-            Err(e) => {
-                return Err(SwapError::TransactionError(e.to_string()));
-            }
-        }
-    }
-
-    let mut instructions: Vec<Instruction> = vec![];
-    let ata_creation_bundle = get_or_create_ata_for_token_in_and_out_with_balance(
-        &token_in,
-        &token_out,
-        keypair_arc.clone()
-    ).await.unwrap();
+    // The wallet's wSOL account is shared and a sell or the sweeper may close it at any time,
+    // so the swap transaction creates it if needed and wraps the full amount itself instead of
+    // relying on an earlier wrap or on wSOL already in the account
+    let setup_instructions: Vec<Instruction> = vec![
+        create_associated_token_account_idempotent(&user, &user, &token_in_mint, &spl_token::ID),
+        create_associated_token_account_idempotent(&user, &user, &token_out_mint, &spl_token::ID),
+        solana_sdk::system_instruction::transfer(&user, &user_in_token_account, amount_in),
+        spl_token::instruction::sync_native(&spl_token::ID, &user_in_token_account)?
+    ];
 
-    //Create input ATAs if instruction exist
-    if ata_creation_bundle.token_in.instruction.is_some() {
-        instructions.push(ata_creation_bundle.token_in.instruction.unwrap());
-    }
-    if ata_creation_bundle.token_out.instruction.is_some() {
-        instructions.push(ata_creation_bundle.token_out.instruction.unwrap());
-    }
-
-    // Send some sol from account to the ata and then call sync native
-    if token_in.is_native() && ata_creation_bundle.token_in.balance < amount_in {
-        let transfer_amount = amount_in - ata_creation_bundle.token_in.balance;
-        let transfer_instruction = solana_sdk::system_instruction::transfer(
-            &keypair_arc.pubkey().clone(),
-            &ata_creation_bundle.token_in.ata_pubkey,
-            transfer_amount
-        );
-        let sync_instruction = spl_token::instruction::sync_native(
-            &spl_token::ID,
-            &ata_creation_bundle.token_in.ata_pubkey
-        )?;
-        instructions.push(transfer_instruction);
-        instructions.push(sync_instruction);
-    } else {
-        // An SPL token is an input. If the ATA token address does not exist, it means that the balance is definitely 0.
-        if ata_creation_bundle.token_in.balance < amount_in {
-            dbg!("Input token not native. Checking sufficient balance");
-            return Err(
-                SwapError::TokenError("Insufficient balance in input token account".to_string())
-            );
-        }
-    }
-
-    let mut slippage = 30.0;
+    let mut slippage = slippage;
 
     let mut retries = 0;
-    let max_retries = 3;
+    let max_retries = ctx.config.trading.max_retries;
 
     loop {
         // Calculate amount out
-        let (_amount_out, minimum_amount_out) = utils::get_out_amount(
-            &token_out_mint.to_string(),
+        let (amount_out, minimum_amount_out) = utils::get_swap_quote(
+            &client,
+            &key_z.id,
+            &token_in_mint,
             amount_in,
             &slippage
         ).await?;
@@ -268,12 +139,13 @@ pub async fn buy_swap(
             &key_z.market_base_vault,
             &key_z.market_quote_vault,
             &key_z.market_authority,
-            &ata_creation_bundle.token_in.ata_pubkey,
-            &ata_creation_bundle.token_out.ata_pubkey,
+            &user_in_token_account,
+            &user_out_token_account,
             &keypair_arc.pubkey().clone(),
             amount_in,
             minimum_amount_out as u64
         )?;
+        let mut instructions = setup_instructions.clone();
         instructions.push(swap_instruction);
 
         let mut token_vaults = TokenVaults {
@@ -293,6 +165,36 @@ pub async fn buy_swap(
             };
         }
 
+        if ctx.config.paper.enabled {
+            let signature = paper
+                ::simulate(ctx, wallet, &instructions).await
+                .map_err(|e| SwapError::TransactionError(e.to_string()))?;
+            let fill = BuyFill {
+                amount: amount_out.to_string(),
+                sol_amount,
+                network_fee: paper::network_fee(ctx),
+            };
+
+            store_buy(
+                ctx,
+                wallet,
+                &signature,
+                fill,
+                lp_decimals,
+                &token_out_mint.to_string(),
+                token_vaults,
+                false,
+                group_title,
+                user_name
+            ).await.map_err(|e| SwapError::TransactionError(e.to_string()))?;
+
+            return Ok(signature.to_string());
+        }
+
+        if ctx.config.trading.simulate_before_send {
+            simulation::preflight(ctx, wallet, &instructions).await?;
+        }
+
         // Create the SmartTransactionConfig
         let config = SmartTransactionConfig {
             create_config: CreateSmartTransactionConfig {
@@ -310,11 +212,18 @@ pub async fn buy_swap(
             },
         };
 
-        match helius.send_smart_transaction_with_tip(config, Some(600000), Some("NY")).await {
+        match
+            helius.send_smart_transaction_with_tip(
+                config,
+                Some(ctx.config.jito.tip_lamports),
+                Some(ctx.config.jito.region())
+            ).await
+        {
             Ok(signature) => {
                 dbg!("Transaction sent successfully: {}", signature);
                 let saved_details = save_buy_details(
-                    client.clone(),
+                    ctx,
+                    wallet,
                     &signature,
                     lp_decimals,
                     &token_out_mint.to_string(),
@@ -347,13 +256,12 @@ pub async fn buy_swap(
                         );
                         tokio::time::sleep(tokio::time::Duration::from_secs(15)).await;
                         // Increase slippage and rebuild the transaction if necessary
-                        let user_out_token_account = token_out.get_associated_token_address(&user);
                         let token_balance = client
                             .get_balance(&user_out_token_account).await
                             .unwrap();
 
                         if token_balance == 0 {
-                            slippage = 8.0;
+                            slippage = ctx.config.trading.buy_compute_retry_slippage;
                             continue;
                         } else {
                             return Err(SwapError::TransactionError(e.to_string()));
@@ -370,7 +278,7 @@ pub async fn buy_swap(
                             max_retries
                         );
                         // Increase slippage and rebuild the transaction if necessary
-                        slippage = 5.0;
+                        slippage = ctx.config.trading.buy_retry_slippage;
 
                         continue;
                     } else {
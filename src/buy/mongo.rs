@@ -1,11 +1,18 @@
-use mongodb::{ Client, options::ClientOptions, bson::doc, bson::Document, Collection };
+use mongodb::{ Client, bson::doc, bson::Document, Collection };
+use crate::config::MongoConfig;
+use crate::wallet::Wallet;
 use mongodb::error::Error as MongoError;
 use serde::Serialize;
 use serde::Deserialize;
 use mongodb::bson::DateTime;
+use futures::stream::TryStreamExt;
 
 pub struct MongoHandler {
     client: Client,
+    state_db: String,
+    wallet: String,
+    owns_legacy: bool,
+    paper: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -41,6 +48,11 @@ pub struct BuyTransaction {
     pub fee_sol: f64,
     pub fee_usd: f64,
     pub token_metadata: TokenMetadata,
+    #[serde(default)]
+    pub wallet: String,
+    /// Simulated fill recorded in paper trading mode
+    #[serde(default)]
+    pub paper: bool,
     pub created_at: DateTime,
 }
 
@@ -87,25 +99,40 @@ pub struct TradeState {
     pub taken_out: f64,
     pub remaining: f64,
     pub token_metadata: Option<TokenMetadata>,
+    #[serde(default)]
+    pub wallet: String,
+    #[serde(default)]
+    pub paper: bool,
     pub created_at: DateTime,
 }
 
 impl MongoHandler {
-    pub async fn new() -> Result<Self, MongoError> {
-        // Load the MongoDB connection string from an environment variable
-        let client_uri = std::env
-            ::var("MONGODB_URI")
-            .expect("You must set the MONGODB_URI environment variable!");
-
-        // Parse the client options
-        let options = ClientOptions::parse(&client_uri).await?;
-        let client = Client::with_options(options)?;
+    pub fn new(client: Client, config: &MongoConfig, wallet: &Wallet) -> Self {
+        Self {
+            client,
+            state_db: config.state_db.clone(),
+            wallet: wallet.address(),
+            owns_legacy: wallet.is_default,
+            paper: config.paper,
+        }
+    }
 
-        Ok(Self { client })
+    /// Restricts `filter` to documents owned by this handler's wallet.
+    fn scoped(&self, mut filter: Document) -> Document {
+        if self.owns_legacy {
+            // Documents written before wallets were tracked belong to the default wallet
+            filter.insert(
+                "$or",
+                vec![doc! { "wallet": &self.wallet }, doc! { "wallet": { "$exists": false } }]
+            );
+        } else {
+            filter.insert("wallet", &self.wallet);
+        }
+        filter
     }
 
     pub async fn create_trade_state(&self, trade_state: &TradeState) -> Result<(), MongoError> {
-        let db = self.client.database("trading"); // Replace with your database name
+        let db = self.client.database(&self.state_db);
         let collection: Collection<Document> = db.collection("trade_states"); // Replace with your collection name
 
         // Convert TradeState to BSON document
@@ -131,7 +158,7 @@ impl MongoHandler {
         let db = self.client.database(db_name);
         let collection = db.collection::<Document>(collection_name);
 
-        let filter = doc! { "token_metadata.mint": &token_metadata.mint };
+        let filter = self.scoped(doc! { "token_metadata.mint": &token_metadata.mint });
         let existing_document = collection.find_one(filter, None).await?;
 
         if existing_document.is_none() {
@@ -142,6 +169,8 @@ impl MongoHandler {
             let document =
                 doc! {
                 "sold": false,
+                "wallet": &self.wallet,
+                "paper": self.paper,
                 "created_at": DateTime::now(),
                 "token_metadata": {
                     "name": &token_metadata.name,
@@ -176,6 +205,8 @@ impl MongoHandler {
                 token_metadata: Some(token_metadata),
                 taken_out: 0.0,
                 remaining: 0.0,
+                wallet: self.wallet.clone(),
+                paper: self.paper,
                 created_at: DateTime::now(),
             };
 
@@ -201,4 +232,75 @@ impl MongoHandler {
 
         Ok(())
     }
+
+    pub async fn get_buy_transaction_by_signature(
+        &self,
+        signature: &str,
+        db_name: &str,
+        collection_name: &str
+    ) -> Result<Option<BuyTransaction>, MongoError> {
+        let db = self.client.database(db_name);
+        let collection: Collection<Document> = db.collection(collection_name);
+
+        let filter = doc! {
+            "transaction_signature": signature
+        };
+
+        match collection.find_one(filter, None).await? {
+            Some(doc) => Ok(Some(bson::from_document(doc)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Mints of every `tokens` document that hasn't been marked as sold.
+    pub async fn get_unsold_token_mints(
+        &self,
+        db_name: &str,
+        collection_name: &str
+    ) -> Result<Vec<String>, MongoError> {
+        let db = self.client.database(db_name);
+        let collection: Collection<Document> = db.collection(collection_name);
+
+        let filter = self.scoped(doc! {
+            "sold": { "$ne": true }
+        });
+
+        let documents: Vec<Document> = collection.find(filter, None).await?.try_collect().await?;
+
+        Ok(
+            documents
+                .iter()
+                .filter_map(|document| document.get_document("token_metadata").ok())
+                .filter_map(|metadata| metadata.get_str("mint").ok())
+                .map(|mint| mint.to_string())
+                .collect()
+        )
+    }
+
+    pub async fn has_buy_transaction(
+        &self,
+        mint: &str,
+        db_name: &str,
+        collection_name: &str
+    ) -> Result<bool, MongoError> {
+        let db = self.client.database(db_name);
+        let collection: Collection<Document> = db.collection(collection_name);
+
+        let filter = self.scoped(doc! {
+            "token_info.base_mint": mint
+        });
+
+        Ok(collection.find_one(filter, None).await?.is_some())
+    }
+
+    pub async fn has_trade_state(&self, mint: &str) -> Result<bool, MongoError> {
+        let db = self.client.database(&self.state_db);
+        let collection: Collection<Document> = db.collection("trade_states");
+
+        let filter = self.scoped(doc! {
+            "token_mint": mint
+        });
+
+        Ok(collection.find_one(filter, None).await?.is_some())
+    }
 }
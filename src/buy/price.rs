@@ -1,9 +1,23 @@
 use std::error::Error;
+use std::str::FromStr;
 use solana_sdk::pubkey::Pubkey;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use solana_sdk::commitment_config::CommitmentConfig;
+use spl_associated_token_account::get_associated_token_address;
 use borsh::{ BorshDeserialize, BorshSerialize };
 use serde::{ Deserialize, Serialize };
 use super::utils::{ pubkey_to_string, string_to_pubkey };
+use crate::confirmation::TradeFailure;
 
+const PUMP_FUN_PROGRAM: &str = "6EF8rrecthR5Dkzon8Nwu78hRvfCKubJ14M5uBEwF6P";
+/// Fee the pump.fun global account charges on trades
+pub const PUMP_FEE_BASIS_POINTS: u64 = 100;
+/// Decimals of every token launched on pump.fun
+pub const PUMP_TOKEN_DECIMALS: u32 = 6;
+const WSOL_MINT: &str = "So11111111111111111111111111111111111111112";
+
+/// Bonding curve account data. `blob1` is the Anchor discriminator and `blob4` the token's
+/// total supply; newer curves append fields after `complete`, which are ignored.
 #[derive(BorshSerialize, BorshDeserialize, Debug, Clone)]
 pub struct BondingCurveLayout {
     pub blob1: u64,
@@ -29,6 +43,120 @@ pub struct PumpAccounts {
     pub metadata: Pubkey,
 }
 
+/// A pump.fun bonding curve and its token vault, with the curve state read from chain.
+#[derive(Debug, Clone)]
+pub struct BondingCurve {
+    pub address: Pubkey,
+    pub associated_bonding_curve: Pubkey,
+    pub state: BondingCurveLayout,
+}
+
+/// The bonding curve PDA for `mint`, seeded with `["bonding-curve", mint]`.
+pub fn bonding_curve_address(mint: &Pubkey) -> Pubkey {
+    let program_id = Pubkey::from_str(PUMP_FUN_PROGRAM).unwrap();
+    let (address, _) = Pubkey::find_program_address(
+        &[b"bonding-curve", mint.as_ref()],
+        &program_id
+    );
+    address
+}
+
+/// Reads and decodes the bonding curve of `mint` in a single RPC call, or `None` if the mint
+/// was never launched on pump.fun.
+pub async fn read_bonding_curve(
+    client: &RpcClient,
+    mint: &Pubkey
+) -> Result<Option<BondingCurve>, Box<dyn Error>> {
+    let address = bonding_curve_address(mint);
+    let account = client
+        .get_account_with_commitment(&address, CommitmentConfig::processed()).await
+        .map_err(|e| TradeFailure::CurveUnavailable { mint: *mint, reason: e.to_string() })?
+        .value;
+    let Some(account) = account else {
+        return Ok(None);
+    };
+
+    let state = BondingCurveLayout::deserialize(&mut account.data.as_slice()).map_err(|e| {
+        TradeFailure::CurveUnavailable { mint: *mint, reason: format!("undecodable data, {}", e) }
+    })?;
+
+    Ok(
+        Some(BondingCurve {
+            address,
+            associated_bonding_curve: get_associated_token_address(&address, mint),
+            state,
+        })
+    )
+}
+
+/// The bonding curve of a mint that still trades on pump.fun. Fails if the curve is missing
+/// or complete, since the token then trades on Raydium instead.
+pub async fn get_bonding_curve(
+    client: &RpcClient,
+    mint: &Pubkey
+) -> Result<BondingCurve, Box<dyn Error>> {
+    let curve = read_bonding_curve(client, mint).await?.ok_or_else(|| {
+        TradeFailure::CurveUnavailable { mint: *mint, reason: "account not found".to_string() }
+    })?;
+
+    if curve.state.complete {
+        return Err(TradeFailure::CurveComplete(*mint).into());
+    }
+
+    Ok(curve)
+}
+
+/// Spot price on the bonding curve in SOL per whole token.
+pub fn curve_price_sol(state: &BondingCurveLayout) -> f64 {
+    if state.virtual_token_reserves == 0 {
+        return 0.0;
+    }
+    let sol = (state.virtual_sol_reserves as f64) / 1_000_000_000.0;
+    let tokens = (state.virtual_token_reserves as f64) / (10u64).pow(PUMP_TOKEN_DECIMALS) as f64;
+    sol / tokens
+}
+
+/// Spot price of `mint` in SOL per whole token, from the balances of a Raydium pool's two
+/// vaults. The vaults may be given in either order; their mints tell which side is SOL.
+pub async fn pool_price_sol(
+    client: &RpcClient,
+    mint: &Pubkey,
+    vaults: [&Pubkey; 2]
+) -> Result<f64, Box<dyn Error>> {
+    let mut token_reserve = None;
+    let mut sol_reserve = None;
+
+    for vault in vaults {
+        let account = client
+            .get_token_account(vault).await
+            .map_err(|e| {
+                TradeFailure::QuoteUnavailable(format!("failed to fetch vault {}: {}", vault, e))
+            })?
+            .ok_or_else(|| TradeFailure::QuoteUnavailable(format!("vault {} not found", vault)))?;
+        let balance = account.token_amount.ui_amount.unwrap_or_default();
+
+        if account.mint == mint.to_string() {
+            token_reserve = Some(balance);
+        } else if account.mint == WSOL_MINT {
+            sol_reserve = Some(balance);
+        }
+    }
+
+    match (token_reserve, sol_reserve) {
+        (Some(tokens), Some(sol)) if tokens > 0.0 => Ok(sol / tokens),
+        (Some(_), Some(_)) => {
+            Err(TradeFailure::QuoteUnavailable(format!("pool for {} has no tokens", mint)).into())
+        }
+        _ => {
+            Err(
+                TradeFailure::QuoteUnavailable(
+                    format!("vaults are not a {}/SOL pool", mint)
+                ).into()
+            )
+        }
+    }
+}
+
 pub async fn get_current_sol_price() -> Result<f64, Box<dyn Error>> {
     let url = "https://api.coingecko.com/api/v3/simple/price?ids=solana&vs_currencies=usd";
     let response = reqwest::get(url).await?;
@@ -1,20 +1,18 @@
 use super::service;
-use solana_sdk::{
-    instruction::{ AccountMeta, Instruction },
-    pubkey::Pubkey,
-    signature::{ Keypair, Signer },
-};
+use solana_sdk::{ instruction::{ AccountMeta, Instruction }, pubkey::Pubkey };
 use service::TokenVaults;
 use helius::types::*;
-use helius::Helius;
+use crate::command::validate_slippage;
+use crate::confirmation::TradeFailure;
+use crate::context::AppContext;
+use crate::wallet::Wallet;
+use crate::paper;
+use crate::simulation;
 use spl_associated_token_account::get_associated_token_address;
-use spl_associated_token_account::instruction::create_associated_token_account;
+use spl_associated_token_account::instruction::create_associated_token_account_idempotent;
 use std::str::FromStr;
-use serde::Deserialize;
-use reqwest::header::*;
-use solana_client::nonblocking::rpc_client::RpcClient;
-use service::save_buy_details;
-use std::sync::Arc;
+use super::price::{ get_bonding_curve, PUMP_FEE_BASIS_POINTS };
+use service::{ save_buy_details, store_buy, BuyFill };
 use std::error::Error;
 use solana_sdk::signature::Signature;
 use solana_sdk::instruction::Instruction as SolanaInstruction;
@@ -27,23 +25,18 @@ const RENT: &str = "SysvarRent111111111111111111111111111111111";
 const PUMP_FUN_PROGRAM: &str = "6EF8rrecthR5Dkzon8Nwu78hRvfCKubJ14M5uBEwF6P";
 const PUMP_FUN_ACCOUNT: &str = "Ce6TQqeHC9p8KetsN6JsjHK7UTZk7nasjjnr7XxXp9F1";
 const SYSTEM_PROGRAM_ID: &str = "11111111111111111111111111111111";
-
-const MAX_RETRIES: usize = 4;
+/// First 8 bytes of sha256("global:buy")
+const BUY_DISCRIMINATOR: [u8; 8] = [102, 6, 61, 18, 1, 218, 235, 234];
 
 async fn create_transaction(
-    instructions: Vec<SolanaInstruction>,
-    keypair: Keypair
+    ctx: &AppContext,
+    wallet: &Wallet,
+    instructions: Vec<SolanaInstruction>
 ) -> Result<Signature, Box<dyn Error>> {
-    let api_key: String = std::env
-        ::var("HELIUS_API_KEY")
-        .expect("You must set the HELIUS_API_KEY environment variable!");
-    let cluster: Cluster = Cluster::MainnetBeta;
-    let helius: Helius = Helius::new(&api_key, cluster).unwrap();
-
     let config = SmartTransactionConfig {
         create_config: CreateSmartTransactionConfig {
             instructions: instructions.clone(),
-            signers: vec![&keypair],
+            signers: vec![&wallet.keypair],
             lookup_tables: None,
             fee_payer: None,
         },
@@ -56,78 +49,104 @@ async fn create_transaction(
         },
     };
 
-    match helius.send_smart_transaction_with_tip(config, Some(600000), Some("NY")).await {
+    match
+        ctx.helius.send_smart_transaction_with_tip(
+            config,
+            Some(ctx.config.jito.tip_lamports),
+            Some(ctx.config.jito.region())
+        ).await
+    {
         Ok(signature) => {
             dbg!("Transaction sent successfully: {}", &signature);
             return Ok(signature);
         }
         Err(e) => {
             dbg!("Failed to send transaction on attempt {}: {:?}", &e);
-            return Err("Failed to send transaction".into());
+            return Err(TradeFailure::SendFailed.into());
         }
     }
 }
 
+/// What a pump.fun buy is sized by.
+#[derive(Debug, Clone, Copy)]
+pub enum PumpBuyAmount {
+    /// Spend this much SOL, protocol fee included
+    Sol(f64),
+    /// Receive exactly this many raw tokens
+    Tokens(u64),
+}
+
 pub async fn pump_fun_buy(
+    ctx: &AppContext,
+    wallet: &Wallet,
     mint_str: &str,
-    sol_in: f64,
-    slippage_decimal: f64,
+    amount: PumpBuyAmount,
+    slippage_pct: f64,
     lp_decimals: u8,
     group_title: String,
     user_name: String
 ) -> Result<Signature, Box<dyn Error>> {
-    let rpc_endpoint = std::env
-        ::var("RPC_URL")
-        .expect("You must set the RPC_URL environment variable!");
-    let client: Arc<RpcClient> = Arc::new(RpcClient::new(rpc_endpoint.to_string()));
-
-    let private_key = std::env
-        ::var("PRIVATE_KEY")
-        .expect("You must set the PRIVATE_KEY environment variable!");
-    let payer = Keypair::from_base58_string(&private_key);
-    let owner = payer.pubkey();
-    let mint = Pubkey::from_str(mint_str).unwrap();
+    validate_slippage(slippage_pct)?;
+
+    let client = ctx.rpc_client.clone();
 
-    let mut instructions = vec![];
+    let owner = wallet.pubkey();
+    let mint = Pubkey::from_str(mint_str).unwrap();
 
     let token_account_address = get_associated_token_address(&owner, &mint);
 
-    let token_account_exists = client.get_account(&token_account_address).await.is_ok();
-    if !token_account_exists {
-        let create_account_instruction = create_associated_token_account(
-            &payer.pubkey(),
-            &payer.pubkey(),
-            &mint,
-            &Pubkey::from_str(TOKEN_PROGRAM_ID).unwrap()
-        );
-        instructions.push(create_account_instruction);
-    }
+    for _ in 0..ctx.config.trading.pump_max_retries {
+        let curve = get_bonding_curve(&client, &mint).await?;
 
-    for _ in 0..MAX_RETRIES {
-        let coin_data = match get_coin_data(mint_str).await {
-            Ok(data) => data,
-            Err(_) => {
-                return Err("Failed to retrieve coin data...".into());
+        let state = &curve.state;
+
+        // Both sizings pin the token amount and cap what the curve may charge for it
+        let (token_out, sol_cost) = match amount {
+            PumpBuyAmount::Sol(sol_in) => {
+                let sol_in_lamports = (sol_in * 1_000_000_000.0) as u64;
+                let token_out = buy_quote(
+                    sol_in_lamports,
+                    state.virtual_token_reserves,
+                    state.virtual_sol_reserves,
+                    state.real_token_reserves
+                );
+                (token_out, sol_in_lamports)
+            }
+            PumpBuyAmount::Tokens(token_amount) => {
+                if token_amount > state.real_token_reserves {
+                    return Err(
+                        format!(
+                            "Cannot buy {} tokens, the bonding curve only has {} left",
+                            token_amount,
+                            state.real_token_reserves
+                        ).into()
+                    );
+                }
+                let sol_cost = buy_cost(
+                    token_amount,
+                    state.virtual_token_reserves,
+                    state.virtual_sol_reserves
+                ).ok_or("Token amount exceeds the bonding curve's virtual reserves")?;
+                (token_amount, sol_cost)
             }
         };
-
-        let sol_in_lamports = (sol_in * 1_000_000_000.0) as u128;
-        let token_out =
-            (sol_in_lamports * (coin_data.virtual_token_reserves as u128)) /
-            (coin_data.virtual_sol_reserves as u128);
-        println!("Token out: {}", token_out);
-        let sol_in_with_slippage = sol_in * (1.0 + slippage_decimal);
-        let max_sol_cost = (sol_in_with_slippage * 1_000_000_000.0) as u128;
-
-        let token_out_u64: u64 = token_out.try_into().map_err(|_| "Overflow")?;
-        let max_sol_cost_u64: u64 = max_sol_cost.try_into().map_err(|_| "Overflow")?;
+        if token_out == 0 {
+            return Err("Minimum expected output amount is zero.".into());
+        }
+        let max_sol_cost = max_cost_with_slippage(sol_cost, slippage_pct);
+        println!(
+            "Token out: {}, SOL cost: {}, max SOL cost: {}",
+            token_out,
+            sol_cost,
+            max_sol_cost
+        );
 
         let keys = vec![
             AccountMeta::new_readonly(Pubkey::from_str(GLOBAL).unwrap(), false),
             AccountMeta::new(Pubkey::from_str(FEE_RECIPIENT).unwrap(), false),
             AccountMeta::new_readonly(mint, false),
-            AccountMeta::new(Pubkey::from_str(&coin_data.bonding_curve).unwrap(), false),
-            AccountMeta::new(Pubkey::from_str(&coin_data.associated_bonding_curve).unwrap(), false),
+            AccountMeta::new(curve.address, false),
+            AccountMeta::new(curve.associated_bonding_curve, false),
             AccountMeta::new(token_account_address, false),
             AccountMeta::new(owner, true),
             AccountMeta::new_readonly(Pubkey::from_str(SYSTEM_PROGRAM_ID).unwrap(), false),
@@ -137,11 +156,7 @@ pub async fn pump_fun_buy(
             AccountMeta::new_readonly(Pubkey::from_str(PUMP_FUN_PROGRAM).unwrap(), false)
         ];
 
-        let buy: u64 = 16927863322537952870;
-        let mut data = vec![];
-        data.extend_from_slice(&buy.to_le_bytes());
-        data.extend_from_slice(&token_out_u64.to_le_bytes());
-        data.extend_from_slice(&max_sol_cost_u64.to_le_bytes());
+        let data = buy_instruction_data(token_out, max_sol_cost);
 
         let instruction = Instruction {
             program_id: Pubkey::from_str(PUMP_FUN_PROGRAM).unwrap(),
@@ -149,9 +164,52 @@ pub async fn pump_fun_buy(
             data,
         };
 
-        instructions.push(instruction);
+        // Every attempt carries its own token account creation, so a retry after a failed
+        // send still works on a mint the wallet never held
+        let instructions = vec![
+            create_associated_token_account_idempotent(
+                &owner,
+                &owner,
+                &mint,
+                &Pubkey::from_str(TOKEN_PROGRAM_ID).unwrap()
+            ),
+            instruction
+        ];
+
+        if ctx.config.paper.enabled {
+            let signature = paper::simulate(ctx, wallet, &instructions).await?;
+            let fill = BuyFill {
+                amount: token_out.to_string(),
+                sol_amount: (sol_cost as f64) / 1_000_000_000.0,
+                network_fee: paper::network_fee(ctx),
+            };
+            let key_z = TokenVaults {
+                base_vault: "".to_string(),
+                quote_vault: "".to_string(),
+                base_mint: mint_str.to_string(),
+                quote_mint: "So11111111111111111111111111111111111111112".to_string(),
+            };
+
+            store_buy(
+                ctx,
+                wallet,
+                &signature,
+                fill,
+                lp_decimals,
+                mint_str,
+                key_z,
+                true,
+                group_title,
+                user_name
+            ).await?;
+            return Ok(signature);
+        }
+
+        if ctx.config.trading.simulate_before_send {
+            simulation::preflight(ctx, wallet, &instructions).await?;
+        }
 
-        match create_transaction(instructions.clone(), payer.insecure_clone()).await {
+        match create_transaction(ctx, wallet, instructions).await {
             Ok(tx) => {
                 let key_z = TokenVaults {
                     base_vault: "".to_string(),
@@ -161,7 +219,8 @@ pub async fn pump_fun_buy(
                 };
 
                 let _saved_details = save_buy_details(
-                    client.clone(),
+                    ctx,
+                    wallet,
                     &tx,
                     lp_decimals,
                     mint_str,
@@ -174,43 +233,113 @@ pub async fn pump_fun_buy(
             }
             Err(e) => {
                 dbg!("Failed to send transaction: {:?}", e);
-                instructions.clear(); // Clear instructions to recalculate in the next iteration
             }
         }
     }
 
-    Err("Failed to create transaction after retries".into())
+    Err(TradeFailure::RetriesExhausted.into())
 }
 
-#[derive(Deserialize)]
-struct CoinData {
+/// Tokens the bonding curve gives for `sol_in` lamports once the protocol fee is taken out,
+/// capped at the tokens left on the curve.
+pub fn buy_quote(
+    sol_in: u64,
     virtual_token_reserves: u64,
     virtual_sol_reserves: u64,
-    bonding_curve: String,
-    associated_bonding_curve: String,
+    real_token_reserves: u64
+) -> u64 {
+    let sol_after_fee =
+        ((sol_in as u128) * 10_000) / (10_000 + (PUMP_FEE_BASIS_POINTS as u128));
+    let virtual_sol_reserves = virtual_sol_reserves as u128;
+    if virtual_sol_reserves + sol_after_fee == 0 {
+        return 0;
+    }
+
+    let token_out =
+        ((virtual_token_reserves as u128) * sol_after_fee) / (virtual_sol_reserves + sol_after_fee);
+    token_out.min(real_token_reserves as u128) as u64
 }
 
-async fn get_coin_data(mint_str: &str) -> Result<CoinData, Box<dyn std::error::Error>> {
-    let url = format!("https://frontend-api.pump.fun/coins/{}", mint_str);
-    let client = reqwest::Client::new();
-    let response = client
-        .get(&url)
-        .header(
-            USER_AGENT,
-            "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:125.0) Gecko/20100101 Firefox/125.0"
-        )
-        .header(ACCEPT, "*/*")
-        .header(ACCEPT_LANGUAGE, "en-US,en;q=0.5")
-        .header(ACCEPT_ENCODING, "gzip, deflate, br")
-        .header(REFERER, "https://www.pump.fun/")
-        .header(ORIGIN, "https://www.pump.fun")
-        .header(CONNECTION, "keep-alive")
-        .send().await?;
-
-    if response.status().is_success() {
-        let coin_data: CoinData = response.json().await?;
-        Ok(coin_data)
-    } else {
-        Err(format!("Failed to retrieve coin data: {}", response.status()).into())
+/// Lamports the bonding curve charges for exactly `token_amount` tokens, fee included, or
+/// `None` if the curve can't supply that many.
+pub fn buy_cost(
+    token_amount: u64,
+    virtual_token_reserves: u64,
+    virtual_sol_reserves: u64
+) -> Option<u64> {
+    if token_amount >= virtual_token_reserves {
+        return None;
+    }
+
+    let token_amount = token_amount as u128;
+    let sol_cost =
+        ((virtual_sol_reserves as u128) * token_amount) /
+            ((virtual_token_reserves as u128) - token_amount) +
+        1;
+    let fee = (sol_cost * (PUMP_FEE_BASIS_POINTS as u128)) / 10_000;
+    (sol_cost + fee).try_into().ok()
+}
+
+/// `sol_cost` raised by `slippage_pct` percent, the most the buy may spend.
+pub fn max_cost_with_slippage(sol_cost: u64, slippage_pct: f64) -> u64 {
+    let slippage_bps = (slippage_pct.max(0.0) * 100.0).round() as u128;
+    let max_cost = ((sol_cost as u128) * (10_000 + slippage_bps)) / 10_000;
+    max_cost.min(u64::MAX as u128) as u64
+}
+
+/// Anchor `buy` instruction data: discriminator, token amount, maximum SOL cost.
+pub fn buy_instruction_data(token_amount: u64, max_sol_cost: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(24);
+    data.extend_from_slice(&BUY_DISCRIMINATOR);
+    data.extend_from_slice(&token_amount.to_le_bytes());
+    data.extend_from_slice(&max_sol_cost.to_le_bytes());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Initial curve: 1.073B tokens (6 decimals) against 30 SOL, 793.1M tokens for sale
+    const VIRTUAL_TOKENS: u64 = 1_073_000_000_000_000;
+    const VIRTUAL_SOL: u64 = 30_000_000_000;
+    const REAL_TOKENS: u64 = 793_100_000_000_000;
+
+    #[test]
+    fn buy_instruction_data_layout() {
+        let data = buy_instruction_data(34_281_150_129_545, 1_249_999_998);
+
+        assert_eq!(data.len(), 24);
+        assert_eq!(&data[..8], &(16927863322537952870u64).to_le_bytes());
+        assert_eq!(&data[8..16], &(34_281_150_129_545u64).to_le_bytes());
+        assert_eq!(&data[16..], &(1_249_999_998u64).to_le_bytes());
+    }
+
+    #[test]
+    fn buy_quote_takes_fee_from_input() {
+        let token_out = buy_quote(1_000_000_000, VIRTUAL_TOKENS, VIRTUAL_SOL, REAL_TOKENS);
+
+        assert_eq!(token_out, 34_281_150_129_545);
+    }
+
+    #[test]
+    fn buy_quote_is_capped_by_real_reserves() {
+        let token_out = buy_quote(1_000_000_000_000, VIRTUAL_TOKENS, VIRTUAL_SOL, REAL_TOKENS);
+
+        assert_eq!(token_out, REAL_TOKENS);
+    }
+
+    #[test]
+    fn buy_cost_round_trips_quote() {
+        let cost = buy_cost(34_281_150_129_545, VIRTUAL_TOKENS, VIRTUAL_SOL);
+
+        assert_eq!(cost, Some(999_999_999));
+        assert_eq!(buy_cost(VIRTUAL_TOKENS, VIRTUAL_TOKENS, VIRTUAL_SOL), None);
+    }
+
+    #[test]
+    fn max_cost_adds_slippage_percent() {
+        assert_eq!(max_cost_with_slippage(999_999_999, 25.0), 1_249_999_998);
+        assert_eq!(max_cost_with_slippage(1_000_000_000, 80.0), 1_800_000_000);
     }
 }
@@ -8,12 +8,13 @@ use utils::{ get_second_instruction_amount, calculate_sol_amount_spent, get_toke
 use solana_transaction_status::option_serializer::OptionSerializer;
 use solana_transaction_status::UiInnerInstructions;
 use solana_transaction_status::UiTransactionEncoding;
-use solana_client::nonblocking::rpc_client::RpcClient;
 use mongodb::bson::DateTime;
 use std::error::Error;
-use std::sync::Arc;
 use std::time::Duration;
-use redis::{ Commands, RedisResult };
+use redis::{ AsyncCommands, RedisResult };
+use redis::aio::MultiplexedConnection;
+use crate::context::AppContext;
+use crate::wallet::Wallet;
 
 #[derive(Debug, Clone)]
 pub struct TokenVaults {
@@ -24,7 +25,8 @@ pub struct TokenVaults {
 }
 
 pub async fn save_buy_details(
-    client: Arc<RpcClient>,
+    ctx: &AppContext,
+    wallet: &Wallet,
     signature: &Signature,
     lp_decimals: u8,
     mint: &str,
@@ -33,6 +35,7 @@ pub async fn save_buy_details(
     group_title: String,
     user_name: String
 ) -> Result<(), Box<dyn Error>> {
+    let client = ctx.rpc_client.clone();
     let max_retries = 3;
     let retry_delay = Duration::from_secs(10);
     let mut retries = 0;
@@ -56,134 +59,27 @@ pub async fn save_buy_details(
                     pump
                 );
 
-                let mut sol_amount = calculate_sol_amount_spent(
-                    &confirmed_transaction
-                ).await.unwrap();
+                let sol_amount = calculate_sol_amount_spent(&confirmed_transaction).await.unwrap();
 
-                if let Some(ref amount_str) = amount {
-                    // Parse the amount as f64
-                    let amount = amount_str.parse::<f64>().unwrap_or_default();
-
-                    // Assume `lp_decimals` is of type u8
-                    let token_decimals = lp_decimals as f64;
-
-                    // Adjust the token amount using the decimals
-                    let adjusted_token_amount = amount / (10f64).powf(token_decimals);
-
-                    // Calculate the buy price per token in SOL
-                    let buy_price_per_token_in_sol = sol_amount / adjusted_token_amount;
-                    let current_sol_price = get_current_sol_price().await.unwrap_or_default();
-                    //let usd_amount = sol_amount * current_sol_price;
-
-                    let mut fee = confirmed_transaction.transaction.meta.unwrap().fee as f64;
-
-                    if pump {
-                        // Calculate the pump fee (1% of the original sol_amount spent)
-                        fee = sol_amount * 0.01;
-
-                        // Optionally, subtract the pump fee from sol_amount if needed
-                        sol_amount -= fee;
-                    }
-
-                    let fee_sol = fee;
-                    let fee_usd = fee_sol * current_sol_price;
-
-                    // Determine the vaults to use
-                    let (base_vault, quote_vault) = if pump {
-                        ("".to_string(), "".to_string())
-                    } else {
-                        (token_vaults.base_vault.to_string(), token_vaults.quote_vault.to_string())
-                    };
-
-                    // Determine the base mint and base vault based on whether base_mint is SOL
-                    let base_mint_to_use = if
-                        token_vaults.base_mint == "So11111111111111111111111111111111111111112"
-                    {
-                        // If base_mint is SOL, use quote_mint and quote_vault instead
-                        token_vaults.quote_mint.to_string()
-                    } else {
-                        // Otherwise, use base_mint and base_vault
-                        token_vaults.base_mint.to_string()
-                    };
-
-                    let token_info = TokenInfo {
-                        base_mint: base_mint_to_use,
-                        quote_mint: token_vaults.quote_mint.to_string(),
-                        base_vault,
-                        quote_vault,
-                    };
-
-                    // Initialize MongoDB handler
-                    let mongo_handler = match MongoHandler::new().await {
-                        Ok(handler) => handler,
-                        Err(err) => {
-                            return Err(err.into());
-                        }
-                    };
-
-                    // Prepare token_metadata and ensure it's not None
-                    let token_metadata = loop {
-                        match get_token_metadata(&mint, adjusted_token_amount, &client).await {
-                            Ok(metadata) => {
-                                break metadata;
-                            }
-                            Err(err) => {
-                                // You might want to retry or provide a default value here
-                                tokio::time::sleep(retry_delay).await;
-                                retries += 1;
-                                if retries > max_retries {
-                                    return Err(err.into());
-                                }
-                            }
-                        }
-                    };
-
-                    let usd_amount = sol_amount * current_sol_price;
-
-                    let buy_transaction: BuyTransaction = BuyTransaction {
-                        transaction_signature: signature.to_string().clone(),
-                        token_info: token_info.clone(),
-                        initial_amount: amount,
+                if let Some(amount) = amount {
+                    let fill = BuyFill {
                         amount,
                         sol_amount,
-                        sol_price: current_sol_price,
-                        highest_profit_percentage: 0.0,
-                        usd_amount,
-                        token_metadata: token_metadata.clone(),
-                        entry_price: buy_price_per_token_in_sol,
-                        fee_sol,
-                        fee_usd,
-                        transaction_type: TransactionType::LongTermHold,
-                        created_at: DateTime::now(),
+                        network_fee: confirmed_transaction.transaction.meta.unwrap().fee,
                     };
 
-                    // Store transaction info in MongoDB
-                    if
-                        let Err(e) = mongo_handler.store_buy_transaction_info(
-                            buy_transaction,
-                            "solsniper",
-                            "buy_transactions"
-                        ).await
-                    {
-                        eprintln!("Error storing transaction info: {:?}", e);
-                    }
-
-                    increase_buy_counter().await?;
-
-                    if
-                        let Err(e) = mongo_handler.store_token(
-                            token_metadata,
-                            buy_price_per_token_in_sol,
-                            "solsniper",
-                            fee_sol,
-                            "tokens",
-                            sol_amount,
-                            group_title,
-                            user_name
-                        ).await
-                    {
-                        eprintln!("Error storing token info: {:?}", e);
-                    }
+                    store_buy(
+                        ctx,
+                        wallet,
+                        signature,
+                        fill,
+                        lp_decimals,
+                        mint,
+                        token_vaults,
+                        pump,
+                        group_title,
+                        user_name
+                    ).await?;
                 } else {
                     eprintln!("Error getting amount from inner instructions");
                 }
@@ -205,27 +101,187 @@ pub async fn save_buy_details(
     Err("Failed to get transaction details after maximum retries".into())
 }
 
-pub async fn increase_buy_counter() -> RedisResult<()> {
-    let redis_url = std::env
-        ::var("REDIS_URL")
-        .expect("You must set the REDIS_URL environment variable");
+/// What a buy got, either read back from the confirmed transaction or synthesized in paper mode.
+pub struct BuyFill {
+    /// Raw token amount received
+    pub amount: String,
+    /// SOL spent
+    pub sol_amount: f64,
+    /// Network fee in lamports, as `meta.fee` reports it
+    pub network_fee: u64,
+}
+
+/// Records a buy in the `buy_transactions` and `tokens` collections and opens its trade state.
+pub async fn store_buy(
+    ctx: &AppContext,
+    wallet: &Wallet,
+    signature: &Signature,
+    fill: BuyFill,
+    lp_decimals: u8,
+    mint: &str,
+    token_vaults: TokenVaults,
+    pump: bool,
+    group_title: String,
+    user_name: String
+) -> Result<(), Box<dyn Error>> {
+    let client = ctx.rpc_client.clone();
+    let max_retries = 3;
+    let retry_delay = Duration::from_secs(10);
+    let mut retries = 0;
+
+    let sol_amount = fill.sol_amount;
+
+    // Parse the amount as f64
+    let amount = fill.amount.parse::<f64>().unwrap_or_default();
+
+    // Assume `lp_decimals` is of type u8
+    let token_decimals = lp_decimals as f64;
+
+    // Adjust the token amount using the decimals
+    let adjusted_token_amount = amount / (10f64).powf(token_decimals);
+
+    // Calculate the buy price per token in SOL
+    let buy_price_per_token_in_sol = sol_amount / adjusted_token_amount;
+    let current_sol_price = get_current_sol_price().await.unwrap_or_default();
+    //let usd_amount = sol_amount * current_sol_price;
+
+    let (sol_amount, fee_sol) = buy_fee(sol_amount, fill.network_fee, pump);
+    let fee_usd = fee_sol * current_sol_price;
+
+    // Determine the vaults to use
+    let (base_vault, quote_vault) = if pump {
+        ("".to_string(), "".to_string())
+    } else {
+        (token_vaults.base_vault.to_string(), token_vaults.quote_vault.to_string())
+    };
+
+    // Determine the base mint and base vault based on whether base_mint is SOL
+    let base_mint_to_use = if
+        token_vaults.base_mint == "So11111111111111111111111111111111111111112"
+    {
+        // If base_mint is SOL, use quote_mint and quote_vault instead
+        token_vaults.quote_mint.to_string()
+    } else {
+        // Otherwise, use base_mint and base_vault
+        token_vaults.base_mint.to_string()
+    };
+
+    let token_info = TokenInfo {
+        base_mint: base_mint_to_use,
+        quote_mint: token_vaults.quote_mint.to_string(),
+        base_vault,
+        quote_vault,
+    };
+
+    let mongo_handler = MongoHandler::new(ctx.mongo.clone(), &ctx.config.mongo, wallet);
+
+    // Prepare token_metadata and ensure it's not None
+    let token_metadata = loop {
+        match get_token_metadata(&mint, adjusted_token_amount, &client).await {
+            Ok(metadata) => {
+                break metadata;
+            }
+            Err(err) => {
+                // You might want to retry or provide a default value here
+                tokio::time::sleep(retry_delay).await;
+                retries += 1;
+                if retries > max_retries {
+                    return Err(err.into());
+                }
+            }
+        }
+    };
+
+    let usd_amount = sol_amount * current_sol_price;
+
+    let buy_transaction: BuyTransaction = BuyTransaction {
+        transaction_signature: signature.to_string().clone(),
+        token_info: token_info.clone(),
+        initial_amount: amount,
+        amount,
+        sol_amount,
+        sol_price: current_sol_price,
+        highest_profit_percentage: 0.0,
+        usd_amount,
+        token_metadata: token_metadata.clone(),
+        entry_price: buy_price_per_token_in_sol,
+        fee_sol,
+        fee_usd,
+        transaction_type: TransactionType::LongTermHold,
+        wallet: wallet.address(),
+        paper: ctx.config.paper.enabled,
+        created_at: DateTime::now(),
+    };
+
+    // Store transaction info in MongoDB
+    if
+        let Err(e) = mongo_handler.store_buy_transaction_info(
+            buy_transaction,
+            &ctx.config.mongo.trades_db,
+            "buy_transactions"
+        ).await
+    {
+        eprintln!("Error storing transaction info: {:?}", e);
+    }
 
-    let client = redis::Client::open(redis_url.clone()).expect("Failed to create Redis client");
+    increase_buy_counter(
+        &mut ctx.redis.clone(),
+        &wallet.buy_counter_key(ctx.config.paper.enabled)
+    ).await?;
+
+    if
+        let Err(e) = mongo_handler.store_token(
+            token_metadata,
+            buy_price_per_token_in_sol,
+            &ctx.config.mongo.trades_db,
+            fee_sol,
+            "tokens",
+            sol_amount,
+            group_title,
+            user_name
+        ).await
+    {
+        eprintln!("Error storing token info: {:?}", e);
+    }
 
-    let mut con = client.get_connection().expect("Failed to connect to Redis");
+    Ok(())
+}
 
-    // Get the current value of buy_transaction_count
-    let current_count: i32 = con.get("buy_transaction_count").unwrap_or(0);
-    println!("Current count: {}", current_count);
+/// Splits what a buy spent into the SOL that bought tokens and the fee, both in SOL. pump.fun
+/// takes its 1% protocol fee out of the amount spent; Raydium buys only pay the network fee.
+fn buy_fee(sol_amount: f64, network_fee: u64, pump: bool) -> (f64, f64) {
+    if pump {
+        let fee = sol_amount * 0.01;
+        (sol_amount - fee, fee)
+    } else {
+        (sol_amount, (network_fee as f64) / 1_000_000_000.0)
+    }
+}
 
-    // Calculate the new value by subtracting 1
-    let new_count = current_count + 1;
+pub async fn increase_buy_counter(con: &mut MultiplexedConnection, key: &str) -> RedisResult<()> {
+    let new_count: i32 = con.incr(key, 1).await?;
     println!("New count: {}", new_count);
 
-    // Set the new value back to Redis
-    let _: () = con
-        .set("buy_transaction_count", new_count)
-        .expect("Failed to set buy_transaction_count");
-
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn raydium_buy_fee_is_converted_to_sol() {
+        let (sol_amount, fee_sol) = buy_fee(0.5, 5_000, false);
+
+        assert_eq!(sol_amount, 0.5);
+        assert!((fee_sol - 0.000005).abs() < 1e-12);
+    }
+
+    #[test]
+    fn pump_buy_fee_is_taken_from_the_amount_spent() {
+        let (sol_amount, fee_sol) = buy_fee(1.0, 5_000, true);
+
+        assert!((sol_amount - 0.99).abs() < 1e-12);
+        assert!((fee_sol - 0.01).abs() < 1e-12);
+    }
+}
@@ -8,11 +8,6 @@ use mongo::TokenMetadata;
 use solana_client::nonblocking::rpc_client::RpcClient;
 use solana_sdk::pubkey::Pubkey;
 use std::str::FromStr;
-use solana_sdk::{ instruction::Instruction, signer::Signer };
-use spl_token_client::{
-    client::{ SendTransaction, SimulateTransaction },
-    token::{ Token, TokenError },
-};
 use solana_account_decoder::UiAccountEncoding;
 use solana_sdk::commitment_config::CommitmentConfig;
 use solana_transaction_status::EncodedConfirmedTransactionWithStatusMeta;
@@ -27,39 +22,6 @@ use solana_client::{
 use raydium_sdk::LiquidityPoolKeys;
 use crate::buy::buy::SwapError;
 
-#[derive(Debug, Deserialize)]
-struct SwapResponse {
-    id: String,
-    success: bool,
-    version: String,
-    data: SwapData,
-}
-
-#[derive(Debug, Deserialize)]
-struct SwapData {
-    swapType: String,
-    inputMint: String,
-    inputAmount: String,
-    outputMint: String,
-    outputAmount: String,
-    otherAmountThreshold: String,
-    slippageBps: i32,
-    priceImpactPct: f64,
-    referrerAmount: String,
-    routePlan: Vec<RoutePlan>,
-}
-
-#[derive(Debug, Deserialize)]
-struct RoutePlan {
-    poolId: String,
-    inputMint: String,
-    outputMint: String,
-    feeMint: String,
-    feeRate: i32,
-    feeAmount: String,
-    remainingAccounts: Vec<String>,
-}
-
 #[derive(Serialize, Deserialize)]
 struct MinimalMarketLayoutV3 {
     event_queue: Pubkey,
@@ -67,6 +29,19 @@ struct MinimalMarketLayoutV3 {
     asks: Pubkey,
 }
 
+/// Leading fields of an OpenBook `OpenOrders` account, after its 5-byte "serum" padding.
+#[derive(Serialize, Deserialize)]
+struct MinimalOpenOrdersLayout {
+    padding: [u8; 5],
+    account_flags: u64,
+    market: Pubkey,
+    owner: Pubkey,
+    base_token_free: u64,
+    base_token_total: u64,
+    quote_token_free: u64,
+    quote_token_total: u64,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LiquidityStateV4 {
     pub base_mint: Pubkey,
@@ -181,73 +156,132 @@ async fn get_minimal_market_v3(client: &RpcClient, market_id: Pubkey) -> Minimal
     minimal_market_layout_v3
 }
 
-pub async fn get_out_amount(
-    output_mint: &str,
+/// Raydium AMM v4 quote for swapping `amount` of `input_mint` through the pool `pool_id`,
+/// as (expected out, minimum out after `slippage_pct`). Works in either direction.
+pub async fn get_swap_quote(
+    client: &RpcClient,
+    pool_id: &Pubkey,
+    input_mint: &Pubkey,
     amount: u64,
     slippage_pct: &f64
 ) -> Result<(u64, u64), SwapError> {
-    let slippage_bps = (slippage_pct * 100.0).round() as i32;
+    let pool_data = client
+        .get_account_data(pool_id).await
+        .map_err(|e| SwapError::QuoteError(format!("failed to fetch pool {}: {}", pool_id, e)))?;
+    let pool_state: MyAccountData = bincode
+        ::deserialize(&pool_data)
+        .map_err(|e| SwapError::QuoteError(format!("failed to decode pool {}: {}", pool_id, e)))?;
+
+    let base_vault = vault_amount(client, &pool_state.base_vault).await?;
+    let quote_vault = vault_amount(client, &pool_state.quote_vault).await?;
+    let open_orders = open_orders_totals(client, &pool_state.open_orders).await?;
+
+    let (base_reserve, quote_reserve) = pool_reserves(
+        (base_vault, quote_vault),
+        open_orders,
+        (pool_state.base_need_take_pnl, pool_state.quote_need_take_pnl)
+    );
 
-    let url = format!(
-        "https://transaction-v1.raydium.io/compute/swap-base-in?inputMint=So11111111111111111111111111111111111111112&outputMint={}&amount={}&slippageBps={}&txVersion=V0",
-        output_mint,
+    let (reserve_in, reserve_out) = if *input_mint == pool_state.base_mint {
+        (base_reserve, quote_reserve)
+    } else if *input_mint == pool_state.quote_mint {
+        (quote_reserve, base_reserve)
+    } else {
+        return Err(
+            SwapError::QuoteError(format!("{} is not traded by pool {}", input_mint, pool_id))
+        );
+    };
+
+    let amount_out = constant_product_out(
         amount,
-        slippage_bps
+        reserve_in,
+        reserve_out,
+        pool_state.swap_fee_numerator,
+        pool_state.swap_fee_denominator
     );
 
-    let client = reqwest::Client::new();
-    let response = match
-        client
-            .get(&url)
-            .header(
-                "User-Agent",
-                "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:128.0) Gecko/20100101 Firefox/128.0"
-            )
-            .header("Accept", "application/json, text/plain, */*")
-            .header("Accept-Language", "en-US,en;q=0.5")
-            .header("Accept-Encoding", "gzip, deflate, br, zstd")
-            .header("Referer", "https://raydium.io/")
-            .header("Origin", "https://raydium.io")
-            .header("Connection", "keep-alive")
-            .header("Sec-Fetch-Dest", "empty")
-            .header("Sec-Fetch-Mode", "cors")
-            .header("Sec-Fetch-Site", "same-site")
-            .header("Sec-GPC", "1")
-            .header("TE", "trailers")
-            .header(
-                "Cookie",
-                "__cf_bm=hVD6YdWXvyXMAGrApQGdgnXTDA8rjORMmO6F6dIa1l0-1722813884-1.0.1.1-.LXXz1cn23gsMerhHo9pmRreQ.Su6Xg7jqTkzWUzWUrPZF_wuQQgIpqCkX0B7KxQ4sskIrXriotSRVrDtSXZBQ; path=/; expires=Sun, 04-Aug-24 23:54:44 GMT; domain=.raydium.io; HttpOnly; Secure; SameSite=None"
-            )
-            .send().await
-    {
-        Ok(resp) => { resp }
-        Err(err) => {
-            return Err(SwapError::TokenError(err.to_string()));
-        }
-    };
+    Ok((amount_out, apply_slippage(amount_out, *slippage_pct)))
+}
 
-    let swap_response: SwapResponse = match response.json().await {
-        Ok(json) => json,
-        Err(err) => {
-            return Err(SwapError::TokenError(err.to_string()));
-        }
-    };
+/// Unix time at which the pool `pool_id` starts accepting swaps.
+pub async fn get_pool_open_time(
+    client: &RpcClient,
+    pool_id: &Pubkey
+) -> Result<u64, Box<dyn Error>> {
+    let pool_data = client.get_account_data(pool_id).await?;
+    let pool_state: MyAccountData = bincode::deserialize(&pool_data)?;
+    Ok(pool_state.pool_open_time)
+}
 
-    let output_amount = match swap_response.data.outputAmount.parse::<u64>() {
-        Ok(amount) => amount,
-        Err(err) => {
-            return Err(SwapError::TokenError(err.to_string()));
-        }
-    };
+/// Swappable (base, quote) reserves of a pool, computed like Raydium's
+/// `calc_total_without_take_pnl`: the vault balances plus the tokens the pool holds in its
+/// OpenBook open orders, less the fees owed to the pool owner.
+fn pool_reserves(
+    vaults: (u64, u64),
+    open_orders: (u64, u64),
+    need_take_pnl: (u64, u64)
+) -> (u64, u64) {
+    (
+        vaults.0.saturating_add(open_orders.0).saturating_sub(need_take_pnl.0),
+        vaults.1.saturating_add(open_orders.1).saturating_sub(need_take_pnl.1),
+    )
+}
 
-    let min_amount = match swap_response.data.otherAmountThreshold.parse::<u64>() {
-        Ok(amount) => amount,
-        Err(err) => {
-            return Err(SwapError::TokenError(err.to_string()));
-        }
-    };
+/// Output of a constant-product swap after the pool's swap fee, which Raydium takes from the
+/// input and rounds up.
+pub fn constant_product_out(
+    amount_in: u64,
+    reserve_in: u64,
+    reserve_out: u64,
+    fee_numerator: u64,
+    fee_denominator: u64
+) -> u64 {
+    if reserve_in == 0 || reserve_out == 0 || fee_denominator == 0 {
+        return 0;
+    }
 
-    Ok((output_amount, min_amount))
+    let amount_in = amount_in as u128;
+    let fee = (amount_in * (fee_numerator as u128)).div_ceil(fee_denominator as u128);
+    let amount_in_after_fee = amount_in.saturating_sub(fee);
+
+    let amount_out =
+        ((reserve_out as u128) * amount_in_after_fee) /
+        ((reserve_in as u128) + amount_in_after_fee);
+    amount_out as u64
+}
+
+/// `amount` reduced by `slippage_pct` percent, clamped to 0..=100.
+pub fn apply_slippage(amount: u64, slippage_pct: f64) -> u64 {
+    let slippage_bps = (slippage_pct.clamp(0.0, 100.0) * 100.0).round() as u128;
+    (((amount as u128) * (10_000 - slippage_bps)) / 10_000) as u64
+}
+
+async fn vault_amount(client: &RpcClient, vault: &Pubkey) -> Result<u64, SwapError> {
+    let balance = client
+        .get_token_account_balance(vault).await
+        .map_err(|e| SwapError::QuoteError(format!("failed to fetch vault {}: {}", vault, e)))?;
+
+    balance.amount
+        .parse::<u64>()
+        .map_err(|e| SwapError::QuoteError(format!("invalid vault {} balance: {}", vault, e)))
+}
+
+/// (base, quote) totals the pool's OpenBook open orders account holds, free or on the book.
+async fn open_orders_totals(
+    client: &RpcClient,
+    open_orders: &Pubkey
+) -> Result<(u64, u64), SwapError> {
+    let data = client
+        .get_account_data(open_orders).await
+        .map_err(|e| {
+            SwapError::QuoteError(format!("failed to fetch open orders {}: {}", open_orders, e))
+        })?;
+    let layout: MinimalOpenOrdersLayout = bincode
+        ::deserialize(&data)
+        .map_err(|e| {
+            SwapError::QuoteError(format!("failed to decode open orders {}: {}", open_orders, e))
+        })?;
+    Ok((layout.base_token_total, layout.quote_token_total))
 }
 
 fn create_pool_keys(
@@ -526,78 +560,6 @@ fn default_token_metadata(balance: f64, mint_address: &str) -> TokenMetadata {
         created_on: "".to_string(),
     }
 }
-pub struct AtaCreationBundle {
-    pub token_in: AtaInfo,
-    pub token_out: AtaInfo,
-}
-
-pub struct AtaInfo {
-    pub instruction: Option<Instruction>,
-    pub ata_pubkey: Pubkey,
-    pub balance: u64,
-}
-
-pub async fn get_or_create_ata_for_token_in_and_out_with_balance<
-    S: Signer + 'static,
-    PC: SendTransaction + SimulateTransaction + 'static
->(
-    token_in: &Token<PC>,
-    token_out: &Token<PC>,
-    payer: Arc<S>
-) -> Result<AtaCreationBundle, Box<dyn std::error::Error>> {
-    let (create_token_in_ata_ix, token_in_ata, token_in_balance) = token_ata_creation_instruction(
-        token_in,
-        &payer
-    ).await?;
-    let (create_token_out_ata_ix, token_out_ata, token_out_balance) =
-        token_ata_creation_instruction(token_out, &payer).await?;
-
-    Ok(AtaCreationBundle {
-        token_in: AtaInfo {
-            instruction: create_token_in_ata_ix,
-            ata_pubkey: token_in_ata,
-            balance: token_in_balance,
-        },
-        token_out: AtaInfo {
-            instruction: create_token_out_ata_ix,
-            ata_pubkey: token_out_ata,
-            balance: token_out_balance,
-        },
-    })
-}
-
-async fn token_ata_creation_instruction<
-    S: Signer + 'static,
-    PC: SendTransaction + SimulateTransaction + 'static
->(
-    token: &Token<PC>,
-    payer: &Arc<S>
-) -> Result<(Option<Instruction>, Pubkey, u64), Box<dyn std::error::Error>> {
-    let payer_token_account = token.get_associated_token_address(&payer.pubkey());
-    let (instruction, amount) = match token.get_account_info(&payer_token_account).await {
-        Ok(res) => (None, res.base.amount),
-        Err(TokenError::AccountNotFound) | Err(TokenError::AccountInvalidOwner) => {
-            dbg!("User does not have ATA {payer_token_account} for token. Creating");
-            (
-                Some(
-                    spl_associated_token_account::instruction::create_associated_token_account(
-                        &payer.pubkey(),
-                        &payer.pubkey(),
-                        token.get_address(),
-                        &spl_token::ID
-                    )
-                ),
-                0,
-            )
-        }
-        Err(error) => {
-            dbg!("Error retrieving user's input-tokens ATA: {}", error);
-            return Err("Error retrieving user's input-tokens ATA".into());
-        }
-    };
-    Ok((instruction, payer_token_account, amount))
-}
-
 pub async fn calculate_sol_amount_spent(
     tx: &EncodedConfirmedTransactionWithStatusMeta
 ) -> Result<f64, Box<dyn std::error::Error>> {
@@ -641,3 +603,121 @@ pub fn string_to_pubkey<'de, D>(deserializer: D) -> Result<Pubkey, D::Error>
     let s = String::deserialize(deserializer)?;
     Pubkey::from_str(&s).map_err(serde::de::Error::custom)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Raydium AMM v4 swap fee of 0.25%
+    const FEE_NUMERATOR: u64 = 25;
+    const FEE_DENOMINATOR: u64 = 10_000;
+
+    // 100 SOL against 1M tokens with 9 decimals
+    const SOL_RESERVE: u64 = 100_000_000_000;
+    const TOKEN_RESERVE: u64 = 1_000_000_000_000_000;
+
+    #[test]
+    fn constant_product_takes_fee_from_input() {
+        let amount_out = constant_product_out(
+            1_000_000_000,
+            SOL_RESERVE,
+            TOKEN_RESERVE,
+            FEE_NUMERATOR,
+            FEE_DENOMINATOR
+        );
+
+        // 2_500_000 lamports of fee leaves 997_500_000 to swap
+        assert_eq!(amount_out, 9_876_482_091_140);
+    }
+
+    #[test]
+    fn constant_product_rounds_fee_up() {
+        assert_eq!(
+            constant_product_out(1_001, 1_000_000, 1_000_000, FEE_NUMERATOR, FEE_DENOMINATOR),
+            997
+        );
+        assert_eq!(
+            constant_product_out(1, SOL_RESERVE, TOKEN_RESERVE, FEE_NUMERATOR, FEE_DENOMINATOR),
+            0
+        );
+    }
+
+    #[test]
+    fn constant_product_handles_empty_pools() {
+        assert_eq!(constant_product_out(1_000, 0, SOL_RESERVE, FEE_NUMERATOR, FEE_DENOMINATOR), 0);
+        assert_eq!(constant_product_out(1_000, SOL_RESERVE, 0, FEE_NUMERATOR, FEE_DENOMINATOR), 0);
+        assert_eq!(constant_product_out(1_000, SOL_RESERVE, SOL_RESERVE, FEE_NUMERATOR, 0), 0);
+    }
+
+    #[test]
+    fn constant_product_does_not_overflow_at_u64_reserves() {
+        assert_eq!(
+            constant_product_out(u64::MAX, u64::MAX, u64::MAX, FEE_NUMERATOR, FEE_DENOMINATOR),
+            9_211_828_392_252_955_061
+        );
+        assert_eq!(
+            constant_product_out(
+                1_000_000_000,
+                u64::MAX,
+                u64::MAX,
+                FEE_NUMERATOR,
+                FEE_DENOMINATOR
+            ),
+            997_499_999
+        );
+    }
+
+    #[test]
+    fn reserves_exclude_fees_owed_to_the_pool_owner() {
+        let (base, quote) = pool_reserves(
+            (TOKEN_RESERVE, SOL_RESERVE),
+            (0, 0),
+            (1_000_000_000_000, 500_000_000)
+        );
+
+        assert_eq!((base, quote), (999_000_000_000_000, 99_500_000_000));
+        assert_eq!(
+            constant_product_out(1_000_000_000, quote, base, FEE_NUMERATOR, FEE_DENOMINATOR),
+            9_915_694_420_259
+        );
+        assert_eq!(pool_reserves((100, 100), (0, 0), (150, 0)), (0, 100));
+    }
+
+    #[test]
+    fn reserves_include_tokens_held_in_open_orders() {
+        assert_eq!(
+            pool_reserves((TOKEN_RESERVE, SOL_RESERVE), (5_000, 700), (1_000, 200)),
+            (TOKEN_RESERVE + 4_000, SOL_RESERVE + 500)
+        );
+        assert_eq!(pool_reserves((100, 100), (50, 0), (150, 0)), (0, 100));
+    }
+
+    #[test]
+    fn open_orders_totals_are_read_after_the_padding() {
+        let mut data = vec![0u8; 3228];
+        data[..5].copy_from_slice(b"serum");
+        data[77..85].copy_from_slice(&1u64.to_le_bytes());
+        data[85..93].copy_from_slice(&2_000u64.to_le_bytes());
+        data[93..101].copy_from_slice(&3u64.to_le_bytes());
+        data[101..109].copy_from_slice(&4_000u64.to_le_bytes());
+
+        let layout: MinimalOpenOrdersLayout = bincode::deserialize(&data).unwrap();
+
+        assert_eq!((layout.base_token_total, layout.quote_token_total), (2_000, 4_000));
+    }
+
+    #[test]
+    fn slippage_is_a_percentage_of_the_amount() {
+        assert_eq!(apply_slippage(1_000_000, 0.0), 1_000_000);
+        assert_eq!(apply_slippage(1_000_000, 30.0), 700_000);
+        assert_eq!(apply_slippage(1_000_000, 100.0), 0);
+    }
+
+    #[test]
+    fn slippage_is_clamped_and_does_not_overflow() {
+        assert_eq!(apply_slippage(1_000_000, -5.0), 1_000_000);
+        assert_eq!(apply_slippage(1_000_000, 150.0), 0);
+        assert_eq!(apply_slippage(u64::MAX, 0.0), u64::MAX);
+        assert_eq!(apply_slippage(u64::MAX, 30.0), 12_912_720_851_596_686_130);
+    }
+}
@@ -0,0 +1,211 @@
+use crate::buy::raydium_sdk::LiquidityPoolKeysString;
+use crate::sell::sell::SellTransaction;
+use serde::{ Deserialize, Serialize };
+use solana_sdk::pubkey::Pubkey;
+use std::str::FromStr;
+
+/// Highest `version` of the `trading` message schema this build understands.
+/// Messages without a `version` field are treated as version 1.
+pub const SCHEMA_VERSION: u64 = 1;
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BuyTransaction {
+    pub in_token: String,
+    pub out_token: String,
+    /// SOL to spend. Ignored when `token_amount` is set
+    #[serde(default)]
+    pub amount_in: f64,
+    /// Buy exactly this many raw tokens instead of spending `amount_in` (pump.fun only)
+    #[serde(default)]
+    pub token_amount: Option<u64>,
+    pub key_z: Option<LiquidityPoolKeysString>,
+    pub lp_decimals: u8,
+    pub group_title: String,
+    pub user_name: String,
+    /// Slippage tolerance in percent
+    #[serde(default)]
+    pub slippage: Option<f64>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(tag = "type_", rename_all = "lowercase")]
+pub enum TradeCommand {
+    Buy(BuyTransaction),
+    Sell(SellTransaction),
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum CommandError {
+    #[error("Malformed payload: {0}")] Malformed(String),
+    #[error("Unsupported schema version {0}, this build supports up to {}", SCHEMA_VERSION)]
+    UnsupportedVersion(u64),
+    #[error("Unknown command type: {0}")] UnknownType(String),
+    #[error("Invalid mint in `{field}`: {value}")] InvalidMint {
+        field: &'static str,
+        value: String,
+    },
+    #[error("Invalid amount in `{field}`: {value}")] InvalidAmount {
+        field: &'static str,
+        value: f64,
+    },
+    #[error("Invalid slippage {0}, expected a percentage in (0, 100]")] InvalidSlippage(f64),
+    #[error("Invalid sell percentage {0}, expected a percentage in (0, 100]")] InvalidPercent(f64),
+}
+
+impl CommandError {
+    /// Stable machine-readable reason published back on `trading_confirmation`.
+    pub fn code(&self) -> &'static str {
+        match self {
+            CommandError::Malformed(_) => "malformed_payload",
+            CommandError::UnsupportedVersion(_) => "unsupported_version",
+            CommandError::UnknownType(_) => "unknown_type",
+            CommandError::InvalidMint { .. } => "invalid_mint",
+            CommandError::InvalidAmount { .. } => "invalid_amount",
+            CommandError::InvalidSlippage(_) => "invalid_slippage",
+            CommandError::InvalidPercent(_) => "invalid_percent",
+        }
+    }
+
+    pub fn field(&self) -> Option<&'static str> {
+        match self {
+            CommandError::InvalidMint { field, .. } => Some(*field),
+            CommandError::InvalidAmount { field, .. } => Some(*field),
+            CommandError::InvalidSlippage(_) => Some("slippage"),
+            CommandError::InvalidPercent(_) => Some("percent"),
+            _ => None,
+        }
+    }
+}
+
+/// Parses and validates a raw `trading` message, returning the command and the mint it targets.
+pub fn parse_trade_command(payload: &str) -> Result<(TradeCommand, Pubkey), CommandError> {
+    let value: serde_json::Value = serde_json
+        ::from_str(payload)
+        .map_err(|e| CommandError::Malformed(e.to_string()))?;
+
+    let version = match value.get("version") {
+        None => 1,
+        Some(version) =>
+            version
+                .as_u64()
+                .ok_or_else(|| CommandError::Malformed("`version` must be an integer".to_string()))?,
+    };
+    if version == 0 || version > SCHEMA_VERSION {
+        return Err(CommandError::UnsupportedVersion(version));
+    }
+
+    match value["type_"].as_str() {
+        Some("buy") | Some("sell") => {}
+        Some(other) => {
+            return Err(CommandError::UnknownType(other.to_string()));
+        }
+        None => {
+            return Err(CommandError::Malformed("missing `type_` field".to_string()));
+        }
+    }
+
+    let command: TradeCommand = serde_json
+        ::from_value(value)
+        .map_err(|e| CommandError::Malformed(e.to_string()))?;
+    let mint = command.validate()?;
+
+    Ok((command, mint))
+}
+
+/// Best-effort mint lookup for messages that failed to parse, so rejections can still be correlated.
+pub fn raw_mint(payload: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(payload).ok()?;
+    value["mint"]
+        .as_str()
+        .or_else(|| value["in_token"].as_str())
+        .map(|mint| mint.to_string())
+}
+
+/// Client-supplied id used to deduplicate retried commands.
+pub fn request_id(payload: &str) -> Option<String> {
+    let value: serde_json::Value = serde_json::from_str(payload).ok()?;
+    value["request_id"]
+        .as_str()
+        .filter(|id| !id.is_empty())
+        .map(|id| id.to_string())
+}
+
+impl TradeCommand {
+    fn validate(&self) -> Result<Pubkey, CommandError> {
+        match self {
+            TradeCommand::Buy(tx) => {
+                let mint = parse_mint("in_token", &tx.in_token)?;
+                match tx.token_amount {
+                    Some(token_amount) => positive_amount("token_amount", token_amount as f64)?,
+                    None => positive_amount("amount_in", tx.amount_in)?,
+                }
+                tx.slippage.map(validate_slippage).transpose()?;
+                Ok(mint)
+            }
+            TradeCommand::Sell(tx) => {
+                let mint = parse_mint("mint", &tx.mint)?;
+                match tx.percent {
+                    Some(percent) => validate_percent(percent)?,
+                    None => positive_amount("amount", tx.amount as f64)?,
+                }
+                non_negative_amount("sol_amount", tx.sol_amount)?;
+                non_negative_amount("entry", tx.entry)?;
+                non_negative_amount("current_token_price_sol", tx.current_token_price_sol)?;
+                non_negative_amount("current_token_price_usd", tx.current_token_price_usd)?;
+                // Pump.fun positions have no Raydium vaults, so these may be empty
+                if !tx.base_vault.is_empty() {
+                    parse_mint("base_vault", &tx.base_vault)?;
+                }
+                if !tx.quote_vault.is_empty() {
+                    parse_mint("quote_vault", &tx.quote_vault)?;
+                }
+                tx.slippage.map(validate_slippage).transpose()?;
+                Ok(mint)
+            }
+        }
+    }
+}
+
+fn parse_mint(field: &'static str, value: &str) -> Result<Pubkey, CommandError> {
+    Pubkey::from_str(value).map_err(|_| CommandError::InvalidMint {
+        field,
+        value: value.to_string(),
+    })
+}
+
+fn positive_amount(field: &'static str, value: f64) -> Result<(), CommandError> {
+    if value.is_finite() && value > 0.0 {
+        Ok(())
+    } else {
+        Err(CommandError::InvalidAmount { field, value })
+    }
+}
+
+fn non_negative_amount(field: &'static str, value: f64) -> Result<(), CommandError> {
+    if value.is_finite() && value >= 0.0 {
+        Ok(())
+    } else {
+        Err(CommandError::InvalidAmount { field, value })
+    }
+}
+
+/// Whether `value` is a percentage in (0, 100], the range every slippage and sell share uses.
+pub fn is_percentage(value: f64) -> bool {
+    value.is_finite() && value > 0.0 && value <= 100.0
+}
+
+pub fn validate_slippage(slippage: f64) -> Result<(), CommandError> {
+    if is_percentage(slippage) {
+        Ok(())
+    } else {
+        Err(CommandError::InvalidSlippage(slippage))
+    }
+}
+
+fn validate_percent(percent: f64) -> Result<(), CommandError> {
+    if is_percentage(percent) {
+        Ok(())
+    } else {
+        Err(CommandError::InvalidPercent(percent))
+    }
+}
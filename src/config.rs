@@ -0,0 +1,706 @@
+use crate::buy::buy::DEFAULT_BUY_SLIPPAGE;
+use crate::command::is_percentage;
+use crate::sell::sell::DEFAULT_SELL_SLIPPAGE;
+use crate::dispatch::DEFAULT_MAX_IN_FLIGHT;
+use crate::idempotency::{ DEFAULT_REQUEST_LEASE_SECS, DEFAULT_REQUEST_TTL_SECS };
+use crate::shutdown::DEFAULT_SHUTDOWN_DEADLINE_SECS;
+use crate::signer::{ SignerBackend, SignerConfig, SignerError };
+use crate::stream::DEFAULT_CLAIM_IDLE_MS;
+use helius::jito::{ JitoRegion, JITO_API_URLS };
+use serde::Deserialize;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashSet;
+use std::path::Path;
+use std::str::FromStr;
+
+const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+/// Name of the wallet loaded from `[signer]`, used when no `[[wallets]]` entry matches.
+pub const DEFAULT_WALLET_NAME: &str = "default";
+
+/// Close-account instructions that still fit in one transaction alongside their accounts.
+const MAX_SWEEP_BATCH_SIZE: usize = 25;
+
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("Failed to read config file {path}: {source}")] Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Failed to parse config file {path}: {source}")] Parse {
+        path: String,
+        source: toml::de::Error,
+    },
+    #[error("Missing required setting `{field}`, set it in the config file or via {var}")]
+    Missing {
+        field: &'static str,
+        var: &'static str,
+    },
+    #[error("Environment variable {var} has an invalid value: {value}")] InvalidEnv {
+        var: &'static str,
+        value: String,
+    },
+    #[error("Invalid setting `{field}`: {reason}")] Invalid {
+        field: &'static str,
+        reason: String,
+    },
+    #[error("Failed to load signer for wallet {wallet}: {source}")] Signer {
+        wallet: String,
+        source: SignerError,
+    },
+}
+
+/// All runtime settings, loaded once at startup from an optional TOML file and the environment.
+///
+/// Environment variables take precedence over the file, which takes precedence over defaults.
+#[derive(Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub rpc_url: String,
+    pub redis_url: String,
+    pub mongodb_uri: String,
+    pub helius_api_key: String,
+    /// Where the default wallet's keypair comes from
+    pub signer: SignerConfig,
+    /// Expected public key of the default wallet, checked at startup to catch a mismatched key
+    pub wallet: Option<String>,
+    /// Extra wallets that trades are routed to by `user_name` or `group_title`
+    pub wallets: Vec<WalletConfig>,
+    pub trading: TradingConfig,
+    pub jito: JitoConfig,
+    pub mongo: MongoConfig,
+    pub intake: IntakeConfig,
+    pub paper: PaperConfig,
+    pub positions: PositionsConfig,
+    pub price_feed: PriceFeedConfig,
+    pub sweeper: SweeperConfig,
+}
+
+/// A `[[wallets]]` entry. Trades whose `user_name` is in `users`, or failing that whose
+/// `group_title` is in `groups`, are signed by this wallet instead of the default one.
+#[derive(Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WalletConfig {
+    pub name: String,
+    pub signer: SignerConfig,
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TradingConfig {
+    /// Raydium buy slippage in percent when the command doesn't carry one
+    pub buy_slippage: f64,
+    /// Raydium buy slippage used when resending after a failed send
+    pub buy_retry_slippage: f64,
+    /// Raydium buy slippage used when resending after compute unit estimation failed
+    pub buy_compute_retry_slippage: f64,
+    /// Raydium sell slippage in percent when the command doesn't carry one
+    pub sell_slippage: f64,
+    /// pump.fun buy slippage in percent: how far above the quoted SOL cost a buy may pay
+    pub pump_buy_slippage: f64,
+    /// pump.fun sell slippage in percent when the command doesn't carry one
+    pub pump_sell_slippage: f64,
+    /// Send attempts for Raydium swaps
+    pub max_retries: u32,
+    /// Send attempts for pump.fun trades
+    pub pump_max_retries: u32,
+    /// Raw token balance at or below which a Raydium position is treated as already sold
+    pub min_balance_threshold: u64,
+    /// UI token balance at or below which a pump.fun position is treated as already sold
+    pub pump_dust_epsilon: f64,
+    /// Simulate each trade before sending it and abort with the decoded failure reason
+    pub simulate_before_send: bool,
+    /// Times to re-check a completed bonding curve for its Raydium pool before giving up
+    pub migration_retries: u32,
+    /// Delay between migration re-checks, in milliseconds
+    pub migration_retry_delay_ms: u64,
+    /// Close the wSOL account in each Raydium sell so its proceeds arrive as native SOL.
+    /// Raydium buys and sells recreate and fund the account in their own transaction
+    pub unwrap_wsol: bool,
+}
+
+impl Default for TradingConfig {
+    fn default() -> Self {
+        Self {
+            buy_slippage: DEFAULT_BUY_SLIPPAGE,
+            buy_retry_slippage: 5.0,
+            buy_compute_retry_slippage: 8.0,
+            sell_slippage: DEFAULT_SELL_SLIPPAGE,
+            pump_buy_slippage: DEFAULT_BUY_SLIPPAGE,
+            pump_sell_slippage: 80.0,
+            max_retries: 3,
+            pump_max_retries: 4,
+            min_balance_threshold: 500_000,
+            pump_dust_epsilon: 1.0,
+            simulate_before_send: false,
+            migration_retries: 10,
+            migration_retry_delay_ms: 3_000,
+            unwrap_wsol: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct JitoConfig {
+    pub tip_lamports: u64,
+    pub region: String,
+}
+
+impl Default for JitoConfig {
+    fn default() -> Self {
+        Self {
+            tip_lamports: 600_000,
+            region: "NY".to_string(),
+        }
+    }
+}
+
+impl JitoConfig {
+    /// The configured block engine region, in the `'static` form the Helius SDK expects.
+    pub fn region(&self) -> JitoRegion {
+        JITO_API_URLS.get_entry(self.region.as_str())
+            .map(|(region, _)| *region)
+            .unwrap_or("Default")
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MongoConfig {
+    /// Holds `tokens`, `buy_transactions` and `sell_transactions`
+    pub trades_db: String,
+    /// Holds `trade_states`
+    pub state_db: String,
+    /// Set when paper trading has pointed the databases above at `[paper]`
+    #[serde(skip)]
+    pub paper: bool,
+}
+
+impl Default for MongoConfig {
+    fn default() -> Self {
+        Self {
+            trades_db: "solsniper".to_string(),
+            state_db: "trading".to_string(),
+            paper: false,
+        }
+    }
+}
+
+/// Paper trading: transactions are built and simulated but never sent, fills come from
+/// the quote, and records go to separate databases tagged `paper`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PaperConfig {
+    pub enabled: bool,
+    pub trades_db: String,
+    pub state_db: String,
+}
+
+impl Default for PaperConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            trades_db: "solsniper_paper".to_string(),
+            state_db: "trading_paper".to_string(),
+        }
+    }
+}
+
+/// Built-in position manager: watches every open position and sells on the take-profit
+/// ladder, the trailing stop, the breakeven stop and the stop loss. Percentages are profit
+/// relative to the entry price; a stop set to 0 is disabled.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PositionsConfig {
+    pub enabled: bool,
+    pub poll_interval_ms: u64,
+    /// Rungs in ascending `profit_pct` order, each taken once
+    pub take_profit: Vec<TakeProfitLevel>,
+    /// Sell everything once profit falls this many points below its high
+    pub trailing_stop_pct: f64,
+    /// Profit the position must have reached before the trailing stop arms
+    pub trailing_activation_pct: f64,
+    /// Profit at which the stop moves up to breakeven
+    pub breakeven_trigger_pct: f64,
+    /// Sell everything at this loss
+    pub stop_loss_pct: f64,
+}
+
+/// At `profit_pct` profit, sell `sell_pct` percent of what is left of the position.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TakeProfitLevel {
+    pub profit_pct: f64,
+    pub sell_pct: f64,
+}
+
+impl Default for PositionsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            poll_interval_ms: 5_000,
+            take_profit: vec![
+                TakeProfitLevel { profit_pct: 100.0, sell_pct: 50.0 },
+                TakeProfitLevel { profit_pct: 300.0, sell_pct: 50.0 }
+            ],
+            trailing_stop_pct: 30.0,
+            trailing_activation_pct: 50.0,
+            breakeven_trigger_pct: 50.0,
+            stop_loss_pct: 50.0,
+        }
+    }
+}
+
+/// Live prices for open positions from account subscriptions on the Helius websocket.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PriceFeedConfig {
+    pub enabled: bool,
+    /// Redis channel every price tick is published on
+    pub channel: String,
+    /// How often open positions are listed to subscribe new ones and drop sold ones
+    pub refresh_interval_ms: u64,
+    /// How long the latest tick of a mint stays readable under `price:<mint>`
+    pub tick_ttl_secs: u64,
+}
+
+impl Default for PriceFeedConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            channel: "price_ticks".to_string(),
+            refresh_interval_ms: 10_000,
+            tick_ttl_secs: 30,
+        }
+    }
+}
+
+/// Periodic cleanup that closes the wallets' empty token accounts to reclaim their rent and
+/// unwraps leftover wSOL.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SweeperConfig {
+    pub enabled: bool,
+    pub interval_secs: u64,
+    /// Accounts closed per transaction
+    pub batch_size: usize,
+    /// Also close wSOL accounts that still hold SOL, unwrapping it into the wallet
+    pub unwrap_wsol: bool,
+}
+
+impl Default for SweeperConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_secs: 3_600,
+            batch_size: 20,
+            unwrap_wsol: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IntakeMode {
+    Pubsub,
+    Stream,
+}
+
+impl FromStr for IntakeMode {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "pubsub" => Ok(IntakeMode::Pubsub),
+            "stream" => Ok(IntakeMode::Stream),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct IntakeConfig {
+    pub mode: IntakeMode,
+    /// Pub/sub channel read in `pubsub` mode
+    pub channel: String,
+    pub max_in_flight: usize,
+    /// How long a request's confirmation is kept for replaying to duplicates
+    pub request_ttl_secs: u64,
+    /// Lease held on a request while it executes, renewed until it finishes. A consumer that
+    /// dies stops renewing, so another one can take the request over once the lease runs out
+    pub request_lease_secs: u64,
+    pub shutdown_deadline_secs: u64,
+    pub reconcile_on_startup: bool,
+    pub reconcile_backfill: bool,
+    pub stream: String,
+    pub stream_group: String,
+    /// Defaults to the hostname, then the process id
+    pub stream_consumer: Option<String>,
+    pub stream_claim_idle_ms: usize,
+}
+
+impl Default for IntakeConfig {
+    fn default() -> Self {
+        Self {
+            mode: IntakeMode::Pubsub,
+            channel: "trading".to_string(),
+            max_in_flight: DEFAULT_MAX_IN_FLIGHT,
+            request_ttl_secs: DEFAULT_REQUEST_TTL_SECS,
+            request_lease_secs: DEFAULT_REQUEST_LEASE_SECS,
+            shutdown_deadline_secs: DEFAULT_SHUTDOWN_DEADLINE_SECS,
+            reconcile_on_startup: true,
+            reconcile_backfill: false,
+            stream: "trading".to_string(),
+            stream_group: "redis_sniper".to_string(),
+            stream_consumer: None,
+            stream_claim_idle_ms: DEFAULT_CLAIM_IDLE_MS,
+        }
+    }
+}
+
+impl IntakeConfig {
+    /// Name this process reads the trade stream and holds request leases under.
+    pub fn consumer_name(&self) -> String {
+        self.stream_consumer
+            .clone()
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| format!("redis_sniper-{}", std::process::id()))
+    }
+}
+
+impl Config {
+    /// Reads `CONFIG_PATH` (or `config.toml` when present), applies env overrides and validates.
+    pub fn load() -> Result<Self, ConfigError> {
+        let mut config = match std::env::var("CONFIG_PATH") {
+            Ok(path) => Self::from_file(&path)?,
+            Err(_) if Path::new(DEFAULT_CONFIG_PATH).exists() => {
+                Self::from_file(DEFAULT_CONFIG_PATH)?
+            }
+            Err(_) => Self::default(),
+        };
+
+        config.apply_env()?;
+        config.validate()?;
+
+        if config.paper.enabled {
+            config.mongo = MongoConfig {
+                trades_db: config.paper.trades_db.clone(),
+                state_db: config.paper.state_db.clone(),
+                paper: true,
+            };
+        }
+
+        Ok(config)
+    }
+
+    fn from_file(path: &str) -> Result<Self, ConfigError> {
+        let contents = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_string(),
+            source,
+        })?;
+
+        toml::from_str(&contents).map_err(|source| ConfigError::Parse {
+            path: path.to_string(),
+            source,
+        })
+    }
+
+    fn apply_env(&mut self) -> Result<(), ConfigError> {
+        env_override(&mut self.rpc_url, "RPC_URL")?;
+        env_override(&mut self.redis_url, "REDIS_URL")?;
+        env_override(&mut self.mongodb_uri, "MONGODB_URI")?;
+        env_override(&mut self.helius_api_key, "HELIUS_API_KEY")?;
+        env_override(&mut self.signer.backend, "SIGNER_BACKEND")?;
+        env_override(&mut self.signer.env_var, "SIGNER_ENV_VAR")?;
+        env_override(&mut self.signer.password_env, "SIGNER_PASSWORD_ENV")?;
+        if let Ok(path) = std::env::var("SIGNER_PATH") {
+            self.signer.path = Some(path);
+        }
+        if let Ok(wallet) = std::env::var("WALLET_ADDRESS") {
+            self.wallet = Some(wallet);
+        }
+
+        let trading = &mut self.trading;
+        env_override(&mut trading.buy_slippage, "BUY_SLIPPAGE")?;
+        env_override(&mut trading.buy_retry_slippage, "BUY_RETRY_SLIPPAGE")?;
+        env_override(&mut trading.buy_compute_retry_slippage, "BUY_COMPUTE_RETRY_SLIPPAGE")?;
+        env_override(&mut trading.sell_slippage, "SELL_SLIPPAGE")?;
+        env_override(&mut trading.pump_buy_slippage, "PUMP_BUY_SLIPPAGE")?;
+        env_override(&mut trading.pump_sell_slippage, "PUMP_SELL_SLIPPAGE")?;
+        env_override(&mut trading.max_retries, "MAX_RETRIES")?;
+        env_override(&mut trading.pump_max_retries, "PUMP_MAX_RETRIES")?;
+        env_override(&mut trading.min_balance_threshold, "MIN_BALANCE_THRESHOLD")?;
+        env_override(&mut trading.pump_dust_epsilon, "PUMP_DUST_EPSILON")?;
+        env_override(&mut trading.simulate_before_send, "SIMULATE_BEFORE_SEND")?;
+        env_override(&mut trading.migration_retries, "MIGRATION_RETRIES")?;
+        env_override(&mut trading.migration_retry_delay_ms, "MIGRATION_RETRY_DELAY_MS")?;
+        env_override(&mut trading.unwrap_wsol, "UNWRAP_WSOL")?;
+
+        env_override(&mut self.jito.tip_lamports, "JITO_TIP_LAMPORTS")?;
+        env_override(&mut self.jito.region, "JITO_REGION")?;
+
+        env_override(&mut self.mongo.trades_db, "MONGO_TRADES_DB")?;
+        env_override(&mut self.mongo.state_db, "MONGO_STATE_DB")?;
+
+        env_override(&mut self.paper.enabled, "PAPER_TRADING")?;
+        env_override(&mut self.paper.trades_db, "PAPER_TRADES_DB")?;
+        env_override(&mut self.paper.state_db, "PAPER_STATE_DB")?;
+
+        let positions = &mut self.positions;
+        env_override(&mut positions.enabled, "POSITIONS_ENABLED")?;
+        env_override(&mut positions.poll_interval_ms, "POSITIONS_POLL_INTERVAL_MS")?;
+        env_override(&mut positions.trailing_stop_pct, "POSITIONS_TRAILING_STOP_PCT")?;
+        env_override(&mut positions.trailing_activation_pct, "POSITIONS_TRAILING_ACTIVATION_PCT")?;
+        env_override(&mut positions.breakeven_trigger_pct, "POSITIONS_BREAKEVEN_TRIGGER_PCT")?;
+        env_override(&mut positions.stop_loss_pct, "POSITIONS_STOP_LOSS_PCT")?;
+
+        let price_feed = &mut self.price_feed;
+        env_override(&mut price_feed.enabled, "PRICE_FEED_ENABLED")?;
+        env_override(&mut price_feed.channel, "PRICE_FEED_CHANNEL")?;
+        env_override(&mut price_feed.refresh_interval_ms, "PRICE_FEED_REFRESH_INTERVAL_MS")?;
+        env_override(&mut price_feed.tick_ttl_secs, "PRICE_FEED_TICK_TTL_SECS")?;
+
+        let sweeper = &mut self.sweeper;
+        env_override(&mut sweeper.enabled, "SWEEPER_ENABLED")?;
+        env_override(&mut sweeper.interval_secs, "SWEEPER_INTERVAL_SECS")?;
+        env_override(&mut sweeper.batch_size, "SWEEPER_BATCH_SIZE")?;
+        env_override(&mut sweeper.unwrap_wsol, "SWEEPER_UNWRAP_WSOL")?;
+
+        let intake = &mut self.intake;
+        env_override(&mut intake.mode, "TRADE_INTAKE")?;
+        env_override(&mut intake.channel, "TRADE_CHANNEL")?;
+        env_override(&mut intake.max_in_flight, "MAX_IN_FLIGHT_TRADES")?;
+        env_override(&mut intake.request_ttl_secs, "REQUEST_ID_TTL_SECS")?;
+        env_override(&mut intake.request_lease_secs, "REQUEST_LEASE_SECS")?;
+        env_override(&mut intake.shutdown_deadline_secs, "SHUTDOWN_DEADLINE_SECS")?;
+        env_override(&mut intake.reconcile_on_startup, "RECONCILE_ON_STARTUP")?;
+        env_override(&mut intake.reconcile_backfill, "RECONCILE_BACKFILL")?;
+        env_override(&mut intake.stream, "TRADE_STREAM")?;
+        env_override(&mut intake.stream_group, "TRADE_STREAM_GROUP")?;
+        env_override(&mut intake.stream_claim_idle_ms, "TRADE_STREAM_CLAIM_IDLE_MS")?;
+        if let Ok(consumer) = std::env::var("TRADE_STREAM_CONSUMER") {
+            intake.stream_consumer = Some(consumer);
+        }
+
+        Ok(())
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        require(&self.rpc_url, "rpc_url", "RPC_URL")?;
+        require(&self.redis_url, "redis_url", "REDIS_URL")?;
+        require(&self.mongodb_uri, "mongodb_uri", "MONGODB_URI")?;
+        require(&self.helius_api_key, "helius_api_key", "HELIUS_API_KEY")?;
+        validate_signer(&self.signer, "signer")?;
+        if let Some(wallet) = &self.wallet {
+            Pubkey::from_str(wallet).map_err(|_|
+                invalid("wallet", &format!("{} is not a valid public key", wallet))
+            )?;
+        }
+
+        self.validate_wallets()?;
+
+        let trading = &self.trading;
+        percentage(trading.buy_slippage, "trading.buy_slippage")?;
+        percentage(trading.buy_retry_slippage, "trading.buy_retry_slippage")?;
+        percentage(trading.buy_compute_retry_slippage, "trading.buy_compute_retry_slippage")?;
+        percentage(trading.sell_slippage, "trading.sell_slippage")?;
+        percentage(trading.pump_buy_slippage, "trading.pump_buy_slippage")?;
+        percentage(trading.pump_sell_slippage, "trading.pump_sell_slippage")?;
+        at_least_one(trading.max_retries as u64, "trading.max_retries")?;
+        at_least_one(trading.pump_max_retries as u64, "trading.pump_max_retries")?;
+        at_least_one(trading.migration_retry_delay_ms, "trading.migration_retry_delay_ms")?;
+        if !(trading.pump_dust_epsilon.is_finite() && trading.pump_dust_epsilon >= 0.0) {
+            return Err(invalid("trading.pump_dust_epsilon", "must be zero or positive"));
+        }
+
+        at_least_one(self.jito.tip_lamports, "jito.tip_lamports")?;
+        if !JITO_API_URLS.contains_key(self.jito.region.as_str()) {
+            let known: Vec<&str> = JITO_API_URLS.keys().copied().collect();
+            return Err(
+                invalid(
+                    "jito.region",
+                    &format!("unknown region {:?}, expected one of {:?}", self.jito.region, known)
+                )
+            );
+        }
+
+        if self.mongo.trades_db.is_empty() {
+            return Err(invalid("mongo.trades_db", "must not be empty"));
+        }
+        if self.mongo.state_db.is_empty() {
+            return Err(invalid("mongo.state_db", "must not be empty"));
+        }
+        if self.paper.enabled {
+            let live = [self.mongo.trades_db.as_str(), self.mongo.state_db.as_str()];
+            if self.paper.trades_db.is_empty() || live.contains(&self.paper.trades_db.as_str()) {
+                return Err(
+                    invalid("paper.trades_db", "must be set and differ from the live databases")
+                );
+            }
+            if self.paper.state_db.is_empty() || live.contains(&self.paper.state_db.as_str()) {
+                return Err(
+                    invalid("paper.state_db", "must be set and differ from the live databases")
+                );
+            }
+        }
+
+        self.validate_positions()?;
+
+        if self.price_feed.channel.is_empty() {
+            return Err(invalid("price_feed.channel", "must not be empty"));
+        }
+        at_least_one(self.price_feed.refresh_interval_ms, "price_feed.refresh_interval_ms")?;
+        at_least_one(self.price_feed.tick_ttl_secs, "price_feed.tick_ttl_secs")?;
+
+        at_least_one(self.sweeper.interval_secs, "sweeper.interval_secs")?;
+        at_least_one(self.sweeper.batch_size as u64, "sweeper.batch_size")?;
+        if self.sweeper.batch_size > MAX_SWEEP_BATCH_SIZE {
+            return Err(
+                invalid(
+                    "sweeper.batch_size",
+                    &format!("at most {} accounts fit in one transaction", MAX_SWEEP_BATCH_SIZE)
+                )
+            );
+        }
+
+        at_least_one(self.intake.max_in_flight as u64, "intake.max_in_flight")?;
+        at_least_one(self.intake.request_ttl_secs, "intake.request_ttl_secs")?;
+        at_least_one(self.intake.request_lease_secs, "intake.request_lease_secs")?;
+        // A stale stream entry must only be claimed once its owner's lease has run out
+        if self.intake.request_lease_secs * 1_000 >= (self.intake.stream_claim_idle_ms as u64) {
+            return Err(
+                invalid("intake.request_lease_secs", "must be shorter than stream_claim_idle_ms")
+            );
+        }
+        if self.intake.channel.is_empty() {
+            return Err(invalid("intake.channel", "must not be empty"));
+        }
+        if self.intake.stream.is_empty() || self.intake.stream_group.is_empty() {
+            return Err(invalid("intake.stream", "stream and stream_group must not be empty"));
+        }
+
+        Ok(())
+    }
+
+    fn validate_positions(&self) -> Result<(), ConfigError> {
+        let positions = &self.positions;
+        at_least_one(positions.poll_interval_ms, "positions.poll_interval_ms")?;
+
+        let mut previous = 0.0;
+        for level in &positions.take_profit {
+            positive(level.profit_pct, "positions.take_profit.profit_pct")?;
+            percentage(level.sell_pct, "positions.take_profit.sell_pct")?;
+            if level.profit_pct <= previous {
+                return Err(
+                    invalid("positions.take_profit", "levels must be in ascending profit_pct order")
+                );
+            }
+            previous = level.profit_pct;
+        }
+
+        non_negative(positions.trailing_stop_pct, "positions.trailing_stop_pct")?;
+        non_negative(positions.trailing_activation_pct, "positions.trailing_activation_pct")?;
+        non_negative(positions.breakeven_trigger_pct, "positions.breakeven_trigger_pct")?;
+        non_negative(positions.stop_loss_pct, "positions.stop_loss_pct")?;
+        if positions.stop_loss_pct > 100.0 {
+            return Err(invalid("positions.stop_loss_pct", "a loss can't exceed 100%"));
+        }
+
+        Ok(())
+    }
+
+    fn validate_wallets(&self) -> Result<(), ConfigError> {
+        let mut names = HashSet::from([DEFAULT_WALLET_NAME]);
+        let mut users = HashSet::new();
+        let mut groups = HashSet::new();
+
+        for wallet in &self.wallets {
+            if !names.insert(wallet.name.as_str()) {
+                return Err(invalid("wallets", &format!("duplicate wallet name {:?}", wallet.name)));
+            }
+            validate_signer(&wallet.signer, "wallets.signer")?;
+
+            if let Some(user) = wallet.users.iter().find(|user| !users.insert(user.as_str())) {
+                return Err(invalid("wallets.users", &format!("{:?} is mapped to two wallets", user)));
+            }
+            if let Some(group) = wallet.groups.iter().find(|group| !groups.insert(group.as_str())) {
+                return Err(
+                    invalid("wallets.groups", &format!("{:?} is mapped to two wallets", group))
+                );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Checks that the backend has what it needs. Keys are only read when the keystore is built.
+fn validate_signer(signer: &SignerConfig, field: &'static str) -> Result<(), ConfigError> {
+    match signer.backend {
+        SignerBackend::Env if signer.env_var.is_empty() => {
+            Err(invalid(field, "env_var must not be empty for the env backend"))
+        }
+        SignerBackend::Keystore if signer.password_env.is_empty() => {
+            Err(invalid(field, "password_env must not be empty for the keystore backend"))
+        }
+        SignerBackend::Keystore | SignerBackend::SolanaCli if signer.path.is_none() => {
+            Err(invalid(field, "path is required for file backends"))
+        }
+        _ => Ok(()),
+    }
+}
+
+fn env_override<T: FromStr>(target: &mut T, var: &'static str) -> Result<(), ConfigError> {
+    if let Ok(value) = std::env::var(var) {
+        *target = value.parse().map_err(|_| ConfigError::InvalidEnv { var, value })?;
+    }
+    Ok(())
+}
+
+fn require(value: &str, field: &'static str, var: &'static str) -> Result<(), ConfigError> {
+    if value.is_empty() {
+        return Err(ConfigError::Missing { field, var });
+    }
+    Ok(())
+}
+
+fn percentage(value: f64, field: &'static str) -> Result<(), ConfigError> {
+    if !is_percentage(value) {
+        return Err(invalid(field, &format!("{} is not a percentage in (0, 100]", value)));
+    }
+    Ok(())
+}
+
+fn positive(value: f64, field: &'static str) -> Result<(), ConfigError> {
+    if !(value.is_finite() && value > 0.0) {
+        return Err(invalid(field, &format!("{} must be positive", value)));
+    }
+    Ok(())
+}
+
+fn non_negative(value: f64, field: &'static str) -> Result<(), ConfigError> {
+    if !(value.is_finite() && value >= 0.0) {
+        return Err(invalid(field, &format!("{} must be zero or positive", value)));
+    }
+    Ok(())
+}
+
+fn at_least_one(value: u64, field: &'static str) -> Result<(), ConfigError> {
+    if value == 0 {
+        return Err(invalid(field, "must be at least 1"));
+    }
+    Ok(())
+}
+
+fn invalid(field: &'static str, reason: &str) -> ConfigError {
+    ConfigError::Invalid {
+        field,
+        reason: reason.to_string(),
+    }
+}
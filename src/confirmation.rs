@@ -0,0 +1,425 @@
+use crate::buy::buy::SwapError;
+use crate::buy::mongo::MongoHandler as BuyMongoHandler;
+use crate::command::CommandError;
+use crate::context::AppContext;
+use crate::sell::mongo::MongoHandler as SellMongoHandler;
+use crate::simulation::SimulationError;
+use crate::wallet::Wallet;
+use helius::error::HeliusError;
+use redis::AsyncCommands;
+use serde::{ Deserialize, Serialize };
+use solana_client::client_error::ClientError;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use solana_sdk::transaction::TransactionError;
+use std::error::Error;
+use std::sync::atomic::{ AtomicU64, Ordering };
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+pub const CONFIRMATION_CHANNEL: &str = "trading_confirmation";
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Success,
+    Fail,
+    Rejected,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Side {
+    Buy,
+    Sell,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Venue {
+    Raydium,
+    PumpFun,
+}
+
+/// What the trade actually did on-chain, as recorded in Mongo after confirmation.
+///
+/// `token_amount` is in raw token units, SOL amounts are in SOL and `price_sol`
+/// is the entry price for buys and the exit price for sells.
+#[derive(Debug, Clone, Serialize)]
+pub struct Fill {
+    pub token_amount: f64,
+    pub sol_amount: f64,
+    pub fee_sol: f64,
+    pub fee_usd: f64,
+    pub price_sol: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TradeError {
+    pub category: &'static str,
+    pub field: Option<&'static str>,
+    pub message: String,
+}
+
+/// The single event published on `trading_confirmation` for every trade command.
+#[derive(Debug, Clone, Serialize)]
+pub struct TradeConfirmation {
+    pub correlation_id: String,
+    pub status: Status,
+    pub side: Option<Side>,
+    pub mint: Option<String>,
+    pub venue: Option<Venue>,
+    pub signature: Option<String>,
+    pub fill: Option<Fill>,
+    pub error: Option<TradeError>,
+    pub latency_ms: u64,
+    /// Set for commands handled in paper trading mode, where nothing was sent on-chain
+    pub paper: bool,
+}
+
+impl TradeConfirmation {
+    pub fn rejected(
+        correlation_id: String,
+        mint: Option<String>,
+        error: &CommandError,
+        latency: Duration
+    ) -> Self {
+        Self {
+            correlation_id,
+            status: Status::Rejected,
+            side: None,
+            mint,
+            venue: None,
+            signature: None,
+            fill: None,
+            error: Some(TradeError {
+                category: error.code(),
+                field: error.field(),
+                message: error.to_string(),
+            }),
+            latency_ms: latency.as_millis() as u64,
+            paper: false,
+        }
+    }
+
+    pub fn success(
+        correlation_id: String,
+        side: Side,
+        mint: String,
+        venue: Venue,
+        signature: String,
+        fill: Option<Fill>,
+        latency: Duration
+    ) -> Self {
+        Self {
+            correlation_id,
+            status: Status::Success,
+            side: Some(side),
+            mint: Some(mint),
+            venue: Some(venue),
+            signature: Some(signature),
+            fill,
+            error: None,
+            latency_ms: latency.as_millis() as u64,
+            paper: false,
+        }
+    }
+
+    pub fn failed(
+        correlation_id: String,
+        side: Side,
+        mint: String,
+        venue: Option<Venue>,
+        error: &(dyn Error + 'static),
+        latency: Duration
+    ) -> Self {
+        Self {
+            correlation_id,
+            status: Status::Fail,
+            side: Some(side),
+            mint: Some(mint),
+            venue,
+            signature: None,
+            fill: None,
+            error: Some(TradeError {
+                category: error_category(error).code(),
+                field: None,
+                message: error.to_string(),
+            }),
+            latency_ms: latency.as_millis() as u64,
+            paper: false,
+        }
+    }
+
+    pub fn to_message(&self) -> Option<String> {
+        match serde_json::to_string(self) {
+            Ok(message) => Some(message),
+            Err(e) => {
+                eprintln!("Failed to serialize confirmation: {}", e);
+                None
+            }
+        }
+    }
+}
+
+pub async fn publish_message(ctx: &AppContext, message: &str) {
+    let mut connection = ctx.redis.clone();
+    if let Err(e) = connection.publish::<_, _, ()>(CONFIRMATION_CHANNEL, message).await {
+        eprintln!("Failed to send confirmation: {}", e);
+    }
+}
+
+/// Stable category published with a failed trade.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCategory {
+    SlippageExceeded,
+    BondingCurveComplete,
+    InsufficientBalance,
+    SimulationFailed,
+    SimulationUnavailable,
+    AlreadySold,
+    AmountMismatch,
+    MigrationPending,
+    PoolNotFound,
+    QuoteUnavailable,
+    BalanceUnavailable,
+    NotConfirmed,
+    SendFailed,
+    InvalidSlippage,
+    Unknown,
+}
+
+impl ErrorCategory {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ErrorCategory::SlippageExceeded => "slippage_exceeded",
+            ErrorCategory::BondingCurveComplete => "bonding_curve_complete",
+            ErrorCategory::InsufficientBalance => "insufficient_balance",
+            ErrorCategory::SimulationFailed => "simulation_failed",
+            ErrorCategory::SimulationUnavailable => "simulation_unavailable",
+            ErrorCategory::AlreadySold => "already_sold",
+            ErrorCategory::AmountMismatch => "amount_mismatch",
+            ErrorCategory::MigrationPending => "migration_pending",
+            ErrorCategory::PoolNotFound => "pool_not_found",
+            ErrorCategory::QuoteUnavailable => "quote_unavailable",
+            ErrorCategory::BalanceUnavailable => "balance_unavailable",
+            ErrorCategory::NotConfirmed => "not_confirmed",
+            ErrorCategory::SendFailed => "send_failed",
+            ErrorCategory::InvalidSlippage => "invalid_slippage",
+            ErrorCategory::Unknown => "unknown",
+        }
+    }
+}
+
+/// Failures of the buy and sell paths that clients tell apart by category.
+#[derive(Debug, thiserror::Error)]
+pub enum TradeFailure {
+    #[error("Insufficient balance. {0}")] InsufficientBalance(String),
+    #[error("Token already sold")]
+    AlreadySold,
+    #[error("Token amount does not match the buy transaction")]
+    AmountMismatch,
+    #[error("Pool info not found for the given tokens.")]
+    PoolNotFound,
+    #[error("Migration pending: {mint} has no open Raydium pool after {checks} checks")]
+    MigrationPending {
+        mint: Pubkey,
+        checks: u32,
+    },
+    #[error("Bonding curve complete: {0} has migrated to Raydium")] CurveComplete(Pubkey),
+    #[error("Failed to read bonding curve state for {mint}: {reason}")] CurveUnavailable {
+        mint: Pubkey,
+        reason: String,
+    },
+    #[error("Quote error: {0}")] QuoteUnavailable(String),
+    #[error("Failed to fetch token balance: {0}")] BalanceUnavailable(String),
+    #[error(
+        "Sell {signature} failed: slippage exceeded, the pool would pay less than the minimum of {min_amount_out} lamports"
+    )]
+    SellSlippageExceeded {
+        signature: Signature,
+        min_amount_out: u64,
+    },
+    #[error("Sell transaction {signature} failed: {err}")] SellFailed {
+        signature: Signature,
+        err: TransactionError,
+    },
+    #[error("Transaction not confirmed after {0} retries")] NotConfirmed(u32),
+    #[error("Failed to send transaction")]
+    SendFailed,
+    #[error("Failed to create transaction after retries")]
+    RetriesExhausted,
+}
+
+impl TradeFailure {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            TradeFailure::InsufficientBalance(_) => ErrorCategory::InsufficientBalance,
+            TradeFailure::AlreadySold => ErrorCategory::AlreadySold,
+            TradeFailure::AmountMismatch => ErrorCategory::AmountMismatch,
+            TradeFailure::PoolNotFound => ErrorCategory::PoolNotFound,
+            TradeFailure::MigrationPending { .. } => ErrorCategory::MigrationPending,
+            TradeFailure::CurveComplete(_) => ErrorCategory::BondingCurveComplete,
+            TradeFailure::CurveUnavailable { .. } | TradeFailure::QuoteUnavailable(_) => {
+                ErrorCategory::QuoteUnavailable
+            }
+            TradeFailure::BalanceUnavailable(_) => ErrorCategory::BalanceUnavailable,
+            TradeFailure::SellSlippageExceeded { .. } => ErrorCategory::SlippageExceeded,
+            TradeFailure::NotConfirmed(_) => ErrorCategory::NotConfirmed,
+            TradeFailure::SellFailed { .. } |
+            TradeFailure::SendFailed |
+            TradeFailure::RetriesExhausted => ErrorCategory::SendFailed,
+        }
+    }
+}
+
+impl SimulationError {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            SimulationError::SlippageExceeded { .. } => ErrorCategory::SlippageExceeded,
+            SimulationError::InsufficientFunds { .. } => ErrorCategory::InsufficientBalance,
+            SimulationError::BondingCurveComplete => ErrorCategory::BondingCurveComplete,
+            SimulationError::Program { .. } | SimulationError::Transaction(_) => {
+                ErrorCategory::SimulationFailed
+            }
+            SimulationError::Rpc(_) => ErrorCategory::SimulationUnavailable,
+        }
+    }
+}
+
+/// Category of an error raised by the buy and sell paths, taken from the first error in its
+/// source chain whose type carries one. Messages are never inspected.
+pub fn error_category(err: &(dyn Error + 'static)) -> ErrorCategory {
+    let mut current = Some(err);
+
+    while let Some(err) = current {
+        if let Some(failure) = err.downcast_ref::<TradeFailure>() {
+            return failure.category();
+        }
+        if let Some(simulation) = err.downcast_ref::<SimulationError>() {
+            return simulation.category();
+        }
+        if let Some(swap) = err.downcast_ref::<SwapError>() {
+            match swap {
+                SwapError::Simulation(simulation) => {
+                    return simulation.category();
+                }
+                SwapError::QuoteError(_) | SwapError::TokenError(_) => {
+                    return ErrorCategory::QuoteUnavailable;
+                }
+                SwapError::TransactionError(_) | SwapError::ClientError(_) => {
+                    return ErrorCategory::SendFailed;
+                }
+                SwapError::InvalidTransactionData | SwapError::ProgramError(_) => {
+                    return ErrorCategory::Unknown;
+                }
+            }
+        }
+        if let Some(CommandError::InvalidSlippage(_)) = err.downcast_ref::<CommandError>() {
+            return ErrorCategory::InvalidSlippage;
+        }
+        if err.is::<ClientError>() || err.is::<HeliusError>() {
+            return ErrorCategory::SendFailed;
+        }
+        current = err.source();
+    }
+
+    ErrorCategory::Unknown
+}
+
+/// Uses the publisher's `correlation_id`, then its `request_id`, otherwise generates one.
+pub fn correlation_id(payload: &str) -> String {
+    static SEQUENCE: AtomicU64 = AtomicU64::new(0);
+
+    let supplied = serde_json
+        ::from_str::<serde_json::Value>(payload)
+        .ok()
+        .and_then(|value| {
+            value["correlation_id"]
+                .as_str()
+                .or_else(|| value["request_id"].as_str())
+                .map(|id| id.to_string())
+        });
+
+    supplied.unwrap_or_else(|| {
+        let millis = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis())
+            .unwrap_or_default();
+        format!("{}-{}", millis, SEQUENCE.fetch_add(1, Ordering::Relaxed))
+    })
+}
+
+pub async fn buy_fill(ctx: &AppContext, wallet: &Wallet, signature: &str) -> Option<Fill> {
+    let mongo_handler = BuyMongoHandler::new(ctx.mongo.clone(), &ctx.config.mongo, wallet);
+
+    match
+        mongo_handler.get_buy_transaction_by_signature(
+            signature,
+            &ctx.config.mongo.trades_db,
+            "buy_transactions"
+        ).await
+    {
+        Ok(Some(buy)) =>
+            Some(Fill {
+                token_amount: buy.amount,
+                sol_amount: buy.sol_amount,
+                fee_sol: buy.fee_sol,
+                fee_usd: buy.fee_usd,
+                price_sol: buy.entry_price,
+            }),
+        Ok(None) => None,
+        Err(e) => {
+            eprintln!("Failed to load buy record for {}: {:?}", signature, e);
+            None
+        }
+    }
+}
+
+pub async fn sell_fill(ctx: &AppContext, wallet: &Wallet, signature: &str) -> Option<Fill> {
+    let mongo_handler = SellMongoHandler::new(ctx.mongo.clone(), &ctx.config.mongo, wallet);
+
+    match
+        mongo_handler.get_sell_transaction_by_signature(
+            signature,
+            &ctx.config.mongo.trades_db,
+            "sell_transactions"
+        ).await
+    {
+        Ok(Some(sell)) =>
+            Some(Fill {
+                token_amount: sell.amount,
+                sol_amount: sell.sol_amount,
+                fee_sol: sell.fee_sol,
+                fee_usd: sell.fee_usd,
+                price_sol: sell.sol_price,
+            }),
+        Ok(None) => None,
+        Err(e) => {
+            eprintln!("Failed to load sell record for {}: {:?}", signature, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn categories_come_from_the_error_type() {
+        let already_sold: Box<dyn Error> = TradeFailure::AlreadySold.into();
+        assert_eq!(error_category(&*already_sold), ErrorCategory::AlreadySold);
+
+        let slippage = SwapError::Simulation(SimulationError::SlippageExceeded {
+            program: "raydium",
+        });
+        assert_eq!(error_category(&slippage), ErrorCategory::SlippageExceeded);
+
+        let quote = SwapError::QuoteError("pool not found".to_string());
+        assert_eq!(error_category(&quote), ErrorCategory::QuoteUnavailable);
+
+        // Only the type counts, not what the message happens to say
+        let text: Box<dyn Error> = "Insufficient balance. Token already sold".into();
+        assert_eq!(error_category(&*text), ErrorCategory::Unknown);
+    }
+}
@@ -0,0 +1,54 @@
+use crate::config::Config;
+use crate::idempotency::LeaseOwner;
+use crate::wallet::Keystore;
+use helius::types::Cluster;
+use helius::Helius;
+use mongodb::{ Client as MongoClient, options::ClientOptions };
+use redis::aio::MultiplexedConnection;
+use solana_client::nonblocking::rpc_client::RpcClient;
+use std::error::Error;
+use std::sync::Arc;
+
+/// Long-lived clients and validated configuration shared by the buy and sell pipelines.
+///
+/// Built once at startup so the latency-critical paths never read env vars
+/// or open new connections per trade.
+pub struct AppContext {
+    pub rpc_client: Arc<RpcClient>,
+    pub redis_client: redis::Client,
+    pub redis: MultiplexedConnection,
+    pub mongo: MongoClient,
+    pub helius: Arc<Helius>,
+    pub keystore: Keystore,
+    /// Owner written into the leases on requests this process executes
+    pub lease_owner: LeaseOwner,
+    pub config: Config,
+}
+
+impl AppContext {
+    pub async fn new(config: Config) -> Result<Self, Box<dyn Error>> {
+        let rpc_client = Arc::new(RpcClient::new(config.rpc_url.clone()));
+
+        let redis_client = redis::Client::open(config.redis_url.as_str())?;
+        let redis = redis_client.get_multiplexed_async_connection().await?;
+
+        let options = ClientOptions::parse(&config.mongodb_uri).await?;
+        let mongo = MongoClient::with_options(options)?;
+
+        let helius = Arc::new(Helius::new(&config.helius_api_key, Cluster::MainnetBeta)?);
+
+        let keystore = Keystore::from_config(&config)?;
+        let lease_owner = LeaseOwner::new(config.intake.consumer_name());
+
+        Ok(Self {
+            rpc_client,
+            redis_client,
+            redis,
+            mongo,
+            helius,
+            keystore,
+            lease_owner,
+            config,
+        })
+    }
+}
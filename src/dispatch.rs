@@ -0,0 +1,181 @@
+use crate::command::{ raw_mint, request_id };
+use crate::context::AppContext;
+use crate::stream::StreamAck;
+use std::collections::{ HashMap, HashSet };
+use std::future::Future;
+use std::sync::atomic::{ AtomicBool, AtomicU64, Ordering };
+use std::sync::{ Arc, Mutex };
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::sync::oneshot::{ self, error::TryRecvError };
+use tokio::sync::Semaphore;
+use tokio::task::JoinHandle;
+
+pub const DEFAULT_MAX_IN_FLIGHT: usize = 8;
+
+/// Drives a non-Send task to completion on its own blocking thread.
+///
+/// The trade paths can't be `tokio::spawn`ed: the Helius transaction builder borrows a non-Sync
+/// `&dyn Signer` and makes blocking RPC calls, so their futures are not Send and would stall a
+/// runtime worker. Trades, the background tasks and reconciliation all go through here, each
+/// holding one blocking-pool thread while it runs. Trades are bounded by the dispatcher's
+/// semaphore; the background tasks hold theirs for the life of the process.
+pub fn spawn_non_send<F, Fut>(task: F) -> JoinHandle<Fut::Output>
+    where
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future + 'static,
+        Fut::Output: Send + 'static
+{
+    let handle = Handle::current();
+    tokio::task::spawn_blocking(move || handle.block_on(task()))
+}
+
+/// Runs trade messages concurrently with a bounded number in flight.
+///
+/// Messages for the same mint are chained so they execute in arrival order:
+/// each one waits for the previous message on that mint to finish first. Sells issued by the
+/// position manager are queued through `submit` so they share that ordering with user trades.
+pub struct TradeDispatcher {
+    ctx: Arc<AppContext>,
+    max_in_flight: usize,
+    in_flight: Arc<Semaphore>,
+    mint_tails: Mutex<HashMap<String, oneshot::Receiver<()>>>,
+    /// Trades that have been handed to a worker and not finished yet, keyed by dispatch order
+    running: Arc<Mutex<HashMap<u64, String>>>,
+    /// Stream entries handed to `dispatch` and not finished yet
+    entries: Arc<Mutex<HashSet<String>>>,
+    next_id: AtomicU64,
+    /// Set once `drain` starts, after which new messages are refused
+    draining: AtomicBool,
+}
+
+impl TradeDispatcher {
+    pub fn new(ctx: Arc<AppContext>, max_in_flight: usize) -> Self {
+        let max_in_flight = max_in_flight.max(1);
+
+        Self {
+            ctx,
+            max_in_flight,
+            in_flight: Arc::new(Semaphore::new(max_in_flight)),
+            mint_tails: Mutex::new(HashMap::new()),
+            running: Arc::new(Mutex::new(HashMap::new())),
+            entries: Arc::new(Mutex::new(HashSet::new())),
+            next_id: AtomicU64::new(0),
+            draining: AtomicBool::new(false),
+        }
+    }
+
+    /// Waits for a free slot, then hands the message to a worker.
+    ///
+    /// `ack` is sent once the trade has finished, whether it succeeded or failed. Messages
+    /// whose request is still held by another consumer, and messages arriving after `drain`
+    /// has started, are left unacked so the stream redelivers them.
+    pub async fn dispatch(&self, payload: String, ack: Option<StreamAck>) {
+        let mint = raw_mint(&payload);
+        let label = describe(&payload);
+
+        let entry = ack.as_ref().map(|ack| ack.id().to_string());
+        if let Some(entry) = &entry {
+            self.entries.lock().unwrap().insert(entry.clone());
+        }
+        let job_entry = entry.clone();
+        let entries = self.entries.clone();
+
+        let queued = self.submit(mint, label, move |ctx| async move {
+            let settled = crate::handle_trade_message(&ctx, payload).await;
+
+            if let Some(ack) = ack.filter(|_| settled) {
+                if let Err(e) = ack.ack(&mut ctx.redis.clone()).await {
+                    eprintln!("Failed to ack trade stream entry: {}", e);
+                }
+            }
+            if let Some(entry) = job_entry {
+                entries.lock().unwrap().remove(&entry);
+            }
+        }).await;
+
+        if let Some(entry) = entry.filter(|_| !queued) {
+            self.entries.lock().unwrap().remove(&entry);
+        }
+    }
+
+    /// Whether the stream entry `id` was dispatched and hasn't finished yet.
+    pub fn is_dispatched(&self, id: &str) -> bool {
+        self.entries.lock().unwrap().contains(id)
+    }
+
+    /// Waits for a free slot, then runs `job` once every earlier trade on `mint` has finished.
+    ///
+    /// Returns false without running `job` once `drain` has started.
+    pub async fn submit<F, Fut>(&self, mint: Option<String>, label: String, job: F) -> bool
+        where
+            F: FnOnce(Arc<AppContext>) -> Fut + Send + 'static,
+            Fut: Future<Output = ()> + 'static
+    {
+        let permit = self.in_flight
+            .clone()
+            .acquire_owned().await
+            .expect("Trade semaphore closed");
+        if self.draining.load(Ordering::SeqCst) {
+            eprintln!("Shutting down, not starting trade {}", label);
+            return false;
+        }
+
+        let (done_tx, done_rx) = oneshot::channel::<()>();
+        let previous = {
+            let mut mint_tails = self.mint_tails.lock().unwrap();
+            // Forget mints whose last message has already finished
+            mint_tails.retain(|_, tail| matches!(tail.try_recv(), Err(TryRecvError::Empty)));
+            match mint {
+                Some(mint) => mint_tails.insert(mint, done_rx),
+                None => None,
+            }
+        };
+
+        let trade_id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        self.running.lock().unwrap().insert(trade_id, label);
+
+        let ctx = self.ctx.clone();
+        let running = self.running.clone();
+
+        tokio::spawn(async move {
+            // Waiting for the previous trade on the mint doesn't need a blocking thread
+            if let Some(previous) = previous {
+                let _ = previous.await;
+            }
+
+            if let Err(e) = spawn_non_send(move || job(ctx)).await {
+                eprintln!("Trade worker panicked: {}", e);
+            }
+
+            running.lock().unwrap().remove(&trade_id);
+            drop(done_tx);
+            drop(permit);
+        });
+
+        true
+    }
+
+    /// Stops accepting messages and waits up to `deadline` for every dispatched trade to finish.
+    ///
+    /// Returns a description of each trade still running when the deadline passed, led by its
+    /// request id.
+    pub async fn drain(&self, deadline: Duration) -> Vec<String> {
+        self.draining.store(true, Ordering::SeqCst);
+        let all_slots = self.in_flight.acquire_many(self.max_in_flight as u32);
+
+        match tokio::time::timeout(deadline, all_slots).await {
+            Ok(_) => Vec::new(),
+            Err(_) => self.running.lock().unwrap().values().cloned().collect(),
+        }
+    }
+}
+
+fn describe(payload: &str) -> String {
+    match (request_id(payload), raw_mint(payload)) {
+        (Some(request_id), Some(mint)) => format!("request {} (mint {})", request_id, mint),
+        (Some(request_id), None) => format!("request {}", request_id),
+        (None, Some(mint)) => format!("mint {} (no request id)", mint),
+        (None, None) => "unparseable message".to_string(),
+    }
+}
@@ -0,0 +1,213 @@
+use redis::aio::MultiplexedConnection;
+use redis::{ AsyncCommands, RedisResult, ExistenceCheck, Script, SetExpiry, SetOptions };
+use std::future::Future;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+pub const DEFAULT_REQUEST_TTL_SECS: u64 = 86_400;
+pub const DEFAULT_REQUEST_LEASE_SECS: u64 = 30;
+
+/// Prefix of the lease stored while a copy of a request is still executing.
+const IN_FLIGHT: &str = "in_flight";
+
+/// Replaces the lease in KEYS[1] with ARGV[2] for ARGV[3] seconds if it still reads ARGV[1].
+const TAKE_OVER_SCRIPT: &str =
+    r"
+    if redis.call('GET', KEYS[1]) == ARGV[1] then
+        redis.call('SET', KEYS[1], ARGV[2], 'EX', ARGV[3])
+        return 1
+    end
+    return 0
+";
+
+/// Extends the lease in KEYS[1] to ARGV[2] seconds if it is still held by ARGV[1].
+const RENEW_SCRIPT: &str =
+    r"
+    if redis.call('GET', KEYS[1]) == ARGV[1] then
+        return redis.call('EXPIRE', KEYS[1], ARGV[2])
+    end
+    return 0
+";
+
+/// Outcome of claiming a request id before executing its command.
+pub enum Claim {
+    /// First time this id is seen, or its previous owner is gone. The caller holds the lease
+    /// and must execute the command.
+    New,
+    /// Another consumer holds a live lease on the request, named here.
+    InFlight(String),
+    /// Already executed, holds the confirmation that was published for it.
+    Completed(String),
+}
+
+/// Who holds a request lease: the stream consumer name plus a per-process instance id, so a
+/// restarted consumer can tell its own dead leases from the ones it is renewing.
+#[derive(Debug, Clone)]
+pub struct LeaseOwner {
+    consumer: String,
+    instance: String,
+}
+
+impl LeaseOwner {
+    pub fn new(consumer: String) -> Self {
+        let started = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_millis())
+            .unwrap_or_default();
+
+        Self {
+            consumer,
+            instance: format!("{}-{}", std::process::id(), started),
+        }
+    }
+
+    fn lease(&self) -> String {
+        format!("{}:{}:{}", IN_FLIGHT, self.instance, self.consumer)
+    }
+}
+
+/// Splits a stored lease into its instance and consumer. Leases written before they carried
+/// an owner come back as `None`.
+fn parse_lease(lease: &str) -> Option<(&str, &str)> {
+    let mut parts = lease.splitn(3, ':');
+    if parts.next() != Some(IN_FLIGHT) {
+        return None;
+    }
+    Some((parts.next()?, parts.next()?))
+}
+
+fn request_key(request_id: &str) -> String {
+    format!("trade_request:{}", request_id)
+}
+
+/// Atomically claims `request_id` so only one copy of a retried command is ever executed.
+///
+/// The claim is a lease of `lease_secs` that the caller keeps alive with `hold_lease`. A lease
+/// left by an earlier run of the same consumer is taken over straight away; one held by another
+/// consumer is only taken once it expires.
+pub async fn claim_request(
+    con: &mut MultiplexedConnection,
+    request_id: &str,
+    owner: &LeaseOwner,
+    lease_secs: u64
+) -> RedisResult<Claim> {
+    let lease = owner.lease();
+
+    loop {
+        let options = SetOptions::default()
+            .conditional_set(ExistenceCheck::NX)
+            .with_expiration(SetExpiry::EX(lease_secs as usize));
+        let claimed: Option<String> = con.set_options(
+            request_key(request_id),
+            &lease,
+            options
+        ).await?;
+
+        if claimed.is_some() {
+            return Ok(Claim::New);
+        }
+
+        let stored: Option<String> = con.get(request_key(request_id)).await?;
+        let stored = match stored {
+            Some(stored) if !stored.starts_with(IN_FLIGHT) => {
+                return Ok(Claim::Completed(stored));
+            }
+            Some(stored) => stored,
+            // Expired between the two calls, try to claim it again
+            None => {
+                continue;
+            }
+        };
+
+        // Live unless it was left by an earlier run of this consumer. Ownerless leases predate
+        // renewal, so whoever wrote them is long gone
+        if let Some((instance, consumer)) = parse_lease(&stored) {
+            if consumer != owner.consumer || instance == owner.instance {
+                return Ok(Claim::InFlight(consumer.to_string()));
+            }
+        }
+
+        let taken: i64 = Script::new(TAKE_OVER_SCRIPT)
+            .key(request_key(request_id))
+            .arg(&stored)
+            .arg(&lease)
+            .arg(lease_secs)
+            .invoke_async(con).await?;
+        if taken == 1 {
+            println!("Took over the lease on request {} from {}", request_id, stored);
+            return Ok(Claim::New);
+        }
+    }
+}
+
+/// The lease on a request expired or was taken over while its command was executing.
+#[derive(Debug, thiserror::Error)]
+#[error("Lost the lease on request {0}")]
+pub struct LeaseLost(pub String);
+
+/// Runs `work` while renewing the lease on `request_id` every third of `lease_secs`.
+///
+/// If a renewal finds the lease gone, another consumer may already be executing the request,
+/// so `work` is dropped at its current await point, before it can send a transaction, and
+/// `LeaseLost` is returned.
+pub async fn hold_lease<T>(
+    mut con: MultiplexedConnection,
+    request_id: &str,
+    owner: &LeaseOwner,
+    lease_secs: u64,
+    work: impl Future<Output = T>
+) -> Result<T, LeaseLost> {
+    let lease = owner.lease();
+    let mut renewal = tokio::time::interval(Duration::from_secs((lease_secs / 3).max(1)));
+    // The first tick completes immediately and the lease was only just written
+    renewal.tick().await;
+
+    tokio::pin!(work);
+    loop {
+        tokio::select! {
+            output = &mut work => {
+                return Ok(output);
+            }
+            _ = renewal.tick() => {
+                let renewed: RedisResult<i64> = Script::new(RENEW_SCRIPT)
+                    .key(request_key(request_id))
+                    .arg(&lease)
+                    .arg(lease_secs)
+                    .invoke_async(&mut con).await;
+                match renewed {
+                    Ok(1) => {}
+                    Ok(_) => {
+                        return Err(LeaseLost(request_id.to_string()));
+                    }
+                    // Redis being unreachable doesn't mean anyone else holds the request
+                    Err(e) => eprintln!("Failed to renew lease on request {}: {}", request_id, e),
+                }
+            }
+        }
+    }
+}
+
+/// Stores the confirmation published for `request_id` so duplicates can replay it.
+pub async fn record_outcome(
+    con: &mut MultiplexedConnection,
+    request_id: &str,
+    confirmation: &str,
+    ttl_secs: u64
+) -> RedisResult<()> {
+    let _: () = con.set_ex(request_key(request_id), confirmation, ttl_secs).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lease_round_trips_its_owner() {
+        let owner = LeaseOwner::new("sniper:1".to_string());
+        let lease = owner.lease();
+
+        assert_eq!(parse_lease(&lease), Some((owner.instance.as_str(), "sniper:1")));
+        assert_eq!(parse_lease("in_flight"), None);
+        assert_eq!(parse_lease("{\"status\":\"success\"}"), None);
+    }
+}
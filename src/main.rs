@@ -1,279 +1,329 @@
 mod sell;
 mod buy;
+mod config;
+mod context;
+mod dispatch;
+mod stream;
+mod command;
+mod confirmation;
+mod idempotency;
+mod shutdown;
+mod reconcile;
+mod wallet;
+mod signer;
+mod paper;
+mod simulation;
+mod venue;
+mod positions;
+mod price_feed;
+mod sweeper;
 use redis::RedisResult;
 use futures_util::StreamExt;
-use serde::{ Serialize, Deserialize };
 use dotenv::dotenv;
-use buy::raydium_sdk::LiquidityPoolKeysString;
-use buy::pump::pump_fun_buy;
+use buy::pump::{ pump_fun_buy, PumpBuyAmount };
 use buy::buy::buy_swap;
 use tokio::time::{ sleep, Duration };
+use std::error::Error;
+use std::process::ExitCode;
 use std::time::Instant;
-use buy::utils::get_liquidity_pool;
-use sell::utils::get_liquidity_pool as get_sell_liquidity_pool;
-use solana_client::nonblocking::rpc_client::RpcClient;
+use config::{ Config, IntakeMode };
+use context::AppContext;
+use dispatch::{ spawn_non_send, TradeDispatcher };
+use stream::StreamIntake;
 use std::sync::Arc;
-use solana_sdk::pubkey::Pubkey;
-use std::str::FromStr;
-use sell::sell::SellTransaction;
+use command::{ parse_trade_command, raw_mint, request_id, TradeCommand };
+use confirmation::{
+    buy_fill,
+    correlation_id,
+    publish_message,
+    sell_fill,
+    Side,
+    TradeConfirmation,
+};
+use idempotency::{ claim_request, hold_lease, record_outcome, Claim };
+use sell::amount::resolve_percent;
 use sell::pump::pump_fun_sell;
-use sell::sell::sell_swap;
-use serde_json::json;
-use redis::AsyncCommands;
-
-#[derive(Debug, Serialize, Deserialize, Clone)]
-struct BuyTransaction {
-    pub type_: String,
-    in_token: String,
-    out_token: String,
-    amount_in: f64,
-    key_z: Option<LiquidityPoolKeysString>,
-    lp_decimals: u8,
-    group_title: String,
-    user_name: String,
-}
-async fn handle_trade_message(payload: String) {
-    let trade_info: serde_json::Value = match serde_json::from_str(&payload) {
-        Ok(info) => info,
-        Err(e) => {
-            eprintln!("Failed to parse trade info, check the other repo: {}", e);
-            return;
+use sell::sell::{ sell_swap, SellTransaction };
+use solana_sdk::pubkey::Pubkey;
+use wallet::Wallet;
+use venue::Route;
+
+/// Executes one trade message and publishes its confirmation.
+///
+/// Returns false when the message must stay unacknowledged: its request is still held by
+/// another consumer, or could not be claimed at all. The stream delivers it again later.
+async fn handle_trade_message(ctx: &AppContext, payload: String) -> bool {
+    // Measure end-to-end latency from the moment the command is picked up
+    let start_time = Instant::now();
+    let correlation_id = correlation_id(&payload);
+    let request_id = request_id(&payload);
+    let intake = &ctx.config.intake;
+
+    if let Some(request_id) = &request_id {
+        let claim = claim_request(
+            &mut ctx.redis.clone(),
+            request_id,
+            &ctx.lease_owner,
+            intake.request_lease_secs
+        ).await;
+        match claim {
+            Ok(Claim::New) => {}
+            Ok(Claim::InFlight(holder)) => {
+                println!("Request {} is being processed by {}, deferring it", request_id, holder);
+                return false;
+            }
+            Ok(Claim::Completed(confirmation)) => {
+                println!("Request {} was already processed, replaying its confirmation", request_id);
+                publish_message(ctx, &confirmation).await;
+                return true;
+            }
+            Err(e) => {
+                // Never trade on a request we can't deduplicate
+                eprintln!("Failed to claim request {}: {}", request_id, e);
+                return false;
+            }
+        }
+    }
+
+    let trade = execute_trade_command(ctx, &payload, correlation_id, start_time);
+    let mut confirmation = match &request_id {
+        Some(request_id) => {
+            let held = hold_lease(
+                ctx.redis.clone(),
+                request_id,
+                &ctx.lease_owner,
+                intake.request_lease_secs,
+                trade
+            ).await;
+            match held {
+                Ok(confirmation) => confirmation,
+                Err(e) => {
+                    // Whoever holds the lease now owns the request and its confirmation
+                    eprintln!("{}, abandoning it", e);
+                    return false;
+                }
+            }
         }
+        None => trade.await,
+    };
+    confirmation.paper = ctx.config.paper.enabled;
+    let Some(message) = confirmation.to_message() else {
+        return true;
     };
 
-    match trade_info["type_"].as_str() {
-        Some("buy") => {
-            let buy_transaction: Result<BuyTransaction, serde_json::Error> = serde_json::from_value(
-                trade_info.clone()
+    if let Some(request_id) = &request_id {
+        if
+            let Err(e) = record_outcome(
+                &mut ctx.redis.clone(),
+                request_id,
+                &message,
+                intake.request_ttl_secs
+            ).await
+        {
+            eprintln!("Failed to record outcome of request {}: {}", request_id, e);
+        }
+    }
+
+    publish_message(ctx, &message).await;
+    true
+}
+
+async fn execute_trade_command(
+    ctx: &AppContext,
+    payload: &str,
+    correlation_id: String,
+    start_time: Instant
+) -> TradeConfirmation {
+    let (command, mint) = match parse_trade_command(payload) {
+        Ok(parsed) => parsed,
+        Err(e) => {
+            eprintln!("Rejected trade command {}: {}", correlation_id, e);
+            return TradeConfirmation::rejected(
+                correlation_id,
+                raw_mint(payload),
+                &e,
+                start_time.elapsed()
             );
-            match buy_transaction {
-                Ok(tx) => {
-                    // Measure time taken for buy transaction
-                    let start_time = Instant::now();
-
-                    let rpc_endpoint = std::env
-                        ::var("RPC_URL")
-                        .expect("You must set the RPC_URL environment variable!");
-                    let client: Arc<RpcClient> = Arc::new(RpcClient::new(rpc_endpoint));
-
-                    let in_token_pubkey = Pubkey::from_str(&tx.in_token).unwrap();
-
-                    let buy_pool_result = get_liquidity_pool(
-                        client.clone(),
-                        &in_token_pubkey
-                    ).await;
-
-                    let buy_success = if let Ok(Some(buy_pool)) = buy_pool_result {
-                        let redis_url = std::env
-                            ::var("REDIS_URL")
-                            .expect("You must set the REDIS_URL environment variable!");
-                        let client = redis::Client
-                            ::open(redis_url)
-                            .expect("Failed to create Redis client");
-                        let mut connection = client
-                            .get_multiplexed_async_connection().await
-                            .expect("Failed to get Redis connection");
-
-                        // Proceed with the buy swap using the existing logic
-                        match
-                            buy_swap(
-                                buy_pool,
-                                tx.lp_decimals,
-                                tx.amount_in,
-                                tx.group_title,
-                                tx.user_name
-                            ).await
-                        {
-                            Ok(_) => {
-                                let elapsed = start_time.elapsed();
-
-                                let confirmation_message =
-                                    json!({
-                        "status": "success",
-                        "mint": tx.in_token,
-                    }).to_string();
-
-                                let _: () = connection
-                                    .publish("trading_confirmation", confirmation_message).await
-                                    .expect("Failed to send confirmation");
-
-                                dbg!("Buy confirmed successfully. Time taken: {:?}", elapsed);
-                                true
-                            }
-                            Err(err) => {
-                                eprintln!("Buy confirmation error: {:?}", err);
-                                false
-                            }
-                        }
-                    } else {
-                        // Treat as pump token
-                        dbg!("Running pump_fun_buy");
-                        let mint_str = &tx.in_token;
-                        let slippage_decimal = 80.0; // Update as necessary
-
-                        match
-                            pump_fun_buy(
-                                mint_str,
-                                tx.amount_in,
-                                slippage_decimal,
-                                tx.lp_decimals,
-                                tx.group_title,
-                                tx.user_name
-                            ).await
-                        {
-                            Ok(_) => {
-                                let elapsed = start_time.elapsed();
-                                println!("Pump fun buy successful. Time taken: {:?}", elapsed);
-                                true
-                            }
-                            Err(err) => {
-                                eprintln!("Pump fun buy error: {:?}", err);
-                                false
-                            }
-                        }
-                    };
+        }
+    };
 
-                    // Send confirmation message back
-                    if buy_success {
-                        let redis_url = std::env
-                            ::var("REDIS_URL")
-                            .expect("You must set the REDIS_URL environment variable!");
-                        let client = redis::Client
-                            ::open(redis_url)
-                            .expect("Failed to create Redis client");
-                        let mut connection = client
-                            .get_multiplexed_async_connection().await
-                            .expect("Failed to get Redis connection");
-
-                        let confirmation_message =
-                            json!({
-                            "status": "success",
-                            "mint": tx.in_token,
-                        }).to_string();
-
-                        let _: () = connection
-                            .publish("trading_confirmation", confirmation_message).await
-                            .expect("Failed to send confirmation");
-                    } else {
-                        let redis_url = std::env
-                            ::var("REDIS_URL")
-                            .expect("You must set the REDIS_URL environment variable!");
-                        let client = redis::Client
-                            ::open(redis_url)
-                            .expect("Failed to create Redis client");
-                        let mut connection = client
-                            .get_multiplexed_async_connection().await
-                            .expect("Failed to get Redis connection");
-
-                        let confirmation_message =
-                            json!({
-                            "status": "fail",
-                            "mint": tx.in_token,
-                        }).to_string();
-
-                        let _: () = connection
-                            .publish("trading_confirmation", confirmation_message).await
-                            .expect("Failed to send confirmation");
-                    }
+    match command {
+        TradeCommand::Buy(tx) => {
+            let wallet = ctx.keystore.route(Some(&tx.user_name), Some(&tx.group_title));
+            let _trade = wallet.begin_trade();
+            let route = match venue::resolve(ctx, &mint).await {
+                Ok(route) => route,
+                Err(err) => {
+                    eprintln!("Buy of {} has no venue: {}", tx.in_token, err);
+                    return TradeConfirmation::failed(
+                        correlation_id,
+                        Side::Buy,
+                        tx.in_token,
+                        None,
+                        &*err,
+                        start_time.elapsed()
+                    );
                 }
-                Err(e) => {
-                    eprintln!("Failed to deserialize BuyTransaction: {}", e);
+            };
+            let venue = route.venue();
+
+            let result: Result<String, Box<dyn Error>> = match route {
+                Route::Raydium(_) if tx.token_amount.is_some() => {
+                    Err("Exact token amount buys are only supported on pump.fun".into())
                 }
-            }
-        }
-        Some("sell") => {
-            let sell_transaction: Result<
-                SellTransaction,
-                serde_json::Error
-            > = serde_json::from_value(trade_info.clone());
-            match sell_transaction {
-                Ok(tx) => {
-                    let start_time = Instant::now();
-
-                    let rpc_endpoint = std::env
-                        ::var("RPC_URL")
-                        .expect("You must set the RPC_URL environment variable!");
-                    let client: Arc<RpcClient> = Arc::new(RpcClient::new(rpc_endpoint));
-
-                    let in_token_pubkey = Pubkey::from_str(&tx.mint).unwrap();
-                    let sell_pool_result = get_sell_liquidity_pool(
-                        client.clone(),
-                        &in_token_pubkey
-                    ).await;
-
-                    let sell_success = if let Ok(Some(_sell_pool)) = sell_pool_result {
-                        match sell_swap(&tx).await {
-                            Ok(_) => {
-                                let elapsed = start_time.elapsed();
-                                dbg!("Sell confirmed successfully. Time taken: {:?}", elapsed);
-                                true
-                            }
-                            Err(err) => {
-                                eprintln!("Sell confirmation error: {:?}", err);
-                                false
-                            }
-                        }
-                    } else {
-                        let mint_str = &tx.mint;
-                        let slippage_decimal = 80.0;
-
-                        match pump_fun_sell(mint_str, tx.amount, slippage_decimal, &tx).await {
-                            Ok(_) => {
-                                let elapsed = start_time.elapsed();
-                                println!("Pump fun sell successful. Time taken: {:?}", elapsed);
-                                true
-                            }
-                            Err(err) => {
-                                eprintln!("Pump fun sell error: {:?}", err);
-                                false
-                            }
-                        }
+                Route::Raydium(buy_pool) =>
+                    buy_swap(
+                        ctx,
+                        &wallet,
+                        buy_pool,
+                        tx.lp_decimals,
+                        tx.amount_in,
+                        tx.slippage.unwrap_or(ctx.config.trading.buy_slippage),
+                        tx.group_title,
+                        tx.user_name
+                    ).await.map_err(|err| err.into()),
+                Route::PumpFun => {
+                    dbg!("Running pump_fun_buy");
+                    let amount = match tx.token_amount {
+                        Some(token_amount) => PumpBuyAmount::Tokens(token_amount),
+                        None => PumpBuyAmount::Sol(tx.amount_in),
                     };
+                    let slippage = tx.slippage.unwrap_or(ctx.config.trading.pump_buy_slippage);
 
-                    let redis_url = std::env
-                        ::var("REDIS_URL")
-                        .expect("You must set the REDIS_URL environment variable!");
-                    let client = redis::Client
-                        ::open(redis_url)
-                        .expect("Failed to create Redis client");
-                    let mut connection = client
-                        .get_multiplexed_async_connection().await
-                        .expect("Failed to get Redis connection");
-
-                    let confirmation_message =
-                        json!({
-                        "status": if sell_success { "success" } else { "fail" },
-                        "mint": tx.mint,
-                    }).to_string();
-
-                    let _: () = connection
-                        .publish("trading_confirmation", confirmation_message).await
-                        .expect("Failed to send confirmation");
+                    pump_fun_buy(
+                        ctx,
+                        &wallet,
+                        &tx.in_token,
+                        amount,
+                        slippage,
+                        tx.lp_decimals,
+                        tx.group_title,
+                        tx.user_name
+                    ).await
+                        .map(|signature| signature.to_string())
                 }
-                Err(e) => {
-                    eprintln!("Failed to deserialize SellTransaction: {}", e);
+            };
+
+            match result {
+                Ok(signature) => {
+                    println!(
+                        "Buy confirmed on {:?}. Time taken: {:?}",
+                        venue,
+                        start_time.elapsed()
+                    );
+                    let fill = buy_fill(ctx, &wallet, &signature).await;
+                    TradeConfirmation::success(
+                        correlation_id,
+                        Side::Buy,
+                        tx.in_token,
+                        venue,
+                        signature,
+                        fill,
+                        start_time.elapsed()
+                    )
+                }
+                Err(err) => {
+                    eprintln!("Buy error on {:?}: {}", venue, err);
+                    TradeConfirmation::failed(
+                        correlation_id,
+                        Side::Buy,
+                        tx.in_token,
+                        Some(venue),
+                        &*err,
+                        start_time.elapsed()
+                    )
                 }
             }
         }
-        _ => {
-            eprintln!("Invalid transaction type or missing 'type_' field");
+        TradeCommand::Sell(tx) => {
+            let wallet = ctx.keystore.route(tx.user_name.as_deref(), tx.group_title.as_deref());
+            execute_sell(ctx, &wallet, tx, &mint, correlation_id, start_time).await
         }
     }
 }
 
-async fn receive_trades() -> RedisResult<()> {
-    let redis_url = std::env
-        ::var("REDIS_URL")
-        .expect("You must set the REDIS_URL environment variable");
+/// Sells from `wallet` on whichever venue `mint` currently trades on.
+async fn execute_sell(
+    ctx: &AppContext,
+    wallet: &Wallet,
+    mut tx: SellTransaction,
+    mint: &Pubkey,
+    correlation_id: String,
+    start_time: Instant
+) -> TradeConfirmation {
+    let _trade = wallet.begin_trade();
+    let route = match venue::resolve(ctx, mint).await {
+        Ok(route) => route,
+        Err(err) => {
+            eprintln!("Sell of {} has no venue: {}", tx.mint, err);
+            return TradeConfirmation::failed(
+                correlation_id,
+                Side::Sell,
+                tx.mint,
+                None,
+                &*err,
+                start_time.elapsed()
+            );
+        }
+    };
+    let venue = route.venue();
 
-    loop {
-        let client = redis::Client::open(redis_url.clone()).expect("Failed to create Redis client");
+    if let Err(err) = resolve_percent(ctx, wallet, &mut tx, venue).await {
+        eprintln!("Sell of {} has no amount: {}", tx.mint, err);
+        return TradeConfirmation::failed(
+            correlation_id,
+            Side::Sell,
+            tx.mint,
+            Some(venue),
+            &*err,
+            start_time.elapsed()
+        );
+    }
+
+    let result = match route {
+        Route::Raydium(pool) => sell_swap(ctx, wallet, &pool, &tx).await,
+        Route::PumpFun => {
+            let slippage = tx.slippage.unwrap_or(ctx.config.trading.pump_sell_slippage);
+            pump_fun_sell(ctx, wallet, &tx.mint, tx.amount, slippage, &tx).await
+        }
+    };
+
+    match result {
+        Ok(signature) => {
+            println!("Sell confirmed on {:?}. Time taken: {:?}", venue, start_time.elapsed());
+            let signature = signature.to_string();
+            let fill = sell_fill(ctx, wallet, &signature).await;
+            TradeConfirmation::success(
+                correlation_id,
+                Side::Sell,
+                tx.mint,
+                venue,
+                signature,
+                fill,
+                start_time.elapsed()
+            )
+        }
+        Err(err) => {
+            eprintln!("Sell error on {:?}: {}", venue, err);
+            TradeConfirmation::failed(
+                correlation_id,
+                Side::Sell,
+                tx.mint,
+                Some(venue),
+                &*err,
+                start_time.elapsed()
+            )
+        }
+    }
+}
 
-        match client.get_multiplexed_async_connection().await {
-            Ok(_connection) => {
-                let mut pubsub = client.get_async_pubsub().await.unwrap();
-                if let Err(e) = pubsub.subscribe("trading").await {
-                    eprintln!("Failed to subscribe to 'trading': {}", e);
+async fn receive_trades(ctx: Arc<AppContext>, dispatcher: &TradeDispatcher) -> RedisResult<()> {
+    loop {
+        match ctx.redis_client.get_async_pubsub().await {
+            Ok(mut pubsub) => {
+                let channel = &ctx.config.intake.channel;
+                if let Err(e) = pubsub.subscribe(channel).await {
+                    eprintln!("Failed to subscribe to '{}': {}", channel, e);
                     sleep(Duration::from_secs(5)).await;
                     continue;
                 }
@@ -287,7 +337,7 @@ async fn receive_trades() -> RedisResult<()> {
                             continue;
                         }
                     };
-                    handle_trade_message(payload).await;
+                    dispatcher.dispatch(payload, None).await;
                 }
             }
             Err(e) => {
@@ -299,10 +349,124 @@ async fn receive_trades() -> RedisResult<()> {
     }
 }
 
-#[tokio::main]
-async fn main() {
+fn main() -> ExitCode {
     dotenv().ok();
-    if let Err(e) = receive_trades().await {
-        eprintln!("Error receiving trades: {}", e);
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("encrypt-keystore") {
+        if let Err(e) = signer::encrypt_keystore_command(&args[1..]) {
+            eprintln!("{}", e);
+            return ExitCode::FAILURE;
+        }
+        return ExitCode::SUCCESS;
+    }
+
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("Invalid configuration: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("Failed to start the async runtime: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let exit_code = runtime.block_on(run(config));
+
+    // Dropping the runtime would wait forever on the background tasks' blocking threads
+    runtime.shutdown_timeout(shutdown::RUNTIME_GRACE);
+    exit_code
+}
+
+async fn run(config: Config) -> ExitCode {
+    let ctx = match AppContext::new(config).await {
+        Ok(ctx) => Arc::new(ctx),
+        Err(e) => {
+            eprintln!("Failed to initialize application context: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    for wallet in ctx.keystore.wallets() {
+        println!("Trading with wallet {} ({})", wallet.name, wallet.address());
+    }
+
+    let intake_config = ctx.config.intake.clone();
+
+    if ctx.config.paper.enabled {
+        println!(
+            "Paper trading: transactions are simulated, never sent, and recorded in {} / {}",
+            ctx.config.mongo.trades_db,
+            ctx.config.mongo.state_db
+        );
+    }
+
+    // Paper positions are not held on-chain, so there is nothing to reconcile them against
+    if intake_config.reconcile_on_startup && !ctx.config.paper.enabled {
+        for wallet in ctx.keystore.wallets() {
+            match reconcile::reconcile(&ctx, wallet, intake_config.reconcile_backfill).await {
+                Ok(report) => reconcile::log_report(&report),
+                Err(e) => eprintln!("Startup reconciliation of wallet {} failed: {}", wallet.name, e),
+            }
+        }
+    }
+    tokio::spawn(reconcile::serve_requests(ctx.clone()));
+
+    if ctx.config.price_feed.enabled {
+        let ctx = ctx.clone();
+        spawn_non_send(move || price_feed::run(ctx));
+    }
+
+    if ctx.config.sweeper.enabled {
+        let ctx = ctx.clone();
+        spawn_non_send(move || sweeper::run(ctx));
+    }
+
+    // Shared with the position manager so its sells queue behind user trades on the same mint
+    let dispatcher = Arc::new(TradeDispatcher::new(ctx.clone(), intake_config.max_in_flight));
+
+    if ctx.config.positions.enabled {
+        let ctx = ctx.clone();
+        let dispatcher = dispatcher.clone();
+        spawn_non_send(move || positions::run(ctx, dispatcher));
+    }
+
+    let intake = async {
+        if intake_config.mode == IntakeMode::Stream {
+            let intake = StreamIntake::from_config(&intake_config);
+            loop {
+                if let Err(e) = intake.run(ctx.clone(), &dispatcher).await {
+                    eprintln!("Error reading trade stream: {}", e);
+                }
+                sleep(Duration::from_secs(5)).await;
+            }
+        } else if let Err(e) = receive_trades(ctx.clone(), &dispatcher).await {
+            eprintln!("Error receiving trades: {}", e);
+        }
+    };
+
+    // Stop taking new trades on SIGTERM/SIGINT, then let the running ones finish
+    tokio::select! {
+        _ = intake => {}
+        _ = shutdown::wait_for_signal() => println!("Stopping trade intake"),
+    }
+
+    let deadline = Duration::from_secs(intake_config.shutdown_deadline_secs);
+    println!("Waiting up to {:?} for in-flight trades to finish", deadline);
+
+    let unresolved = dispatcher.drain(deadline).await;
+    if unresolved.is_empty() {
+        println!("All in-flight trades finished, shutting down");
+        return ExitCode::SUCCESS;
+    }
+
+    eprintln!("{} trade(s) still unresolved at shutdown:", unresolved.len());
+    for trade in &unresolved {
+        eprintln!("  {}", trade);
     }
+    ExitCode::FAILURE
 }
@@ -0,0 +1,104 @@
+use crate::confirmation::TradeFailure;
+use crate::context::AppContext;
+use crate::sell::mongo::MongoHandler;
+use crate::simulation;
+use crate::wallet::Wallet;
+use solana_client::rpc_config::RpcSimulateTransactionConfig;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::instruction::Instruction;
+use solana_sdk::signature::{ Signature, Signer };
+use solana_sdk::transaction::Transaction;
+use std::error::Error;
+
+/// Base fee of a single-signature transaction, in lamports.
+const BASE_FEE_LAMPORTS: u64 = 5_000;
+
+/// Signs and simulates `instructions` without sending them, returning the signature the
+/// transaction would have landed under.
+///
+/// Simulation failures are logged rather than returned: the paper wallet usually does not
+/// hold the balances the real trade would need, so the fill comes from the quote instead.
+pub async fn simulate(
+    ctx: &AppContext,
+    wallet: &Wallet,
+    instructions: &[Instruction]
+) -> Result<Signature, Box<dyn Error>> {
+    let blockhash = ctx.rpc_client.get_latest_blockhash().await?;
+    let transaction = Transaction::new_signed_with_payer(
+        instructions,
+        Some(&wallet.pubkey()),
+        &[&*wallet.keypair],
+        blockhash
+    );
+    let signature = transaction.signatures[0];
+
+    let config = RpcSimulateTransactionConfig {
+        sig_verify: false,
+        replace_recent_blockhash: true,
+        commitment: Some(CommitmentConfig::processed()),
+        ..RpcSimulateTransactionConfig::default()
+    };
+
+    match ctx.rpc_client.simulate_transaction_with_config(&transaction, config).await {
+        Ok(response) =>
+            match response.value.err {
+                Some(err) => {
+                    eprintln!(
+                        "[paper] Simulation of {} failed: {}",
+                        signature,
+                        simulation::decode(&err, instructions)
+                    );
+                    for line in response.value.logs.unwrap_or_default() {
+                        eprintln!("[paper]   {}", line);
+                    }
+                }
+                None => {
+                    println!(
+                        "[paper] Simulated {} ({} compute units)",
+                        signature,
+                        response.value.units_consumed.unwrap_or_default()
+                    );
+                }
+            }
+        Err(e) => eprintln!("[paper] Failed to simulate {}: {}", signature, e),
+    }
+
+    Ok(signature)
+}
+
+/// Paper positions only exist in Mongo, so sells are checked against the recorded buy
+/// instead of the wallet's token balance.
+pub async fn check_position(
+    ctx: &AppContext,
+    mongo_handler: &MongoHandler,
+    mint: &str,
+    amount: u64
+) -> Result<(), Box<dyn Error>> {
+    if mongo_handler.is_token_sold(&ctx.config.mongo.trades_db, "tokens", mint).await? {
+        return Err(TradeFailure::AlreadySold.into());
+    }
+
+    let buy_transaction = mongo_handler.get_buy_transaction_from_token(
+        mint,
+        &ctx.config.mongo.trades_db,
+        "buy_transactions"
+    ).await?;
+    if buy_transaction.amount < (amount as f64) {
+        return Err(
+            TradeFailure::InsufficientBalance(
+                format!(
+                    "Attempting to swap {} tokens, but only {} tokens are available.",
+                    amount,
+                    buy_transaction.amount
+                )
+            ).into()
+        );
+    }
+
+    Ok(())
+}
+
+/// Network fee a paper trade is charged: the base fee plus the Jito tip, in lamports.
+pub fn network_fee(ctx: &AppContext) -> u64 {
+    BASE_FEE_LAMPORTS + ctx.config.jito.tip_lamports
+}
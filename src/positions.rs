@@ -0,0 +1,469 @@
+use crate::buy::mongo::MongoHandler as BuyMongoHandler;
+use crate::buy::price::{
+    curve_price_sol,
+    get_current_sol_price,
+    pool_price_sol,
+    read_bonding_curve,
+};
+use crate::buy::utils::get_liquidity_pool;
+use crate::config::PositionsConfig;
+use crate::confirmation::{ publish_message, Status };
+use crate::context::AppContext;
+use crate::dispatch::TradeDispatcher;
+use crate::price_feed;
+use crate::sell::mongo::{ BuyTransaction, MongoHandler, TradeState };
+use crate::sell::sell::SellTransaction;
+use crate::wallet::Wallet;
+use mongodb::bson::{ doc, DateTime, Document };
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashSet;
+use std::error::Error;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{ Arc, Mutex };
+use std::time::{ Duration, Instant };
+use tokio::time::MissedTickBehavior;
+
+/// Position keys (`wallet:mint`) with a sell issued by the manager that hasn't finished yet.
+type InFlight = Arc<Mutex<HashSet<String>>>;
+
+/// A `positions` rule that sells part or all of a position.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Rule {
+    StopLoss,
+    BreakevenStop,
+    TrailingStop,
+    /// Index into the take-profit ladder
+    TakeProfit(usize),
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::StopLoss => write!(f, "stop_loss"),
+            Rule::BreakevenStop => write!(f, "breakeven_stop"),
+            Rule::TrailingStop => write!(f, "trailing_stop"),
+            Rule::TakeProfit(rung) => write!(f, "take_profit_{}", rung + 1),
+        }
+    }
+}
+
+impl Rule {
+    /// The trade state the rule expects and the update that marks it as taken.
+    fn claim(&self, profit_percentage: f64) -> (Document, Document) {
+        match self {
+            Rule::TakeProfit(rung) => {
+                let count = *rung as i32;
+                (
+                    doc! { "profit_taking_count": count },
+                    doc! {
+                        "$inc": { "profit_taking_count": 1 },
+                        "$set": {
+                            "last_profit_taking_time": DateTime::now(),
+                            "last_profit_percentage": profit_percentage,
+                        }
+                    },
+                )
+            }
+            _ =>
+                (
+                    doc! { "stop_loss_triggered": false },
+                    doc! {
+                        "$set": {
+                            "stop_loss_triggered": true,
+                            "last_profit_percentage": profit_percentage,
+                        }
+                    },
+                ),
+        }
+    }
+
+    /// Undoes `claim` after the sell failed, so the rule is evaluated again on the next poll.
+    fn release(&self) -> (Document, Document) {
+        match self {
+            Rule::TakeProfit(rung) => {
+                let count = (*rung as i32) + 1;
+                (
+                    doc! { "profit_taking_count": count },
+                    doc! { "$inc": { "profit_taking_count": -1 } },
+                )
+            }
+            _ =>
+                (
+                    doc! { "stop_loss_triggered": true },
+                    doc! { "$set": { "stop_loss_triggered": false } },
+                ),
+        }
+    }
+}
+
+/// Polls every open position and sells it on the take-profit ladder, trailing stop,
+/// breakeven stop and stop loss configured under `positions`.
+///
+/// Each rule is claimed on the trade state with a conditional update before its sell is
+/// issued, so it fires once even if a sell for the same position is still running. Sells go
+/// through `dispatcher`, queued behind any user trade on the same mint.
+pub async fn run(ctx: Arc<AppContext>, dispatcher: Arc<TradeDispatcher>) {
+    let config = &ctx.config.positions;
+    let in_flight: InFlight = Arc::default();
+
+    println!(
+        "Position manager checking open positions every {}ms ({} take-profit levels)",
+        config.poll_interval_ms,
+        config.take_profit.len()
+    );
+
+    let mut interval = tokio::time::interval(Duration::from_millis(config.poll_interval_ms));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        interval.tick().await;
+
+        let sol_price_usd = match get_current_sol_price().await {
+            Ok(price) => price,
+            Err(e) => {
+                eprintln!("Failed to fetch SOL price, USD prices will be 0: {}", e);
+                0.0
+            }
+        };
+
+        for wallet in ctx.keystore.wallets() {
+            let checked = check_wallet(&ctx, &dispatcher, wallet, sol_price_usd, &in_flight).await;
+            if let Err(e) = checked {
+                eprintln!("Failed to check positions of wallet {}: {}", wallet.name, e);
+            }
+        }
+    }
+}
+
+async fn check_wallet(
+    ctx: &Arc<AppContext>,
+    dispatcher: &TradeDispatcher,
+    wallet: &Arc<Wallet>,
+    sol_price_usd: f64,
+    in_flight: &InFlight
+) -> Result<(), Box<dyn Error>> {
+    let buy_handler = BuyMongoHandler::new(ctx.mongo.clone(), &ctx.config.mongo, wallet);
+    let mints = buy_handler.get_unsold_token_mints(&ctx.config.mongo.trades_db, "tokens").await?;
+
+    for mint in mints {
+        let key = format!("{}:{}", wallet.address(), mint);
+        if in_flight.lock().unwrap().contains(&key) {
+            continue;
+        }
+
+        let checked = check_position(ctx, dispatcher, wallet, &mint, sol_price_usd, key, in_flight);
+        if let Err(e) = checked.await {
+            eprintln!("Failed to check position {} of wallet {}: {}", mint, wallet.name, e);
+        }
+    }
+
+    Ok(())
+}
+
+async fn check_position(
+    ctx: &Arc<AppContext>,
+    dispatcher: &TradeDispatcher,
+    wallet: &Arc<Wallet>,
+    mint: &str,
+    sol_price_usd: f64,
+    key: String,
+    in_flight: &InFlight
+) -> Result<(), Box<dyn Error>> {
+    let config = &ctx.config.positions;
+    let mongo_handler = MongoHandler::new(ctx.mongo.clone(), &ctx.config.mongo, wallet);
+
+    let buy = mongo_handler.get_buy_transaction_from_token(
+        mint,
+        &ctx.config.mongo.trades_db,
+        "buy_transactions"
+    ).await?;
+    if buy.amount <= 0.0 || buy.entry_price <= 0.0 {
+        return Ok(());
+    }
+
+    let price_sol = spot_price(ctx, mint, &buy).await?;
+    let profit_percentage = (price_sol / buy.entry_price - 1.0) * 100.0;
+
+    let Some(mut state) = mongo_handler.record_profit(mint, profit_percentage).await? else {
+        return Ok(());
+    };
+
+    if
+        config.breakeven_trigger_pct > 0.0 &&
+        !state.stop_loss_at_breakeven &&
+        state.highest_profit_percentage >= config.breakeven_trigger_pct
+    {
+        let moved = mongo_handler.claim_trade_state(
+            mint,
+            doc! { "stop_loss_at_breakeven": false },
+            doc! { "$set": { "stop_loss_at_breakeven": true } }
+        ).await?;
+        if moved {
+            println!(
+                "Stop for {} moved to breakeven at {:.2}% profit",
+                mint,
+                state.highest_profit_percentage
+            );
+        }
+        state.stop_loss_at_breakeven = true;
+    }
+
+    let Some((rule, sell_pct)) = evaluate(config, &state, profit_percentage) else {
+        return Ok(());
+    };
+    let (expected, update) = rule.claim(profit_percentage);
+    if !mongo_handler.claim_trade_state(mint, expected, update).await? {
+        // Another evaluation already acted on this rule
+        return Ok(());
+    }
+
+    println!("{} hit {} at {:.2}% profit, selling {}%", mint, rule, profit_percentage, sell_pct);
+
+    // The amount and its cost basis are resolved against the live balance when the sell runs
+    let sell_transaction = SellTransaction {
+        metadata: state.token_metadata,
+        mint: mint.to_string(),
+        current_token_price_usd: price_sol * sol_price_usd,
+        current_token_price_sol: price_sol,
+        amount: 0,
+        percent: Some(sell_pct),
+        sol_amount: 0.0,
+        entry: buy.entry_price,
+        base_vault: buy.token_info.base_vault,
+        quote_vault: buy.token_info.quote_vault,
+        slippage: None,
+        user_name: Some(state.user_name),
+        group_title: Some(state.group_title),
+        close_account: false,
+    };
+
+    queue_sell(
+        ctx,
+        dispatcher,
+        wallet.clone(),
+        sell_transaction,
+        rule,
+        key,
+        in_flight.clone()
+    ).await;
+    Ok(())
+}
+
+/// The first rule that fires at `profit_percentage` and the percentage of the remaining
+/// position it sells. Stops take precedence over take-profits.
+fn evaluate(
+    config: &PositionsConfig,
+    state: &TradeState,
+    profit_percentage: f64
+) -> Option<(Rule, f64)> {
+    if state.stop_loss_triggered {
+        return None;
+    }
+
+    if config.stop_loss_pct > 0.0 && profit_percentage <= -config.stop_loss_pct {
+        return Some((Rule::StopLoss, 100.0));
+    }
+    if state.stop_loss_at_breakeven && profit_percentage <= 0.0 {
+        return Some((Rule::BreakevenStop, 100.0));
+    }
+    if
+        config.trailing_stop_pct > 0.0 &&
+        state.highest_profit_percentage >= config.trailing_activation_pct &&
+        profit_percentage <= state.highest_profit_percentage - config.trailing_stop_pct
+    {
+        return Some((Rule::TrailingStop, 100.0));
+    }
+
+    let rung = usize::try_from(state.profit_taking_count).ok()?;
+    let level = config.take_profit.get(rung)?;
+    if profit_percentage >= level.profit_pct {
+        return Some((Rule::TakeProfit(rung), level.sell_pct));
+    }
+
+    None
+}
+
+/// Spot price of `mint` in SOL per whole token: the price feed's latest tick when it runs,
+/// otherwise read from its bonding curve while it trades on pump.fun and from its Raydium
+/// pool afterwards.
+async fn spot_price(
+    ctx: &AppContext,
+    mint: &str,
+    buy: &BuyTransaction
+) -> Result<f64, Box<dyn Error>> {
+    if ctx.config.price_feed.enabled {
+        if let Some(tick) = price_feed::latest(ctx, mint).await {
+            return Ok(tick.price_sol);
+        }
+    }
+
+    let mint = Pubkey::from_str(mint)?;
+
+    if let Some(curve) = read_bonding_curve(&ctx.rpc_client, &mint).await? {
+        if !curve.state.complete {
+            return Ok(curve_price_sol(&curve.state));
+        }
+    }
+
+    // Pump.fun buys are recorded without vaults, so migrated tokens look their pool up
+    let token_info = &buy.token_info;
+    if !token_info.base_vault.is_empty() && !token_info.quote_vault.is_empty() {
+        let base_vault = Pubkey::from_str(&token_info.base_vault)?;
+        let quote_vault = Pubkey::from_str(&token_info.quote_vault)?;
+        return pool_price_sol(&ctx.rpc_client, &mint, [&base_vault, &quote_vault]).await;
+    }
+
+    let pool = get_liquidity_pool(ctx.rpc_client.clone(), &mint).await?.ok_or(
+        "Pool info not found for the given tokens."
+    )?;
+    pool_price_sol(&ctx.rpc_client, &mint, [&pool.base_vault, &pool.quote_vault]).await
+}
+
+/// Queues the sell on the dispatcher like any other trade and publishes its confirmation.
+/// A sell that fails, or is refused because the bot is shutting down, releases its rule so the
+/// next poll can try again.
+async fn queue_sell(
+    ctx: &AppContext,
+    dispatcher: &TradeDispatcher,
+    wallet: Arc<Wallet>,
+    sell_transaction: SellTransaction,
+    rule: Rule,
+    key: String,
+    in_flight: InFlight
+) {
+    in_flight.lock().unwrap().insert(key.clone());
+
+    let mint = sell_transaction.mint.clone();
+    let label = format!("{} sell of {} (wallet {})", rule, mint, wallet.name);
+    let job_wallet = wallet.clone();
+    let job_key = key.clone();
+    let job_in_flight = in_flight.clone();
+
+    let queued = dispatcher.submit(Some(mint.clone()), label, move |ctx| async move {
+        let wallet = job_wallet;
+        let mint = sell_transaction.mint.clone();
+        let correlation_id = format!("position:{}:{}", mint, rule);
+
+        let succeeded = match Pubkey::from_str(&mint) {
+            Ok(mint_key) => {
+                let mut confirmation = crate::execute_sell(
+                    &ctx,
+                    &wallet,
+                    sell_transaction,
+                    &mint_key,
+                    correlation_id,
+                    Instant::now()
+                ).await;
+                confirmation.paper = ctx.config.paper.enabled;
+                if let Some(message) = confirmation.to_message() {
+                    publish_message(&ctx, &message).await;
+                }
+                matches!(confirmation.status, Status::Success)
+            }
+            Err(e) => {
+                eprintln!("Invalid mint {} in position: {}", mint, e);
+                false
+            }
+        };
+
+        if !succeeded {
+            release(&ctx, &wallet, &mint, rule).await;
+        }
+        job_in_flight.lock().unwrap().remove(&job_key);
+    }).await;
+
+    if !queued {
+        release(ctx, &wallet, &mint, rule).await;
+        in_flight.lock().unwrap().remove(&key);
+    }
+}
+
+async fn release(ctx: &AppContext, wallet: &Wallet, mint: &str, rule: Rule) {
+    let mongo_handler = MongoHandler::new(ctx.mongo.clone(), &ctx.config.mongo, wallet);
+    let (expected, update) = rule.release();
+    if let Err(e) = mongo_handler.claim_trade_state(mint, expected, update).await {
+        eprintln!("Failed to release {} on {}: {}", rule, mint, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::TakeProfitLevel;
+
+    fn config() -> PositionsConfig {
+        PositionsConfig {
+            enabled: true,
+            poll_interval_ms: 1_000,
+            take_profit: vec![
+                TakeProfitLevel { profit_pct: 100.0, sell_pct: 50.0 },
+                TakeProfitLevel { profit_pct: 300.0, sell_pct: 100.0 }
+            ],
+            trailing_stop_pct: 30.0,
+            trailing_activation_pct: 50.0,
+            breakeven_trigger_pct: 50.0,
+            stop_loss_pct: 40.0,
+        }
+    }
+
+    fn state(profit_taking_count: i32, highest_profit_percentage: f64) -> TradeState {
+        TradeState {
+            token_mint: "mint".to_string(),
+            entry_price: 1.0,
+            initial_investment_taken: false,
+            ath_50_percent_triggered: false,
+            profit_taking_count,
+            last_profit_taking_time: None,
+            last_profit_percentage: 0.0,
+            highest_profit_percentage,
+            stop_loss_triggered: false,
+            initial_investment: 1.0,
+            group_title: String::new(),
+            user_name: String::new(),
+            total_fee: 0.0,
+            stop_loss_at_breakeven: false,
+            taken_out: 0.0,
+            remaining: 0.0,
+            token_metadata: None,
+            created_at: DateTime::now(),
+        }
+    }
+
+    #[test]
+    fn takes_profit_one_rung_at_a_time() {
+        assert_eq!(evaluate(&config(), &state(0, 120.0), 120.0), Some((Rule::TakeProfit(0), 50.0)));
+        // The first rung was taken, the second isn't reached yet
+        assert_eq!(evaluate(&config(), &state(1, 120.0), 120.0), None);
+        assert_eq!(
+            evaluate(&config(), &state(1, 320.0), 320.0),
+            Some((Rule::TakeProfit(1), 100.0))
+        );
+        assert_eq!(evaluate(&config(), &state(2, 500.0), 500.0), None);
+    }
+
+    #[test]
+    fn stop_loss_sells_everything() {
+        assert_eq!(evaluate(&config(), &state(0, 0.0), -40.0), Some((Rule::StopLoss, 100.0)));
+        assert_eq!(evaluate(&config(), &state(0, 0.0), -39.0), None);
+    }
+
+    #[test]
+    fn trailing_stop_arms_at_activation() {
+        // 10% profit after a 45% high: trailing stop not armed yet
+        assert_eq!(evaluate(&config(), &state(0, 45.0), 10.0), None);
+        assert_eq!(evaluate(&config(), &state(0, 80.0), 50.0), Some((Rule::TrailingStop, 100.0)));
+        assert_eq!(evaluate(&config(), &state(0, 80.0), 51.0), None);
+    }
+
+    #[test]
+    fn breakeven_stop_and_triggered_stops() {
+        let mut breakeven = state(0, 40.0);
+        breakeven.stop_loss_at_breakeven = true;
+        assert_eq!(evaluate(&config(), &breakeven, 0.0), Some((Rule::BreakevenStop, 100.0)));
+
+        let mut stopped = state(0, 0.0);
+        stopped.stop_loss_triggered = true;
+        assert_eq!(evaluate(&config(), &stopped, -90.0), None);
+    }
+}
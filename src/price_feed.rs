@@ -0,0 +1,386 @@
+use crate::buy::mongo::MongoHandler as BuyMongoHandler;
+use crate::buy::price::{
+    curve_price_sol,
+    get_current_sol_price,
+    read_bonding_curve,
+    BondingCurveLayout,
+};
+use crate::buy::utils::get_liquidity_pool;
+use crate::confirmation::Venue;
+use crate::context::AppContext;
+use crate::sell::mongo::{ MongoHandler, TokenInfo };
+use borsh::BorshDeserialize;
+use futures::future::BoxFuture;
+use futures::stream::{ BoxStream, SelectAll, StreamExt };
+use helius::websocket::{ EnhancedWebsocket, ENHANCED_WEBSOCKET_URL };
+use redis::AsyncCommands;
+use serde::{ Deserialize, Serialize };
+use solana_account_decoder::{ UiAccount, UiAccountEncoding };
+use solana_client::rpc_config::RpcAccountInfoConfig;
+use solana_client::rpc_response::Response as RpcResponse;
+use solana_sdk::account::Account;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::program_pack::Pack;
+use solana_sdk::pubkey::Pubkey;
+use std::collections::HashMap;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+use tokio::time::MissedTickBehavior;
+
+const WSOL_MINT: &str = "So11111111111111111111111111111111111111112";
+
+/// A live price of a mint, published on `price_feed.channel` and kept under `price:<mint>`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PriceTick {
+    pub mint: String,
+    pub venue: Venue,
+    /// SOL per whole token
+    pub price_sol: f64,
+    pub price_usd: f64,
+    pub slot: u64,
+    /// Unix time in milliseconds
+    pub timestamp: u64,
+}
+
+type Unsubscribe = Box<dyn FnOnce() -> BoxFuture<'static, ()> + Send>;
+/// An account notification, tagged with the account it is for.
+type Update = (Pubkey, RpcResponse<UiAccount>);
+
+/// Where a mint's price comes from.
+enum Source {
+    Curve,
+    /// Raw vault balances of a Raydium pool, kept current from both vaults' notifications
+    Pool {
+        token_vault: Pubkey,
+        decimals: u8,
+        token_reserve: u64,
+        sol_reserve: u64,
+    },
+}
+
+struct Watch {
+    source: Source,
+    unsubscribes: Vec<Unsubscribe>,
+}
+
+impl Watch {
+    fn venue(&self) -> Venue {
+        match self.source {
+            Source::Curve => Venue::PumpFun,
+            Source::Pool { .. } => Venue::Raydium,
+        }
+    }
+
+    /// Applies an account notification and returns the new price in SOL.
+    fn apply(&mut self, account: &Pubkey, update: &UiAccount) -> Result<f64, String> {
+        let data = update
+            .decode::<Account>()
+            .map(|account| account.data)
+            .ok_or_else(|| format!("undecodable data for {}", account))?;
+
+        match &mut self.source {
+            Source::Curve => {
+                let state = BondingCurveLayout::deserialize(&mut data.as_slice()).map_err(|e| {
+                    format!("failed to decode bonding curve state: {}", e)
+                })?;
+                if state.complete {
+                    return Err("bonding curve complete, waiting for the Raydium pool".to_string());
+                }
+                Ok(curve_price_sol(&state))
+            }
+            Source::Pool { token_vault, decimals, token_reserve, sol_reserve } => {
+                let vault = spl_token::state::Account
+                    ::unpack(&data)
+                    .map_err(|e| format!("failed to decode vault {}: {}", account, e))?;
+                if account == token_vault {
+                    *token_reserve = vault.amount;
+                } else {
+                    *sol_reserve = vault.amount;
+                }
+                pool_price(*token_reserve, *sol_reserve, *decimals)
+            }
+        }
+    }
+}
+
+/// Streams prices of every open position over the Helius websocket, reconnecting when the
+/// connection drops.
+///
+/// Pump.fun positions follow their bonding curve account and Raydium positions their two
+/// pool vaults. Every account change publishes a `PriceTick`.
+pub async fn run(ctx: Arc<AppContext>) {
+    let url = format!("{}{}", ENHANCED_WEBSOCKET_URL, ctx.config.helius_api_key);
+
+    loop {
+        match EnhancedWebsocket::new(&url).await {
+            Ok(ws) => {
+                if let Err(e) = stream_prices(&ctx, &ws).await {
+                    eprintln!("Price feed stopped: {}", e);
+                }
+            }
+            Err(e) => eprintln!("Failed to connect to the price websocket: {}", e),
+        }
+
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+/// The latest tick of `mint`, if the feed published one within `price_feed.tick_ttl_secs`.
+pub async fn latest(ctx: &AppContext, mint: &str) -> Option<PriceTick> {
+    let mut connection = ctx.redis.clone();
+    match connection.get::<_, Option<String>>(tick_key(mint)).await {
+        Ok(tick) => tick.and_then(|tick| serde_json::from_str(&tick).ok()),
+        Err(e) => {
+            eprintln!("Failed to read the latest price of {}: {}", mint, e);
+            None
+        }
+    }
+}
+
+async fn stream_prices(ctx: &AppContext, ws: &EnhancedWebsocket) -> Result<(), Box<dyn Error>> {
+    let config = &ctx.config.price_feed;
+    let mut updates: SelectAll<BoxStream<'_, Update>> = SelectAll::new();
+    let mut watches: HashMap<String, Watch> = HashMap::new();
+    let mut accounts: HashMap<Pubkey, String> = HashMap::new();
+    let mut sol_price_usd = 0.0;
+
+    let mut refresh = tokio::time::interval(Duration::from_millis(config.refresh_interval_ms));
+    refresh.set_missed_tick_behavior(MissedTickBehavior::Skip);
+
+    loop {
+        tokio::select! {
+            Some((account, response)) = updates.next(), if !updates.is_empty() => {
+                let Some(mint) = accounts.get(&account).cloned() else {
+                    continue;
+                };
+                let Some(watch) = watches.get_mut(&mint) else {
+                    continue;
+                };
+
+                match watch.apply(&account, &response.value) {
+                    Ok(price_sol) => {
+                        let tick = PriceTick {
+                            mint,
+                            venue: watch.venue(),
+                            price_sol,
+                            price_usd: price_sol * sol_price_usd,
+                            slot: response.context.slot,
+                            timestamp: now_millis(),
+                        };
+                        publish_tick(ctx, &tick).await;
+                    }
+                    Err(e) => {
+                        // Picked up again from its current venue on the next refresh
+                        println!("Unsubscribing {}: {}", mint, e);
+                        unwatch(&mut watches, &mut accounts, &mint).await;
+                    }
+                }
+            }
+            _ = refresh.tick() => {
+                // Without any open stream left the websocket task has died
+                if !watches.is_empty() && updates.is_empty() {
+                    return Err("websocket closed".into());
+                }
+
+                match get_current_sol_price().await {
+                    Ok(price) => {
+                        sol_price_usd = price;
+                    }
+                    Err(e) => eprintln!("Failed to refresh SOL price: {}", e),
+                }
+
+                let open = open_positions(ctx).await?;
+                let closed: Vec<String> = watches
+                    .keys()
+                    .filter(|mint| !open.contains_key(*mint))
+                    .cloned()
+                    .collect();
+                for mint in closed {
+                    unwatch(&mut watches, &mut accounts, &mint).await;
+                }
+
+                for (mint, token_info) in open {
+                    if watches.contains_key(&mint) {
+                        continue;
+                    }
+
+                    match start_watch(ctx, ws, &mint, &token_info).await {
+                        Ok((watch, streams, price_sol)) => {
+                            for (account, stream) in streams {
+                                accounts.insert(account, mint.clone());
+                                updates.push(stream);
+                            }
+                            let tick = PriceTick {
+                                mint: mint.clone(),
+                                venue: watch.venue(),
+                                price_sol,
+                                price_usd: price_sol * sol_price_usd,
+                                slot: 0,
+                                timestamp: now_millis(),
+                            };
+                            println!("Streaming prices of {} from {:?}", mint, tick.venue);
+                            publish_tick(ctx, &tick).await;
+                            watches.insert(mint, watch);
+                        }
+                        Err(e) => eprintln!("Failed to subscribe to prices of {}: {}", mint, e),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Subscribes to the accounts `mint` is priced from and returns them with its current price.
+async fn start_watch<'a>(
+    ctx: &AppContext,
+    ws: &'a EnhancedWebsocket,
+    mint: &str,
+    token_info: &TokenInfo
+) -> Result<(Watch, Vec<(Pubkey, BoxStream<'a, Update>)>, f64), Box<dyn Error>> {
+    let mint = Pubkey::from_str(mint)?;
+
+    if let Some(curve) = read_bonding_curve(&ctx.rpc_client, &mint).await? {
+        if !curve.state.complete {
+            let (stream, unsubscribe) = subscribe(ws, curve.address).await?;
+            let watch = Watch { source: Source::Curve, unsubscribes: vec![unsubscribe] };
+            return Ok((watch, vec![(curve.address, stream)], curve_price_sol(&curve.state)));
+        }
+    }
+
+    // Pump.fun buys are recorded without vaults, so migrated tokens look their pool up
+    let vaults = if !token_info.base_vault.is_empty() && !token_info.quote_vault.is_empty() {
+        [Pubkey::from_str(&token_info.base_vault)?, Pubkey::from_str(&token_info.quote_vault)?]
+    } else {
+        let pool = get_liquidity_pool(ctx.rpc_client.clone(), &mint).await?.ok_or(
+            "Pool info not found for the given tokens."
+        )?;
+        [pool.base_vault, pool.quote_vault]
+    };
+
+    let mut token_vault = None;
+    let mut decimals = 0;
+    let mut token_reserve = 0;
+    let mut sol_reserve = None;
+    for vault in vaults {
+        let account = ctx.rpc_client
+            .get_token_account(&vault).await?
+            .ok_or_else(|| format!("Quote error: vault {} not found", vault))?;
+        let amount = account.token_amount.amount.parse::<u64>()?;
+
+        if account.mint == mint.to_string() {
+            token_vault = Some(vault);
+            decimals = account.token_amount.decimals;
+            token_reserve = amount;
+        } else if account.mint == WSOL_MINT {
+            sol_reserve = Some(amount);
+        }
+    }
+    let (Some(token_vault), Some(sol_reserve)) = (token_vault, sol_reserve) else {
+        return Err(format!("Quote error: vaults are not a {}/SOL pool", mint).into());
+    };
+    let price_sol = pool_price(token_reserve, sol_reserve, decimals)?;
+
+    let mut streams = Vec::new();
+    let mut unsubscribes = Vec::new();
+    for vault in vaults {
+        let (stream, unsubscribe) = subscribe(ws, vault).await?;
+        streams.push((vault, stream));
+        unsubscribes.push(unsubscribe);
+    }
+
+    let source = Source::Pool { token_vault, decimals, token_reserve, sol_reserve };
+    Ok((Watch { source, unsubscribes }, streams, price_sol))
+}
+
+async fn subscribe(
+    ws: &EnhancedWebsocket,
+    account: Pubkey
+) -> Result<(BoxStream<'_, Update>, Unsubscribe), Box<dyn Error>> {
+    let config = RpcAccountInfoConfig {
+        encoding: Some(UiAccountEncoding::Base64),
+        commitment: Some(CommitmentConfig::processed()),
+        ..RpcAccountInfoConfig::default()
+    };
+
+    let (stream, unsubscribe) = ws.account_subscribe(&account, Some(config)).await?;
+    Ok((stream.map(move |response| (account, response)).boxed(), unsubscribe))
+}
+
+async fn unwatch(
+    watches: &mut HashMap<String, Watch>,
+    accounts: &mut HashMap<Pubkey, String>,
+    mint: &str
+) {
+    let Some(watch) = watches.remove(mint) else {
+        return;
+    };
+    accounts.retain(|_, watched| watched != mint);
+    for unsubscribe in watch.unsubscribes {
+        unsubscribe().await;
+    }
+}
+
+/// Mints of every wallet's unsold tokens, with the vaults recorded on their buy.
+async fn open_positions(ctx: &AppContext) -> Result<HashMap<String, TokenInfo>, Box<dyn Error>> {
+    let mut open = HashMap::new();
+
+    for wallet in ctx.keystore.wallets() {
+        let buy_handler = BuyMongoHandler::new(ctx.mongo.clone(), &ctx.config.mongo, wallet);
+        let mongo_handler = MongoHandler::new(ctx.mongo.clone(), &ctx.config.mongo, wallet);
+
+        let mints = buy_handler.get_unsold_token_mints(&ctx.config.mongo.trades_db, "tokens").await?;
+        for mint in mints {
+            if open.contains_key(&mint) {
+                continue;
+            }
+            let buy = mongo_handler.get_buy_transaction_from_token(
+                &mint,
+                &ctx.config.mongo.trades_db,
+                "buy_transactions"
+            ).await?;
+            open.insert(mint, buy.token_info);
+        }
+    }
+
+    Ok(open)
+}
+
+async fn publish_tick(ctx: &AppContext, tick: &PriceTick) {
+    let message = match serde_json::to_string(tick) {
+        Ok(message) => message,
+        Err(e) => {
+            eprintln!("Failed to serialize price tick: {}", e);
+            return;
+        }
+    };
+
+    let mut connection = ctx.redis.clone();
+    let ttl = ctx.config.price_feed.tick_ttl_secs;
+    if let Err(e) = connection.set_ex::<_, _, ()>(tick_key(&tick.mint), &message, ttl).await {
+        eprintln!("Failed to store price tick of {}: {}", tick.mint, e);
+    }
+    if let Err(e) = connection.publish::<_, _, ()>(&ctx.config.price_feed.channel, &message).await {
+        eprintln!("Failed to publish price tick of {}: {}", tick.mint, e);
+    }
+}
+
+fn pool_price(token_reserve: u64, sol_reserve: u64, decimals: u8) -> Result<f64, String> {
+    if token_reserve == 0 {
+        return Err("pool has no tokens".to_string());
+    }
+    let tokens = (token_reserve as f64) / (10f64).powi(decimals as i32);
+    Ok((sol_reserve as f64) / 1_000_000_000.0 / tokens)
+}
+
+fn tick_key(mint: &str) -> String {
+    format!("price:{}", mint)
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_millis() as u64)
+        .unwrap_or_default()
+}
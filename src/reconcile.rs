@@ -0,0 +1,458 @@
+use crate::buy::mongo::MongoHandler;
+use crate::buy::service::{ save_buy_details, TokenVaults };
+use crate::buy::utils::get_liquidity_pool;
+use crate::context::AppContext;
+use crate::dispatch::spawn_non_send;
+use crate::wallet::Wallet;
+use futures_util::StreamExt;
+use redis::AsyncCommands;
+use serde::{ Deserialize, Serialize };
+use solana_account_decoder::UiAccountData;
+use solana_client::rpc_client::GetConfirmedSignaturesForAddress2Config;
+use solana_client::rpc_config::RpcTransactionConfig;
+use solana_client::rpc_request::TokenAccountsFilter;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signature;
+use solana_transaction_status::option_serializer::OptionSerializer;
+use solana_transaction_status::{ UiTransactionEncoding, UiTransactionTokenBalance };
+use std::collections::HashSet;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+const WSOL_MINT: &str = "So11111111111111111111111111111111111111112";
+
+/// How many of the wallet's recent signatures are searched for a missing buy.
+const BACKFILL_SIGNATURE_LIMIT: usize = 100;
+
+pub const RECONCILE_CHANNEL: &str = "reconcile";
+pub const RECONCILE_REPORT_CHANNEL: &str = "reconcile_report";
+
+/// A non-empty SPL token account owned by the wallet.
+#[derive(Debug, Clone, Serialize)]
+pub struct Holding {
+    pub mint: String,
+    pub amount: u64,
+    pub decimals: u8,
+    pub ui_amount: f64,
+}
+
+/// A wallet holding with no unsold `tokens` document behind it.
+#[derive(Debug, Clone, Serialize)]
+pub struct UntrackedHolding {
+    pub holding: Holding,
+    pub has_buy_transaction: bool,
+    pub has_trade_state: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Backfill {
+    pub mint: String,
+    pub signature: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct ReconcileReport {
+    pub wallet: String,
+    pub address: String,
+    /// Held on-chain but not tracked as an open position in Mongo
+    pub untracked_holdings: Vec<UntrackedHolding>,
+    /// Tracked as an open position in Mongo but no longer held on-chain
+    pub stale_positions: Vec<String>,
+    pub backfilled: Vec<Backfill>,
+    pub backfill_failures: Vec<String>,
+}
+
+/// Body of an on-demand request published on the `reconcile` channel.
+#[derive(Debug, Default, Deserialize)]
+struct ReconcileRequest {
+    #[serde(default)]
+    backfill: bool,
+}
+
+/// Compares the wallet's SPL token accounts against its unsold `tokens` documents.
+///
+/// With `backfill` set, holdings that have no buy record are looked up in the wallet's
+/// recent signatures and persisted through `save_buy_details` as if the buy had just landed.
+pub async fn reconcile(
+    ctx: &AppContext,
+    wallet: &Wallet,
+    backfill: bool
+) -> Result<ReconcileReport, Box<dyn Error>> {
+    let owner = wallet.pubkey();
+    let mongo_handler = MongoHandler::new(ctx.mongo.clone(), &ctx.config.mongo, wallet);
+
+    let holdings = wallet_holdings(ctx, &owner).await?;
+    let held_mints: HashSet<&str> = holdings
+        .iter()
+        .map(|holding| holding.mint.as_str())
+        .collect();
+
+    let tracked_mints: HashSet<String> = mongo_handler
+        .get_unsold_token_mints(&ctx.config.mongo.trades_db, "tokens").await?
+        .into_iter()
+        .collect();
+
+    let mut report = ReconcileReport {
+        wallet: wallet.name.clone(),
+        address: wallet.address(),
+        ..Default::default()
+    };
+
+    report.stale_positions = tracked_mints
+        .iter()
+        .filter(|mint| !held_mints.contains(mint.as_str()))
+        .cloned()
+        .collect();
+
+    for holding in holdings.iter().filter(|holding| !tracked_mints.contains(&holding.mint)) {
+        let has_buy_transaction = mongo_handler.has_buy_transaction(
+            &holding.mint,
+            &ctx.config.mongo.trades_db,
+            "buy_transactions"
+        ).await?;
+        let has_trade_state = mongo_handler.has_trade_state(&holding.mint).await?;
+
+        if backfill && !has_buy_transaction {
+            match backfill_buy(ctx, wallet, holding).await {
+                Ok(signature) => {
+                    report.backfilled.push(Backfill {
+                        mint: holding.mint.clone(),
+                        signature: signature.to_string(),
+                    });
+                    continue;
+                }
+                Err(e) => {
+                    eprintln!("Failed to backfill buy for {}: {}", holding.mint, e);
+                    report.backfill_failures.push(holding.mint.clone());
+                }
+            }
+        }
+
+        report.untracked_holdings.push(UntrackedHolding {
+            holding: holding.clone(),
+            has_buy_transaction,
+            has_trade_state,
+        });
+    }
+
+    Ok(report)
+}
+
+pub fn log_report(report: &ReconcileReport) {
+    println!(
+        "Reconciliation of wallet {} ({}): {} untracked holding(s), {} stale position(s), {} backfilled",
+        report.wallet,
+        report.address,
+        report.untracked_holdings.len(),
+        report.stale_positions.len(),
+        report.backfilled.len()
+    );
+
+    for untracked in &report.untracked_holdings {
+        eprintln!(
+            "  Untracked holding {} ({} tokens, buy record: {}, trade state: {})",
+            untracked.holding.mint,
+            untracked.holding.ui_amount,
+            untracked.has_buy_transaction,
+            untracked.has_trade_state
+        );
+    }
+    for mint in &report.stale_positions {
+        eprintln!("  Stale position {} is not held by the wallet", mint);
+    }
+    for backfill in &report.backfilled {
+        println!("  Backfilled buy of {} from {}", backfill.mint, backfill.signature);
+    }
+    for mint in &report.backfill_failures {
+        eprintln!("  Could not backfill buy of {}", mint);
+    }
+}
+
+/// Runs a reconciliation of every wallet for each message on the `reconcile` channel and
+/// publishes one report per wallet. Requests that arrive while one is running are dropped, so
+/// two runs never backfill the same holding.
+pub async fn serve_requests(ctx: Arc<AppContext>) {
+    let running = Arc::new(Mutex::new(()));
+
+    loop {
+        match ctx.redis_client.get_async_pubsub().await {
+            Ok(mut pubsub) => {
+                if let Err(e) = pubsub.subscribe(RECONCILE_CHANNEL).await {
+                    eprintln!("Failed to subscribe to '{}': {}", RECONCILE_CHANNEL, e);
+                    tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+                    continue;
+                }
+
+                let mut messages = pubsub.on_message();
+                while let Some(msg) = messages.next().await {
+                    let request: ReconcileRequest = msg
+                        .get_payload::<String>()
+                        .ok()
+                        .and_then(|payload| serde_json::from_str(&payload).ok())
+                        .unwrap_or_default();
+
+                    let Ok(guard) = running.clone().try_lock_owned() else {
+                        println!("Reconciliation already running, ignoring request");
+                        continue;
+                    };
+
+                    let ctx = ctx.clone();
+                    // Backfill goes through the same non-Send persistence path as trades
+                    spawn_non_send(move || async move {
+                        run_and_publish(&ctx, request.backfill).await;
+                        drop(guard);
+                    });
+                }
+            }
+            Err(e) => {
+                eprintln!("Error connecting to Redis: {}", e);
+            }
+        }
+
+        tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+    }
+}
+
+async fn run_and_publish(ctx: &AppContext, backfill: bool) {
+    let mut connection = ctx.redis.clone();
+
+    for wallet in ctx.keystore.wallets() {
+        let message = match reconcile(ctx, wallet, backfill).await {
+            Ok(report) => {
+                log_report(&report);
+                serde_json::to_string(&report).unwrap_or_default()
+            }
+            Err(e) => {
+                eprintln!("Reconciliation of wallet {} failed: {}", wallet.name, e);
+                serde_json::json!({ "wallet": wallet.name, "error": e.to_string() }).to_string()
+            }
+        };
+
+        if let Err(e) = connection.publish::<_, _, ()>(RECONCILE_REPORT_CHANNEL, message).await {
+            eprintln!("Failed to publish reconciliation report: {}", e);
+        }
+    }
+}
+
+async fn wallet_holdings(ctx: &AppContext, owner: &Pubkey) -> Result<Vec<Holding>, Box<dyn Error>> {
+    let accounts = ctx.rpc_client.get_token_accounts_by_owner(
+        owner,
+        TokenAccountsFilter::ProgramId(spl_token::id())
+    ).await?;
+
+    let holdings = accounts
+        .into_iter()
+        .filter_map(|keyed| {
+            let UiAccountData::Json(parsed) = keyed.account.data else {
+                return None;
+            };
+            let info = &parsed.parsed["info"];
+            let token_amount = &info["tokenAmount"];
+
+            Some(Holding {
+                mint: info["mint"].as_str()?.to_string(),
+                amount: token_amount["amount"].as_str()?.parse().ok()?,
+                decimals: token_amount["decimals"].as_u64()? as u8,
+                ui_amount: token_amount["uiAmount"].as_f64().unwrap_or_default(),
+            })
+        })
+        .filter(|holding| holding.amount > 0 && holding.mint != WSOL_MINT)
+        .collect();
+
+    Ok(holdings)
+}
+
+async fn backfill_buy(
+    ctx: &AppContext,
+    wallet: &Wallet,
+    holding: &Holding
+) -> Result<Signature, Box<dyn Error>> {
+    let signature = find_buy_signature(ctx, &wallet.pubkey(), &holding.mint).await?;
+    let mint = Pubkey::from_str(&holding.mint)?;
+
+    let (token_vaults, pump) = match get_liquidity_pool(ctx.rpc_client.clone(), &mint).await? {
+        Some(pool) if pool.quote_mint.to_string() == WSOL_MINT =>
+            (
+                TokenVaults {
+                    base_vault: pool.quote_vault.to_string(),
+                    quote_vault: pool.base_vault.to_string(),
+                    base_mint: pool.base_mint.to_string(),
+                    quote_mint: WSOL_MINT.to_string(),
+                },
+                false,
+            ),
+        Some(pool) =>
+            (
+                TokenVaults {
+                    base_vault: "".to_string(),
+                    quote_vault: "".to_string(),
+                    base_mint: pool.quote_mint.to_string(),
+                    quote_mint: WSOL_MINT.to_string(),
+                },
+                false,
+            ),
+        None =>
+            (
+                TokenVaults {
+                    base_vault: "".to_string(),
+                    quote_vault: "".to_string(),
+                    base_mint: holding.mint.clone(),
+                    quote_mint: WSOL_MINT.to_string(),
+                },
+                true,
+            ),
+    };
+
+    save_buy_details(
+        ctx,
+        wallet,
+        &signature,
+        holding.decimals,
+        &holding.mint,
+        token_vaults,
+        pump,
+        "reconciled".to_string(),
+        "reconciled".to_string()
+    ).await?;
+
+    Ok(signature)
+}
+
+/// Most recent successful transaction that increased the wallet's balance of `mint`.
+async fn find_buy_signature(
+    ctx: &AppContext,
+    owner: &Pubkey,
+    mint: &str
+) -> Result<Signature, Box<dyn Error>> {
+    let config = GetConfirmedSignaturesForAddress2Config {
+        before: None,
+        until: None,
+        limit: Some(BACKFILL_SIGNATURE_LIMIT),
+        commitment: Some(CommitmentConfig::finalized()),
+    };
+    let signatures = ctx.rpc_client.get_signatures_for_address_with_config(owner, config).await?;
+
+    let owner = owner.to_string();
+    for status in signatures.into_iter().filter(|status| status.err.is_none()) {
+        let signature = Signature::from_str(&status.signature)?;
+        let config = RpcTransactionConfig {
+            encoding: Some(UiTransactionEncoding::Json),
+            commitment: Some(CommitmentConfig::confirmed()),
+            max_supported_transaction_version: Some(0),
+        };
+        let transaction = ctx.rpc_client.get_transaction_with_config(&signature, config).await?;
+
+        let Some(meta) = transaction.transaction.meta else {
+            continue;
+        };
+        if received(&meta.pre_token_balances, &meta.post_token_balances, &owner, mint) {
+            return Ok(signature);
+        }
+    }
+
+    Err(format!("No buy of {} in the last {} signatures", mint, BACKFILL_SIGNATURE_LIMIT).into())
+}
+
+/// Whether a transaction's token balances show `owner` ending up with more of `mint`.
+fn received(
+    pre_balances: &OptionSerializer<Vec<UiTransactionTokenBalance>>,
+    post_balances: &OptionSerializer<Vec<UiTransactionTokenBalance>>,
+    owner: &str,
+    mint: &str
+) -> bool {
+    owned_balance(post_balances, owner, mint) > owned_balance(pre_balances, owner, mint)
+}
+
+fn owned_balance(
+    balances: &OptionSerializer<Vec<UiTransactionTokenBalance>>,
+    owner: &str,
+    mint: &str
+) -> u64 {
+    let OptionSerializer::Some(balances) = balances else {
+        return 0;
+    };
+
+    balances
+        .iter()
+        .filter(|balance| balance.mint == mint)
+        .filter(|balance| matches!(&balance.owner, OptionSerializer::Some(o) if o == owner))
+        .filter_map(|balance| balance.ui_token_amount.amount.parse::<u64>().ok())
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_account_decoder::parse_token::UiTokenAmount;
+
+    const OWNER: &str = "7xKXtg2CW87d97TXJSDpbD5jBkheTqA83TZRuJosgAsU";
+    const OTHER: &str = "9WzDXwBbmkg8ZTbNMqUxvQRAyrZzDsGYdLVL9zYtAWWM";
+    const MINT: &str = "4k3Dyjzvzp8eMZWUXbBCjEvwSkkk59S5iCNLY3QrkX6R";
+
+    fn balance(owner: &str, mint: &str, amount: u64) -> UiTransactionTokenBalance {
+        UiTransactionTokenBalance {
+            account_index: 1,
+            mint: mint.to_string(),
+            ui_token_amount: UiTokenAmount {
+                ui_amount: None,
+                decimals: 6,
+                amount: amount.to_string(),
+                ui_amount_string: String::new(),
+            },
+            owner: OptionSerializer::Some(owner.to_string()),
+            program_id: OptionSerializer::Some(spl_token::id().to_string()),
+        }
+    }
+
+    #[test]
+    fn owned_balance_counts_only_the_owners_mint() {
+        let balances = OptionSerializer::Some(
+            vec![
+                balance(OWNER, MINT, 1_000),
+                balance(OWNER, MINT, 500),
+                balance(OTHER, MINT, 7_000),
+                balance(OWNER, WSOL_MINT, 9_000)
+            ]
+        );
+
+        assert_eq!(owned_balance(&balances, OWNER, MINT), 1_500);
+        assert_eq!(owned_balance(&OptionSerializer::None, OWNER, MINT), 0);
+        assert_eq!(owned_balance(&OptionSerializer::Skip, OWNER, MINT), 0);
+    }
+
+    #[test]
+    fn buys_are_transactions_that_raise_the_owners_balance() {
+        // First buy: the token account only appears after the transaction
+        let pre = OptionSerializer::Some(vec![]);
+        let post = OptionSerializer::Some(vec![balance(OWNER, MINT, 1_000)]);
+        assert!(received(&pre, &post, OWNER, MINT));
+
+        // A later buy on top of an existing balance
+        let pre = OptionSerializer::Some(vec![balance(OWNER, MINT, 1_000)]);
+        let post = OptionSerializer::Some(vec![balance(OWNER, MINT, 3_000)]);
+        assert!(received(&pre, &post, OWNER, MINT));
+    }
+
+    #[test]
+    fn sells_and_other_wallets_buys_are_not_picked() {
+        // A sell lowers the balance
+        let pre = OptionSerializer::Some(vec![balance(OWNER, MINT, 3_000)]);
+        let post = OptionSerializer::Some(vec![balance(OWNER, MINT, 1_000)]);
+        assert!(!received(&pre, &post, OWNER, MINT));
+
+        // Someone else receiving the mint in a transaction the wallet signed
+        let pre = OptionSerializer::Some(vec![balance(OWNER, MINT, 1_000)]);
+        let post = OptionSerializer::Some(
+            vec![balance(OWNER, MINT, 1_000), balance(OTHER, MINT, 5_000)]
+        );
+        assert!(!received(&pre, &post, OWNER, MINT));
+
+        // The wallet receiving a different mint
+        let pre = OptionSerializer::Some(vec![]);
+        let post = OptionSerializer::Some(vec![balance(OWNER, WSOL_MINT, 5_000)]);
+        assert!(!received(&pre, &post, OWNER, MINT));
+    }
+}
@@ -0,0 +1,114 @@
+use super::mongo::MongoHandler;
+use super::sell::SellTransaction;
+use crate::buy::price::PUMP_TOKEN_DECIMALS;
+use crate::confirmation::{ TradeFailure, Venue };
+use crate::context::AppContext;
+use crate::wallet::Wallet;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::pubkey::Pubkey;
+use spl_associated_token_account::get_associated_token_address;
+use std::error::Error;
+use std::str::FromStr;
+
+/// Turns a sell of `percent` of the remaining position into a raw token `amount`.
+///
+/// The position is the smaller of the wallet's token balance and what the buy record has
+/// left. If the sell would leave no more than dust behind (`trading.min_balance_threshold` on
+/// Raydium, `trading.pump_dust_epsilon` on pump.fun) the whole balance is sold instead and
+/// the emptied token account is closed in the same transaction. `sol_amount` is set to the
+/// cost basis of the tokens sold.
+pub async fn resolve_percent(
+    ctx: &AppContext,
+    wallet: &Wallet,
+    sell_transaction: &mut SellTransaction,
+    venue: Venue
+) -> Result<(), Box<dyn Error>> {
+    let Some(percent) = sell_transaction.percent else {
+        return Ok(());
+    };
+
+    let mongo_handler = MongoHandler::new(ctx.mongo.clone(), &ctx.config.mongo, wallet);
+    let buy_transaction = mongo_handler.get_buy_transaction_from_token(
+        &sell_transaction.mint,
+        &ctx.config.mongo.trades_db,
+        "buy_transactions"
+    ).await?;
+    let recorded = buy_transaction.amount.max(0.0) as u64;
+
+    // Paper positions only exist in Mongo
+    let (balance, decimals) = if ctx.config.paper.enabled {
+        (recorded, None)
+    } else {
+        let mint = Pubkey::from_str(&sell_transaction.mint)?;
+        let token_account = get_associated_token_address(&wallet.pubkey(), &mint);
+        let token_balance = ctx.rpc_client
+            .get_token_account_balance_with_commitment(
+                &token_account,
+                CommitmentConfig::confirmed()
+            ).await
+            .map_err(|e| TradeFailure::BalanceUnavailable(e.to_string()))?.value;
+        (token_balance.amount.parse::<u64>()?, Some(token_balance.decimals))
+    };
+
+    // Without a buy record the balance is all we know about the position
+    let position = if recorded > 0 { balance.min(recorded) } else { balance };
+    if position == 0 {
+        return Err(
+            TradeFailure::InsufficientBalance(
+                format!("Nothing left to sell of {}", sell_transaction.mint)
+            ).into()
+        );
+    }
+
+    let basis_points = (percent * 100.0).round() as u128;
+    let mut amount = (((position as u128) * basis_points) / 10_000) as u64;
+
+    let dust = match venue {
+        Venue::Raydium => ctx.config.trading.min_balance_threshold,
+        Venue::PumpFun => {
+            let decimals = decimals.unwrap_or(PUMP_TOKEN_DECIMALS as u8);
+            (ctx.config.trading.pump_dust_epsilon * (10f64).powi(decimals as i32)) as u64
+        }
+    };
+    if balance.saturating_sub(amount) <= dust {
+        println!(
+            "Selling all {} tokens of {}: {}% would leave only dust",
+            balance,
+            sell_transaction.mint,
+            percent
+        );
+        amount = balance;
+        sell_transaction.close_account = !ctx.config.paper.enabled;
+    }
+    if amount == 0 {
+        return Err(
+            TradeFailure::InsufficientBalance(
+                format!("{}% of {} is less than one token unit", percent, sell_transaction.mint)
+            ).into()
+        );
+    }
+
+    let initial_amount = if buy_transaction.initial_amount > 0.0 {
+        buy_transaction.initial_amount
+    } else {
+        buy_transaction.amount
+    };
+    if initial_amount > 0.0 {
+        sell_transaction.sol_amount =
+            (buy_transaction.sol_amount * (amount as f64)) / initial_amount;
+    }
+    if sell_transaction.entry == 0.0 {
+        sell_transaction.entry = buy_transaction.entry_price;
+    }
+    sell_transaction.amount = amount;
+
+    println!(
+        "Resolved {}% of {} to {} tokens (position {}, balance {})",
+        percent,
+        sell_transaction.mint,
+        amount,
+        position,
+        balance
+    );
+    Ok(())
+}
@@ -3,9 +3,11 @@ use super::sell::SellTransaction;
 use super::price;
 use super::utils;
 use mongo::{ MongoHandler, SellTransaction as SellTransactionMongo, TokenInfo };
-use redis::{ Commands, RedisResult };
-use solana_client::nonblocking::rpc_client::RpcClient;
-use std::sync::Arc;
+use redis::{ AsyncCommands, RedisResult };
+use redis::aio::MultiplexedConnection;
+use crate::confirmation::TradeFailure;
+use crate::context::AppContext;
+use crate::wallet::Wallet;
 use std::time::Duration;
 use price::get_current_sol_price;
 use utils::calculate_sol_amount_received;
@@ -15,29 +17,21 @@ use mongodb::bson::DateTime;
 use solana_sdk::signature::Signature;
 use solana_client::rpc_config::RpcTransactionConfig;
 use solana_sdk::commitment_config::CommitmentConfig;
-use solana_sdk::pubkey::Pubkey;
-use std::str::FromStr;
 use chrono::Utc;
 
 pub async fn confirm_sell(
+    ctx: &AppContext,
+    wallet: &Wallet,
     signature: &Signature,
     sell_transaction: &SellTransaction,
     is_pump: bool
 ) -> Result<(), Box<dyn Error>> {
-    let rpc_endpoint = std::env
-        ::var("RPC_URL")
-        .expect("You must set the RPC_URL environment variable!");
-    let rpc_client: Arc<RpcClient> = Arc::new(RpcClient::new(rpc_endpoint.to_string()));
-    let mongo_handler = MongoHandler::new().await.map_err(|err| {
-        format!("Error creating MongoDB handler: {:?}", err)
-    })?;
+    let rpc_client = ctx.rpc_client.clone();
 
     let mut retry_count = 0;
-    let max_retries = 3;
+    let max_retries: u32 = 3;
     let retry_delay = Duration::from_secs(10);
 
-    let usd_sol_price = get_current_sol_price().await?;
-
     let config = RpcTransactionConfig {
         encoding: Some(UiTransactionEncoding::JsonParsed),
         commitment: Some(CommitmentConfig::confirmed()),
@@ -48,105 +42,14 @@ pub async fn confirm_sell(
     while !confirmed && retry_count <= max_retries {
         match rpc_client.get_transaction_with_config(&signature, config.clone()).await {
             Ok(confirmed_transaction) => {
-                let sell_price = sell_transaction.current_token_price_usd;
-                let mut sol_amount = calculate_sol_amount_received(
+                let sol_amount = calculate_sol_amount_received(
                     &confirmed_transaction,
-                    &rpc_client,
-                    &Pubkey::from_str(&sell_transaction.mint).unwrap()
-                ).await? as f64;
-
-                let profit = (sol_amount as f64) - (sell_transaction.sol_amount as f64);
-                let profit_usd = profit * usd_sol_price;
-                let profit_percentage = (profit / (sell_transaction.sol_amount as f64)) * 100.0;
-
-                let mut fee = confirmed_transaction.transaction.meta.unwrap().fee;
-
-                if is_pump {
-                    // Set the fee to 1% of the SOL amount received
-                    fee = (sol_amount * 0.01 * 1_000_000_000.0) as u64;
-
-                    // Subtract the fee from sol_amount to get the net amount after fee deduction
-                    sol_amount -= (fee as f64) / 1_000_000_000.0;
-                }
-
-                // Calculate the fee in SOL and USD terms
-                let fee_sol = (fee as f64) / 1_000_000_000.0;
-                let fee_usd = fee_sol * usd_sol_price;
-
-                // Format and print profit percentage
-                let profit_percentage_str = format!("{:.4}", profit_percentage);
-
-                // If you need to use the profit percentage as a number
-                let profit_percentage_value: f64 = profit_percentage_str
-                    .parse()
-                    .unwrap_or_default();
-
-                let mut trade_state = mongo_handler.fetch_trade_state(
-                    &sell_transaction.mint.clone()
-                ).await?;
-
-                let mut buy_transaction = mongo_handler.get_buy_transaction_from_token(
-                    &sell_transaction.mint.clone(),
-                    "solsniper",
-                    "buy_transactions"
-                ).await?;
-
-                buy_transaction.amount = buy_transaction.amount - (sell_transaction.amount as f64);
-                mongo_handler.update_buy_transaction(&buy_transaction).await?;
-
-                if buy_transaction.amount <= 100.0 {
-                    mongo_handler.update_token_metadata_sold_field(
-                        &sell_transaction.mint,
-                        "solsniper",
-                        "tokens"
-                    ).await?;
-
-                    decrease_buy_counter().await?;
-                }
-
-                if
-                    !trade_state.initial_investment_taken &&
-                    !trade_state.stop_loss_triggered &&
-                    trade_state.stop_loss_at_breakeven
-                {
-                    println!("Initial investment taken");
-                    trade_state.initial_investment_taken = true;
-                    trade_state.last_profit_taking_time = Some(Utc::now().into());
-                }
-
-                trade_state.taken_out += sol_amount;
-                trade_state.total_fee += fee_sol;
-                trade_state.remaining -= 0.0;
-
-                mongo_handler.update_trade_state(&trade_state).await?;
-
-                let sell_transaction_mongo = SellTransactionMongo {
-                    transaction_signature: signature.to_string(),
-                    token_info: TokenInfo {
-                        base_mint: sell_transaction.mint.clone(),
-                        quote_mint: "So11111111111111111111111111111111111111112".to_string(),
-                        base_vault: sell_transaction.base_vault.clone(),
-                        quote_vault: sell_transaction.quote_vault.clone(),
-                    },
-                    amount: sell_transaction.amount as f64,
-                    sol_amount: sol_amount as f64,
-                    sol_price: sell_transaction.current_token_price_sol,
-                    sell_price,
-                    entry_price: sell_transaction.entry.clone(),
-                    token_metadata: sell_transaction.metadata.clone(),
-                    fee_sol: fee_sol,
-                    fee_usd: fee_usd,
-                    profit,
-                    profit_usd,
-                    profit_percentage: profit_percentage_value,
-                    created_at: DateTime::now(),
-                };
-
-                mongo_handler.store_sell_transaction_info(
-                    sell_transaction_mongo,
-                    "solsniper",
-                    "sell_transactions"
-                ).await?;
+                    &wallet.pubkey(),
+                    ctx.config.jito.tip_lamports
+                )?;
+                let fee = confirmed_transaction.transaction.meta.unwrap().fee;
+
+                store_sell(ctx, wallet, signature, sell_transaction, sol_amount, fee, is_pump).await?;
 
                 confirmed = true;
             }
@@ -160,31 +63,134 @@ pub async fn confirm_sell(
     if confirmed {
         return Ok(());
     } else {
-        return Err("Transaction not confirmed after 3 retries".into());
+        return Err(TradeFailure::NotConfirmed(max_retries).into());
     }
 }
 
-pub async fn decrease_buy_counter() -> RedisResult<()> {
-    let redis_url = std::env
-        ::var("REDIS_URL")
-        .expect("You must set the REDIS_URL environment variable");
+/// Records a sell against the open position: reduces the buy, updates the trade state and
+/// stores the `sell_transactions` entry. `fee` is the network fee in lamports.
+pub async fn store_sell(
+    ctx: &AppContext,
+    wallet: &Wallet,
+    signature: &Signature,
+    sell_transaction: &SellTransaction,
+    sol_amount: f64,
+    fee: u64,
+    is_pump: bool
+) -> Result<(), Box<dyn Error>> {
+    let mongo_handler = MongoHandler::new(ctx.mongo.clone(), &ctx.config.mongo, wallet);
+
+    let usd_sol_price = get_current_sol_price().await?;
+
+    let sell_price = sell_transaction.current_token_price_usd;
+    let mut sol_amount = sol_amount;
+
+    let profit = (sol_amount as f64) - (sell_transaction.sol_amount as f64);
+    let profit_usd = profit * usd_sol_price;
+    let profit_percentage = (profit / (sell_transaction.sol_amount as f64)) * 100.0;
+
+    let mut fee = fee;
+
+    if is_pump {
+        // Set the fee to 1% of the SOL amount received
+        fee = (sol_amount * 0.01 * 1_000_000_000.0) as u64;
+
+        // Subtract the fee from sol_amount to get the net amount after fee deduction
+        sol_amount -= (fee as f64) / 1_000_000_000.0;
+    }
+
+    // Calculate the fee in SOL and USD terms
+    let fee_sol = (fee as f64) / 1_000_000_000.0;
+    let fee_usd = fee_sol * usd_sol_price;
+
+    // Format and print profit percentage
+    let profit_percentage_str = format!("{:.4}", profit_percentage);
+
+    // If you need to use the profit percentage as a number
+    let profit_percentage_value: f64 = profit_percentage_str.parse().unwrap_or_default();
+
+    let mut trade_state = mongo_handler.fetch_trade_state(&sell_transaction.mint.clone()).await?;
 
-    let client = redis::Client::open(redis_url.clone()).expect("Failed to create Redis client");
+    let mut buy_transaction = mongo_handler.get_buy_transaction_from_token(
+        &sell_transaction.mint.clone(),
+        &ctx.config.mongo.trades_db,
+        "buy_transactions"
+    ).await?;
 
-    let mut con = client.get_connection().expect("Failed to connect to Redis");
+    buy_transaction.amount = buy_transaction.amount - (sell_transaction.amount as f64);
+    mongo_handler.update_buy_transaction(&buy_transaction).await?;
 
-    // Get the current value of buy_transaction_count
-    let current_count: i32 = con.get("buy_transaction_count").unwrap_or(0);
-    println!("Current count: {}", current_count);
+    if buy_transaction.amount <= 100.0 {
+        mongo_handler.update_token_metadata_sold_field(
+            &sell_transaction.mint,
+            &ctx.config.mongo.trades_db,
+            "tokens"
+        ).await?;
 
-    // Calculate the new value, ensuring it doesn't go below zero
-    let new_count = if current_count > 0 { current_count - 1 } else { 0 };
-    println!("New count: {}", new_count);
+        decrease_buy_counter(
+            &mut ctx.redis.clone(),
+            &wallet.buy_counter_key(ctx.config.paper.enabled)
+        ).await?;
+    }
+
+    if
+        !trade_state.initial_investment_taken &&
+        !trade_state.stop_loss_triggered &&
+        trade_state.stop_loss_at_breakeven
+    {
+        println!("Initial investment taken");
+        trade_state.initial_investment_taken = true;
+        trade_state.last_profit_taking_time = Some(Utc::now().into());
+    }
 
-    // Set the new value back to Redis
-    let _: () = con
-        .set("buy_transaction_count", new_count)
-        .expect("Failed to set buy_transaction_count");
+    trade_state.taken_out += sol_amount;
+    trade_state.total_fee += fee_sol;
+    trade_state.remaining -= 0.0;
+
+    mongo_handler.update_trade_state(&trade_state).await?;
+
+    let sell_transaction_mongo = SellTransactionMongo {
+        transaction_signature: signature.to_string(),
+        token_info: TokenInfo {
+            base_mint: sell_transaction.mint.clone(),
+            quote_mint: "So11111111111111111111111111111111111111112".to_string(),
+            base_vault: sell_transaction.base_vault.clone(),
+            quote_vault: sell_transaction.quote_vault.clone(),
+        },
+        amount: sell_transaction.amount as f64,
+        sol_amount: sol_amount as f64,
+        sol_price: sell_transaction.current_token_price_sol,
+        sell_price,
+        entry_price: sell_transaction.entry.clone(),
+        token_metadata: sell_transaction.metadata.clone(),
+        fee_sol: fee_sol,
+        fee_usd: fee_usd,
+        profit,
+        profit_usd,
+        profit_percentage: profit_percentage_value,
+        wallet: wallet.address(),
+        paper: ctx.config.paper.enabled,
+        created_at: DateTime::now(),
+    };
+
+    mongo_handler.store_sell_transaction_info(
+        sell_transaction_mongo,
+        &ctx.config.mongo.trades_db,
+        "sell_transactions"
+    ).await?;
+
+    Ok(())
+}
+
+pub async fn decrease_buy_counter(con: &mut MultiplexedConnection, key: &str) -> RedisResult<()> {
+    // Decrement atomically so concurrent sells can't lose updates
+    let new_count: i32 = con.decr(key, 1).await?;
+
+    // Ensure the counter doesn't go below zero
+    if new_count < 0 {
+        let _: () = con.set(key, 0).await?;
+    }
+    println!("New count: {}", new_count.max(0));
 
     Ok(())
 }
@@ -4,3 +4,4 @@ pub mod confirm;
 pub mod mongo;
 pub mod price;
 pub mod pump;
+pub mod amount;
@@ -1,5 +1,8 @@
-use mongodb::{ Client, options::ClientOptions, bson::doc, bson::Document, Collection };
+use mongodb::{ Client, bson::doc, bson::Document, Collection };
+use crate::config::MongoConfig;
+use crate::wallet::Wallet;
 use mongodb::error::Error as MongoError;
+use mongodb::options::{ FindOneAndUpdateOptions, ReturnDocument };
 use serde::Serialize;
 use serde::Deserialize;
 use mongodb::bson::DateTime;
@@ -7,6 +10,10 @@ use futures::stream::TryStreamExt;
 
 pub struct MongoHandler {
     client: Client,
+    trades_db: String,
+    state_db: String,
+    wallet: String,
+    owns_legacy: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -31,6 +38,9 @@ pub enum TransactionType {
 pub struct BuyTransaction {
     pub transaction_signature: String,
     pub token_info: TokenInfo,
+    /// Raw token amount originally bought; `amount` is what is left of it
+    #[serde(default)]
+    pub initial_amount: f64,
     pub amount: f64,
     pub sol_amount: f64,
     pub sol_price: f64,
@@ -57,6 +67,11 @@ pub struct SellTransaction {
     pub profit: f64,
     pub profit_usd: f64,
     pub profit_percentage: f64,
+    #[serde(default)]
+    pub wallet: String,
+    /// Simulated fill recorded in paper trading mode
+    #[serde(default)]
+    pub paper: bool,
     pub created_at: DateTime,
 }
 
@@ -69,6 +84,8 @@ pub struct TradeState {
     pub profit_taking_count: i32,
     pub last_profit_taking_time: Option<DateTime>,
     pub last_profit_percentage: f64,
+    #[serde(default)]
+    pub highest_profit_percentage: f64,
     pub stop_loss_triggered: bool,
     pub initial_investment: f64,
     pub group_title: String,
@@ -90,24 +107,35 @@ pub struct TokenInfo {
 }
 
 impl MongoHandler {
-    pub async fn new() -> Result<Self, MongoError> {
-        // Load the MongoDB connection string from an environment variable
-        let client_uri = std::env
-            ::var("MONGODB_URI")
-            .expect("You must set the MONGODB_URI environment variable!");
-
-        // Parse the client options
-        let options = ClientOptions::parse(&client_uri).await?;
-        let client = Client::with_options(options)?;
+    pub fn new(client: Client, config: &MongoConfig, wallet: &Wallet) -> Self {
+        Self {
+            client,
+            trades_db: config.trades_db.clone(),
+            state_db: config.state_db.clone(),
+            wallet: wallet.address(),
+            owns_legacy: wallet.is_default,
+        }
+    }
 
-        Ok(Self { client })
+    /// Restricts `filter` to documents owned by this handler's wallet.
+    fn scoped(&self, mut filter: Document) -> Document {
+        if self.owns_legacy {
+            // Documents written before wallets were tracked belong to the default wallet
+            filter.insert(
+                "$or",
+                vec![doc! { "wallet": &self.wallet }, doc! { "wallet": { "$exists": false } }]
+            );
+        } else {
+            filter.insert("wallet", &self.wallet);
+        }
+        filter
     }
 
     pub async fn update_buy_transaction(
         &self,
         buy_transaction: &BuyTransaction
     ) -> Result<(), MongoError> {
-        let db = self.client.database("solsniper");
+        let db = self.client.database(&self.trades_db);
         let collection: Collection<Document> = db.collection("buy_transactions"); // Replace with your collection name
 
         let filter =
@@ -134,7 +162,7 @@ impl MongoHandler {
         if buy_transaction.amount <= 100.0 {
             self.update_token_metadata_sold_field(
                 &buy_transaction.token_info.base_mint,
-                "solsniper",
+                &self.trades_db,
                 "tokens"
             ).await?;
         }
@@ -154,7 +182,7 @@ impl MongoHandler {
             .database(db_name)
             .collection(collection_name);
 
-        let filter = doc! { "token_metadata.mint": mint_address };
+        let filter = self.scoped(doc! { "token_metadata.mint": mint_address });
 
         // Find the document with the specific mint address
         let mut cursor = my_coll.find(filter, None).await?;
@@ -183,9 +211,9 @@ impl MongoHandler {
         let db = self.client.database(db_name);
         let collection: Collection<Document> = db.collection(collection_name);
 
-        let filter = doc! {
+        let filter = self.scoped(doc! {
             "token_info.base_mint": token_mint
-        };
+        });
 
         let document = match collection.find_one(filter, None).await {
             Ok(doc) => doc,
@@ -212,6 +240,7 @@ impl MongoHandler {
                         base_vault: "".to_string(),
                         quote_vault: "".to_string(),
                     },
+                    initial_amount: 0.0,
                     amount: 0.0,
                     sol_amount: 0.0,
                     sol_price: 0.0,
@@ -236,9 +265,9 @@ impl MongoHandler {
         let collection: Collection<Document> = db.collection(collection_name);
 
         // Define the filter to find the document with the given ObjectId
-        let filter = doc! {
+        let filter = self.scoped(doc! {
             "token_metadata.mint": mint
-        };
+        });
 
         // Define the update operation to set the "sold" field to true
         let update = doc! {
@@ -255,12 +284,12 @@ impl MongoHandler {
     }
 
     pub async fn fetch_trade_state(&self, token_mint: &str) -> Result<TradeState, MongoError> {
-        let db = self.client.database("trading"); // Replace with your database name
+        let db = self.client.database(&self.state_db);
         let collection: Collection<Document> = db.collection("trade_states"); // Replace with your collection name
 
-        let filter = doc! {
+        let filter = self.scoped(doc! {
             "token_mint": token_mint
-        };
+        });
 
         let document = collection.find_one(filter, None).await?;
 
@@ -282,6 +311,7 @@ impl MongoHandler {
                     profit_taking_count: 0,
                     last_profit_taking_time: None,
                     last_profit_percentage: 0.0,
+                    highest_profit_percentage: 0.0,
                     stop_loss_triggered: false,
                     initial_investment: 0.0,
                     group_title: "".to_string(),
@@ -298,12 +328,12 @@ impl MongoHandler {
     }
 
     pub async fn update_trade_state(&self, trade_state: &TradeState) -> Result<(), MongoError> {
-        let db = self.client.database("trading"); // Replace with your database name
+        let db = self.client.database(&self.state_db);
         let collection: Collection<Document> = db.collection("trade_states"); // Replace with your collection name
 
-        let filter = doc! {
+        let filter = self.scoped(doc! {
             "token_mint": &trade_state.token_mint
-        };
+        });
 
         let update =
             doc! {
@@ -327,6 +357,54 @@ impl MongoHandler {
         Ok(())
     }
 
+    /// Raises `highest_profit_percentage` to `profit_percentage` if it is a new high and returns
+    /// the trade state as stored afterwards, or `None` if the mint has no trade state.
+    pub async fn record_profit(
+        &self,
+        token_mint: &str,
+        profit_percentage: f64
+    ) -> Result<Option<TradeState>, MongoError> {
+        let db = self.client.database(&self.state_db);
+        let collection: Collection<Document> = db.collection("trade_states");
+
+        let filter = self.scoped(doc! {
+            "token_mint": token_mint
+        });
+        let update = doc! {
+            "$max": {
+                "highest_profit_percentage": profit_percentage
+            }
+        };
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+
+        match collection.find_one_and_update(filter, update, options).await? {
+            Some(doc) => Ok(Some(bson::from_document(doc)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Applies `update` to the trade state only if it still matches `expected`, so that a
+    /// rule is acted on once even when several evaluations race. Returns whether it applied.
+    pub async fn claim_trade_state(
+        &self,
+        token_mint: &str,
+        expected: Document,
+        update: Document
+    ) -> Result<bool, MongoError> {
+        let db = self.client.database(&self.state_db);
+        let collection: Collection<Document> = db.collection("trade_states");
+
+        let mut filter = self.scoped(doc! {
+            "token_mint": token_mint
+        });
+        filter.extend(expected);
+
+        let result = collection.update_one(filter, update, None).await?;
+        Ok(result.matched_count == 1)
+    }
+
     pub async fn store_sell_transaction_info(
         &self,
         transaction: SellTransaction,
@@ -343,4 +421,23 @@ impl MongoHandler {
 
         Ok(())
     }
+
+    pub async fn get_sell_transaction_by_signature(
+        &self,
+        signature: &str,
+        db_name: &str,
+        collection_name: &str
+    ) -> Result<Option<SellTransaction>, MongoError> {
+        let db = self.client.database(db_name);
+        let collection: Collection<Document> = db.collection(collection_name);
+
+        let filter = doc! {
+            "transaction_signature": signature
+        };
+
+        match collection.find_one(filter, None).await? {
+            Some(doc) => Ok(Some(bson::from_document(doc)?)),
+            None => Ok(None),
+        }
+    }
 }
@@ -1,25 +1,29 @@
-use solana_client::rpc_client::RpcClient;
 use solana_sdk::{
     commitment_config::CommitmentConfig,
     instruction::{ AccountMeta, Instruction },
     pubkey::Pubkey,
-    signature::{ Keypair, Signer },
+    signature::Signer,
 };
 use super::sell;
 use solana_sdk::system_program;
 use super::mongo;
 use mongo::MongoHandler;
 use helius::types::*;
-use helius::Helius;
+use crate::context::AppContext;
+use crate::wallet::Wallet;
+use crate::paper;
+use crate::simulation;
+use crate::buy::utils::apply_slippage;
+use crate::command::validate_slippage;
+use crate::confirmation::TradeFailure;
 use spl_associated_token_account::get_associated_token_address;
 use spl_associated_token_account::instruction::create_associated_token_account;
 use std::str::FromStr;
-use serde::Deserialize;
-use reqwest::header::*;
+use crate::buy::price::{ get_bonding_curve, PUMP_FEE_BASIS_POINTS };
 use sell::SellTransaction;
 use sell::find_sell_signature;
 
-use crate::sell::confirm::confirm_sell;
+use crate::sell::confirm::{ confirm_sell, store_sell };
 
 const GLOBAL: &str = "4wTV1YmiEkRvAtNtsSGPtUrqRYQMe5SKy2uB4Jjaxnjf";
 const FEE_RECIPIENT: &str = "CebN5WGQ4jvEPvsVU4EoHEpgzq1VV7AbicfhtW4xC9iM";
@@ -28,25 +32,22 @@ pub const EVENT_AUTHORITY: &str = "Ce6TQqeHC9p8KetsN6JsjHK7UTZk7nasjjnr7XxXp9F1"
 pub const TOKEN_PROGRAM: &str = "TokenkegQfeZyiNwAJbNbGKPFXCWuBvf9Ss623VQ5DA";
 const PUMP_FUN_PROGRAM: &str = "6EF8rrecthR5Dkzon8Nwu78hRvfCKubJ14M5uBEwF6P";
 pub const ASSOCIATED_TOKEN_PROGRAM: &str = "ATokenGPvbdGVxr1b2hvZbsiqW5xWH25efTNsLJA8knL";
+/// First 8 bytes of sha256("global:sell")
+const SELL_DISCRIMINATOR: [u8; 8] = [51, 230, 133, 164, 1, 127, 131, 173];
 use std::error::Error;
 use solana_sdk::signature::Signature;
 use solana_sdk::instruction::Instruction as SolanaInstruction;
 use solana_client::rpc_config::RpcSendTransactionConfig;
 
 async fn create_transaction(
-    instructions: Vec<SolanaInstruction>,
-    keypair: Keypair
+    ctx: &AppContext,
+    wallet: &Wallet,
+    instructions: Vec<SolanaInstruction>
 ) -> Result<Signature, Box<dyn Error>> {
-    let api_key: String = std::env
-        ::var("HELIUS_API_KEY")
-        .expect("You must set the HELIUS_API_KEY environment variable!");
-    let cluster: Cluster = Cluster::MainnetBeta;
-    let helius: Helius = Helius::new(&api_key, cluster).unwrap();
-
     let config = SmartTransactionConfig {
         create_config: CreateSmartTransactionConfig {
             instructions,
-            signers: vec![&keypair],
+            signers: vec![&wallet.keypair],
             lookup_tables: None,
             fee_payer: None,
         },
@@ -59,7 +60,13 @@ async fn create_transaction(
         },
     };
 
-    match helius.send_smart_transaction_with_tip(config, Some(600000), Some("NY")).await {
+    match
+        ctx.helius.send_smart_transaction_with_tip(
+            config,
+            Some(ctx.config.jito.tip_lamports),
+            Some(ctx.config.jito.region())
+        ).await
+    {
         Ok(signature) => {
             dbg!("Transaction sent successfully: {}", &signature);
             return Ok(signature);
@@ -72,48 +79,57 @@ async fn create_transaction(
 }
 
 pub async fn pump_fun_sell(
+    ctx: &AppContext,
+    wallet: &Wallet,
     mint_str: &str,
     token_amount: u64,
-    slippage_decimal: f64,
+    slippage_pct: f64,
     sell_transaction: &SellTransaction
 ) -> Result<Signature, Box<dyn Error>> {
-    let rpc_endpoint = std::env
-        ::var("RPC_URL")
-        .expect("You must set the RPC_URL environment variable!");
-    let connection = RpcClient::new_with_commitment(
-        rpc_endpoint.to_string(),
-        CommitmentConfig::confirmed()
-    );
-
-    let mongo_handler = MongoHandler::new().await.map_err(|err| {
-        format!("Error creating MongoDB handler: {:?}", err)
-    })?;
-
-    let private_key = std::env
-        ::var("PRIVATE_KEY")
-        .expect("You must set the PRIVATE_KEY environment variable!");
-    let payer = Keypair::from_base58_string(&private_key);
+    validate_slippage(slippage_pct)?;
+
+    let connection = ctx.rpc_client.clone();
+
+    let mongo_handler = MongoHandler::new(ctx.mongo.clone(), &ctx.config.mongo, wallet);
+
+    let payer = wallet.keypair.clone();
     let owner = payer.pubkey();
     let mint = Pubkey::from_str(mint_str).unwrap();
 
     let token_account_address = get_associated_token_address(&owner, &mint);
 
-    let token_balance_result = connection.get_token_account_balance(&token_account_address);
+    let mut close_token_account = sell_transaction.close_account;
+
+    if ctx.config.paper.enabled {
+        paper::check_position(ctx, &mongo_handler, &sell_transaction.mint, token_amount).await?;
+    } else {
+        let token_balance_result = connection.get_token_account_balance_with_commitment(
+            &token_account_address,
+            CommitmentConfig::confirmed()
+        ).await;
+        let token_balance = match token_balance_result.map(|response| response.value) {
+            Ok(token_balance) => token_balance,
+            Err(e) => {
+                // Handle the case where fetching the token balance fails
+                return Err(TradeFailure::BalanceUnavailable(e.to_string()).into());
+            }
+        };
 
-    if let Ok(token_balance) = token_balance_result {
         // Convert the token_amount to the equivalent in decimal form
         let token_amount_decimals =
             (token_amount as f64) / ((10u64).pow(token_balance.decimals as u32) as f64);
 
         // Retrieve the balance in decimal form
         let balance = token_balance.ui_amount.unwrap_or(0.0);
+        // A full exit leaves the token account empty
+        close_token_account |= token_balance.amount.parse::<u64>().ok() == Some(token_amount);
 
-        // Define a larger epsilon value to consider balances like 0.247686 as effectively zero
-        let epsilon = 1.0; // Adjusted to consider balances <= 1.0 as zero
+        // Balances like 0.247686 are dust left over from a previous sell
+        let epsilon = ctx.config.trading.pump_dust_epsilon;
 
         let buy_transaction = mongo_handler.get_buy_transaction_from_token(
             &sell_transaction.mint,
-            "solsniper",
+            &ctx.config.mongo.trades_db,
             "buy_transactions"
         ).await?;
 
@@ -126,14 +142,32 @@ pub async fn pump_fun_sell(
         println!("Buy Transaction Amount: {}", buy_transaction_amount_decimals);
 
         if balance == 0.0 {
-            match mongo_handler.is_token_sold("solsniper", "tokens", &sell_transaction.mint).await {
+            match
+                mongo_handler.is_token_sold(
+                    &ctx.config.mongo.trades_db,
+                    "tokens",
+                    &sell_transaction.mint
+                ).await
+            {
                 Ok(true) => {
-                    return Err("Token already sold".into());
+                    return Err(TradeFailure::AlreadySold.into());
                 }
                 Ok(false) => {
-                    let signature = find_sell_signature(&sell_transaction.mint).await?;
-
-                    if let Err(err) = confirm_sell(&signature, sell_transaction, true).await {
+                    let signature = find_sell_signature(
+                        &connection,
+                        &owner,
+                        &sell_transaction.mint
+                    ).await?;
+
+                    if
+                        let Err(err) = confirm_sell(
+                            ctx,
+                            wallet,
+                            &signature,
+                            sell_transaction,
+                            true
+                        ).await
+                    {
                         return Err(err.into());
                     }
                     return Ok(signature);
@@ -147,14 +181,32 @@ pub async fn pump_fun_sell(
         // 2. Check if the balance is close to zero
         if balance <= epsilon {
             // If the balance is close to zero, handle the sell signature
-            match mongo_handler.is_token_sold("solsniper", "tokens", &sell_transaction.mint).await {
+            match
+                mongo_handler.is_token_sold(
+                    &ctx.config.mongo.trades_db,
+                    "tokens",
+                    &sell_transaction.mint
+                ).await
+            {
                 Ok(true) => {
-                    return Err("Token already sold".into());
+                    return Err(TradeFailure::AlreadySold.into());
                 }
                 Ok(false) => {
-                    let signature = find_sell_signature(&sell_transaction.mint).await?;
-
-                    if let Err(err) = confirm_sell(&signature, sell_transaction, true).await {
+                    let signature = find_sell_signature(
+                        &connection,
+                        &owner,
+                        &sell_transaction.mint
+                    ).await?;
+
+                    if
+                        let Err(err) = confirm_sell(
+                            ctx,
+                            wallet,
+                            &signature,
+                            sell_transaction,
+                            true
+                        ).await
+                    {
                         return Err(err.into());
                     }
                     return Ok(signature);
@@ -168,143 +220,201 @@ pub async fn pump_fun_sell(
         // 1. Check if the balance is sufficient to sell the requested amount
         if balance < token_amount_decimals {
             if buy_transaction_amount_decimals > token_amount_decimals {
-                let signature = find_sell_signature(&sell_transaction.mint).await?;
-
-                if let Err(err) = confirm_sell(&signature, sell_transaction, true).await {
+                let signature = find_sell_signature(
+                    &connection,
+                    &owner,
+                    &sell_transaction.mint
+                ).await?;
+
+                if
+                    let Err(err) = confirm_sell(
+                        ctx,
+                        wallet,
+                        &signature,
+                        sell_transaction,
+                        true
+                    ).await
+                {
                     return Err(err.into());
                 }
-                return Err("Token amount does not match the buy transaction".into());
+                return Err(TradeFailure::AmountMismatch.into());
             }
 
             return Err(
-                format!(
-                    "Insufficient balance. Attempting to sell {} tokens, but only {} tokens are available.",
-                    token_amount_decimals,
-                    balance
+                TradeFailure::InsufficientBalance(
+                    format!(
+                        "Attempting to sell {} tokens, but only {} tokens are available.",
+                        token_amount_decimals,
+                        balance
+                    )
                 ).into()
             );
         }
+    }
 
-        // 3. Proceed with the normal sell process if none of the above conditions are met
-        let mut instructions = vec![];
+    // 3. Proceed with the normal sell process if none of the above conditions are met
+    let mut instructions = vec![];
+
+    if connection.get_account(&token_account_address).await.is_err() {
+        let create_account_instruction = create_associated_token_account(
+            &payer.pubkey(),
+            &payer.pubkey(),
+            &mint,
+            &Pubkey::from_str(TOKEN_PROGRAM_ID).unwrap()
+        );
+        instructions.push(create_account_instruction);
+    }
 
-        if connection.get_account(&token_account_address).is_err() {
-            let create_account_instruction = create_associated_token_account(
-                &payer.pubkey(),
-                &payer.pubkey(),
-                &mint,
-                &Pubkey::from_str(TOKEN_PROGRAM_ID).unwrap()
+    for _ in 0..ctx.config.trading.pump_max_retries {
+        let curve = get_bonding_curve(&connection, &mint).await?;
+
+        let sol_out = sell_quote(
+            token_amount,
+            curve.state.virtual_token_reserves,
+            curve.state.virtual_sol_reserves
+        );
+        let min_sol_output = apply_slippage(sol_out, slippage_pct);
+
+        let sol_out_f64 = (sol_out as f64) / 1_000_000_000.0;
+        dbg!(sol_out_f64);
+
+        let keys = vec![
+            AccountMeta::new_readonly(Pubkey::from_str(GLOBAL).unwrap(), false),
+            AccountMeta::new(Pubkey::from_str(FEE_RECIPIENT).unwrap(), false),
+            AccountMeta::new_readonly(mint, false),
+            AccountMeta::new(curve.address, false),
+            AccountMeta::new(curve.associated_bonding_curve, false),
+            AccountMeta::new(token_account_address, false),
+            AccountMeta::new(owner, true),
+            AccountMeta::new_readonly(system_program::ID, false),
+            AccountMeta::new_readonly(Pubkey::from_str(ASSOCIATED_TOKEN_PROGRAM)?, false),
+            AccountMeta::new_readonly(Pubkey::from_str(TOKEN_PROGRAM)?, false),
+            AccountMeta::new_readonly(Pubkey::from_str(EVENT_AUTHORITY)?, false),
+            AccountMeta::new_readonly(Pubkey::from_str(PUMP_FUN_PROGRAM)?, false)
+        ];
+
+        let data = sell_instruction_data(token_amount, min_sol_output);
+
+        let instruction = Instruction {
+            program_id: Pubkey::from_str(PUMP_FUN_PROGRAM).unwrap(),
+            accounts: keys,
+            data,
+        };
+
+        instructions.push(instruction);
+
+        if close_token_account {
+            // The sell empties the token account, so reclaim its rent
+            instructions.push(
+                spl_token::instruction::close_account(
+                    &spl_token::ID,
+                    &token_account_address,
+                    &owner,
+                    &owner,
+                    &[]
+                )?
             );
-            instructions.push(create_account_instruction);
         }
 
-        let max_retries = 4;
-        for _ in 0..max_retries {
-            let coin_data = match get_coin_data(mint_str).await {
-                Ok(data) => data,
-                Err(_) => {
-                    return Err("Failed to retrieve coin data...".into());
-                }
-            };
-
-            let virtual_token_reserves = coin_data.virtual_token_reserves as u128;
-            let virtual_sol_reserves = coin_data.virtual_sol_reserves as u128;
-            let token_amount = token_amount as u128;
-
-            // Calculate SOL output with u128
-            let sol_out = (token_amount * virtual_sol_reserves) / virtual_token_reserves;
-
-            // Calculate minimum SOL received with slippage using integer arithmetic
-            let slippage_multiplier =
-                1_000_000_000u128 + ((slippage_decimal * 1_000_000_000.0) as u128);
-            let min_sol_received = (sol_out * slippage_multiplier) / 1_000_000_000;
-
-            // Convert back to u64 safely
-            let min_sol_received_u64: u64 = min_sol_received.try_into().map_err(|_| "Overflow")?;
-
-            let sol_out_f64 = (sol_out as f64) / 1_000_000_000.0;
-            dbg!(sol_out_f64);
-
-            let keys = vec![
-                AccountMeta::new_readonly(Pubkey::from_str(GLOBAL).unwrap(), false),
-                AccountMeta::new(Pubkey::from_str(FEE_RECIPIENT).unwrap(), false),
-                AccountMeta::new_readonly(mint, false),
-                AccountMeta::new(Pubkey::from_str(&coin_data.bonding_curve).unwrap(), false),
-                AccountMeta::new(
-                    Pubkey::from_str(&coin_data.associated_bonding_curve).unwrap(),
-                    false
-                ),
-                AccountMeta::new(token_account_address, false),
-                AccountMeta::new(owner, true),
-                AccountMeta::new_readonly(system_program::ID, false),
-                AccountMeta::new_readonly(Pubkey::from_str(ASSOCIATED_TOKEN_PROGRAM)?, false),
-                AccountMeta::new_readonly(Pubkey::from_str(TOKEN_PROGRAM)?, false),
-                AccountMeta::new_readonly(Pubkey::from_str(EVENT_AUTHORITY)?, false),
-                AccountMeta::new_readonly(Pubkey::from_str(PUMP_FUN_PROGRAM)?, false)
-            ];
-
-            let sell: u64 = 12502976635542562355; // Replace with your specific instruction data
-            let mut data = vec![];
-            data.extend_from_slice(&sell.to_le_bytes());
-            data.extend_from_slice(&token_amount.to_le_bytes());
-            data.extend_from_slice(&min_sol_received_u64.to_le_bytes());
-
-            let instruction = Instruction {
-                program_id: Pubkey::from_str(PUMP_FUN_PROGRAM).unwrap(),
-                accounts: keys,
-                data,
-            };
-
-            instructions.push(instruction);
-
-            match create_transaction(instructions.clone(), payer.insecure_clone()).await {
-                Ok(tx) => {
-                    confirm_sell(&tx, sell_transaction, true).await?;
-                    return Ok(tx);
-                }
-                Err(_e) => {
-                    instructions.clear(); // Clear instructions to recalculate in the next iteration
-                }
+        if ctx.config.paper.enabled {
+            let signature = paper::simulate(ctx, wallet, &instructions).await?;
+            store_sell(
+                ctx,
+                wallet,
+                &signature,
+                sell_transaction,
+                sol_out_f64,
+                paper::network_fee(ctx),
+                true
+            ).await?;
+            return Ok(signature);
+        }
+
+        if ctx.config.trading.simulate_before_send {
+            simulation::preflight(ctx, wallet, &instructions).await?;
+        }
+
+        match create_transaction(ctx, wallet, instructions.clone()).await {
+            Ok(tx) => {
+                confirm_sell(ctx, wallet, &tx, sell_transaction, true).await?;
+                return Ok(tx);
+            }
+            Err(_e) => {
+                instructions.clear(); // Clear instructions to recalculate in the next iteration
             }
         }
-        return Err("Failed to create transaction after retries".into());
-    } else {
-        // Handle the case where fetching the token balance fails
-        return Err("Failed to fetch token balance".into());
     }
+    return Err(TradeFailure::RetriesExhausted.into());
 }
 
-// Struct for CoinData
-#[derive(Deserialize)]
-struct CoinData {
+/// SOL the bonding curve pays for `token_amount` tokens, after the protocol fee, in lamports.
+pub fn sell_quote(
+    token_amount: u64,
     virtual_token_reserves: u64,
-    virtual_sol_reserves: u64,
-    bonding_curve: String,
-    associated_bonding_curve: String,
+    virtual_sol_reserves: u64
+) -> u64 {
+    let token_amount = token_amount as u128;
+    let virtual_token_reserves = virtual_token_reserves as u128;
+    let virtual_sol_reserves = virtual_sol_reserves as u128;
+    if virtual_token_reserves + token_amount == 0 {
+        return 0;
+    }
+
+    let sol_out = (token_amount * virtual_sol_reserves) / (virtual_token_reserves + token_amount);
+    let fee = (sol_out * (PUMP_FEE_BASIS_POINTS as u128)) / 10_000;
+    (sol_out - fee) as u64
 }
 
-// Fetch coin data
-async fn get_coin_data(mint_str: &str) -> Result<CoinData, Box<dyn std::error::Error>> {
-    let url = format!("https://frontend-api.pump.fun/coins/{}", mint_str);
-    let client = reqwest::Client::new();
-    let response = client
-        .get(&url)
-        .header(
-            USER_AGENT,
-            "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:125.0) Gecko/20100101 Firefox/125.0"
-        )
-        .header(ACCEPT, "*/*")
-        .header(ACCEPT_LANGUAGE, "en-US,en;q=0.5")
-        .header(ACCEPT_ENCODING, "gzip, deflate, br")
-        .header(REFERER, "https://www.pump.fun/")
-        .header(ORIGIN, "https://www.pump.fun")
-        .header(CONNECTION, "keep-alive")
-        .send().await?;
-
-    if response.status().is_success() {
-        let coin_data: CoinData = response.json().await?;
-        Ok(coin_data)
-    } else {
-        Err(format!("Failed to retrieve coin data: {}", response.status()).into())
+/// Anchor `sell` instruction data: discriminator, token amount, minimum SOL output.
+pub fn sell_instruction_data(token_amount: u64, min_sol_output: u64) -> Vec<u8> {
+    let mut data = Vec::with_capacity(24);
+    data.extend_from_slice(&SELL_DISCRIMINATOR);
+    data.extend_from_slice(&token_amount.to_le_bytes());
+    data.extend_from_slice(&min_sol_output.to_le_bytes());
+    data
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sell_instruction_data_layout() {
+        let data = sell_instruction_data(1_000_000, 25_000);
+
+        assert_eq!(data.len(), 24);
+        assert_eq!(&data[..8], &[51, 230, 133, 164, 1, 127, 131, 173]);
+        assert_eq!(&data[8..16], &(1_000_000u64).to_le_bytes());
+        assert_eq!(&data[16..], &(25_000u64).to_le_bytes());
+    }
+
+    #[test]
+    fn sell_discriminator_matches_legacy_constant() {
+        assert_eq!(SELL_DISCRIMINATOR, (12502976635542562355u64).to_le_bytes());
+    }
+
+    #[test]
+    fn sell_quote_uses_constant_product_and_fee() {
+        // Initial curve: 1.073B tokens (6 decimals) against 30 SOL
+        let sol_out = sell_quote(1_000_000_000_000, 1_073_000_000_000_000, 30_000_000_000);
+
+        assert_eq!(sol_out, 27_653_631);
+    }
+
+    #[test]
+    fn sell_floor_is_below_quote() {
+        let sol_out = sell_quote(1_000_000_000_000, 1_073_000_000_000_000, 30_000_000_000);
+        let floor = apply_slippage(sol_out, 80.0);
+
+        assert_eq!(floor, 5_530_726);
+        assert_eq!(
+            sell_instruction_data(1_000_000_000_000, floor)[16..],
+            (5_530_726u64).to_le_bytes()
+        );
+    }
+
+    #[test]
+    fn sell_quote_of_empty_curve_is_zero() {
+        assert_eq!(sell_quote(0, 0, 0), 0);
     }
 }
@@ -1,5 +1,4 @@
-use crate::sell::confirm::confirm_sell;
-use super::utils;
+use crate::sell::confirm::{ confirm_sell, store_sell };
 use super::mongo;
 use mongo::MongoHandler;
 use super::mongo::TokenMetadata;
@@ -9,18 +8,23 @@ use solana_sdk::signature::Signer;
 use solana_sdk::signature::Signature;
 use std::sync::Arc;
 use raydium_contract_instructions::amm_instruction as amm;
-use utils::get_liquidity_pool;
-use solana_sdk::transaction::Transaction;
+use solana_sdk::transaction::{ Transaction, TransactionError };
+use solana_sdk::instruction::InstructionError;
 use solana_client::nonblocking::rpc_client::RpcClient;
-use spl_token_client::token::TokenError;
-use solana_sdk::signature::Keypair;
+use spl_associated_token_account::instruction::create_associated_token_account_idempotent;
 use solana_client::rpc_config::RpcSendTransactionConfig;
 use serde::{ Serialize, Deserialize };
 use solana_sdk::pubkey::Pubkey;
 use std::str::FromStr;
 use solana_transaction_status::UiTransactionEncoding;
 use helius::types::*;
-use helius::Helius;
+use crate::context::AppContext;
+use crate::wallet::Wallet;
+use crate::paper;
+use crate::simulation;
+use crate::buy::raydium_sdk::LiquidityPoolKeys;
+use crate::buy::utils::get_swap_quote;
+use crate::confirmation::TradeFailure;
 use std::error::Error;
 use solana_client::rpc_client::GetConfirmedSignaturesForAddress2Config;
 use solana_sdk::commitment_config::CommitmentConfig;
@@ -34,51 +38,59 @@ pub struct SellTransaction {
     pub mint: String,
     pub current_token_price_usd: f64,
     pub current_token_price_sol: f64,
+    /// Raw tokens to sell. Ignored when `percent` is set
+    #[serde(default)]
     pub amount: u64,
+    /// Sell this percentage of the remaining position instead of `amount`
+    #[serde(default)]
+    pub percent: Option<f64>,
     pub sol_amount: f64,
     pub entry: f64,
     pub base_vault: String,
     pub quote_vault: String,
+    /// Slippage tolerance in percent
+    #[serde(default)]
+    pub slippage: Option<f64>,
+    /// Routes the sell to the wallet mapped to this user or group, like a buy
+    #[serde(default)]
+    pub user_name: Option<String>,
+    #[serde(default)]
+    pub group_title: Option<String>,
+    /// Close the token account in the same transaction; set when the sell empties it
+    #[serde(skip)]
+    pub close_account: bool,
 }
 
+/// Default for `trading.sell_slippage`, in percent
+pub const DEFAULT_SELL_SLIPPAGE: f64 = 15.0;
+
+/// Sells through the Raydium pool `pool_info` that the venue was resolved to.
 pub async fn sell_swap(
+    ctx: &AppContext,
+    wallet: &Wallet,
+    pool_info: &LiquidityPoolKeys,
     sell_transaction: &SellTransaction
 ) -> Result<Signature, Box<dyn std::error::Error>> {
-    let api_key: String = std::env
-        ::var("HELIUS_API_KEY")
-        .expect("You must set the PRIVATE_KEY environment variable!");
-    let cluster: Cluster = Cluster::MainnetBeta;
-    let helius: Helius = Helius::new(&api_key, cluster).unwrap();
-    let private_key = std::env
-        ::var("PRIVATE_KEY")
-        .expect("You must set the PRIVATE_KEY environment variable!");
-    let keypair = Keypair::from_base58_string(&private_key);
-
-    let rpc_endpoint = std::env
-        ::var("RPC_URL")
-        .expect("You must set the RPC_URL environment variable!");
-
-    let client = Arc::new(RpcClient::new(rpc_endpoint.to_string()));
+    let helius = &ctx.helius;
+    let client = ctx.rpc_client.clone();
 
     let program_client: Arc<dyn ProgramClient<ProgramRpcClientSendTransaction>> = Arc::new(
         ProgramRpcClient::new(client.clone(), ProgramRpcClientSendTransaction)
     );
 
-    let mongo_handler = MongoHandler::new().await.map_err(|err| {
-        format!("Error creating MongoDB handler: {:?}", err)
-    })?;
+    let mongo_handler = MongoHandler::new(ctx.mongo.clone(), &ctx.config.mongo, wallet);
 
     let out_token: Pubkey = Pubkey::from_str(
         "So11111111111111111111111111111111111111112"
     ).unwrap();
 
-    // Clone the keypair to be able to use it multiple times
-    let keypair_arc = Arc::new(keypair);
+    let keypair_arc = wallet.keypair.clone();
+    let mint = Pubkey::from_str(&sell_transaction.mint)?;
 
     let in_token_client = Token::new(
         Arc::clone(&program_client),
         &spl_token::ID,
-        &Pubkey::from_str(&sell_transaction.mint).unwrap(),
+        &mint,
         None,
         keypair_arc.clone()
     );
@@ -92,102 +104,130 @@ pub async fn sell_swap(
 
     let user = keypair_arc.pubkey();
 
-    let pool_info = match
-        get_liquidity_pool(client.clone(), &Pubkey::from_str(&sell_transaction.mint).unwrap()).await
-    {
-        Ok(Some(info)) => info,
-        Ok(None) => {
-            dbg!("Pool info not found for the given tokens.");
-            return Err("Pool info not found for the given tokens.".into());
-        }
-        Err(e) => {
-            return Err(e.into());
-        }
-    };
-
     // Get the user's ATA. We don't try to create it as it is expected to exist.
     let user_in_token_account = in_token_client.get_associated_token_address(&user);
     dbg!("User input-tokens ATA={}", user_in_token_account);
-    let user_in_acct = in_token_client.get_account_info(&user_in_token_account).await?;
+    let user_out_token_account = out_token_client.get_associated_token_address(&user);
+    dbg!("User's output-tokens ATA={}", user_out_token_account);
 
-    const MIN_BALANCE_THRESHOLD: u64 = 500_000; // Adjust this value based on your specific precision needs
-    let balance = user_in_acct.base.amount;
+    let mut close_token_account = sell_transaction.close_account;
 
-    // 1. Check if the balance is sufficient to perform the swap
-    if balance < sell_transaction.amount {
-        return Err(
-            format!(
-                "Insufficient balance. Attempting to swap {} tokens, but only {} tokens are available.",
-                sell_transaction.amount,
-                balance
-            ).into()
-        );
-    }
-
-    // 2. Check if the balance is close to zero
-    if balance <= MIN_BALANCE_THRESHOLD {
-        // If the balance is close to zero, handle the sell signature
-        match mongo_handler.is_token_sold("solsniper", "tokens", &sell_transaction.mint).await {
-            Ok(true) => {
-                return Err("Token already sold".into());
-            }
-            Ok(false) => {
-                let signature = find_sell_signature(&sell_transaction.mint).await?;
+    if ctx.config.paper.enabled {
+        paper::check_position(
+            ctx,
+            &mongo_handler,
+            &sell_transaction.mint,
+            sell_transaction.amount
+        ).await?;
+    } else {
+        let user_in_acct = in_token_client.get_account_info(&user_in_token_account).await?;
+
+        let balance = user_in_acct.base.amount;
+        // A full exit leaves the token account empty
+        close_token_account |= balance == sell_transaction.amount;
+
+        // 1. Check if the balance is sufficient to perform the swap
+        if balance < sell_transaction.amount {
+            return Err(
+                TradeFailure::InsufficientBalance(
+                    format!(
+                        "Attempting to swap {} tokens, but only {} tokens are available.",
+                        sell_transaction.amount,
+                        balance
+                    )
+                ).into()
+            );
+        }
 
-                if let Err(err) = confirm_sell(&signature, sell_transaction, false).await {
+        // 2. Check if the balance is close to zero
+        if balance <= ctx.config.trading.min_balance_threshold {
+            // If the balance is close to zero, handle the sell signature
+            match
+                mongo_handler.is_token_sold(
+                    &ctx.config.mongo.trades_db,
+                    "tokens",
+                    &sell_transaction.mint
+                ).await
+            {
+                Ok(true) => {
+                    return Err(TradeFailure::AlreadySold.into());
+                }
+                Ok(false) => {
+                    let signature = find_sell_signature(
+                        &client,
+                        &user,
+                        &sell_transaction.mint
+                    ).await?;
+
+                    if
+                        let Err(err) = confirm_sell(
+                            ctx,
+                            wallet,
+                            &signature,
+                            sell_transaction,
+                            false
+                        ).await
+                    {
+                        return Err(err.into());
+                    }
+                    return Ok(signature);
+                }
+                Err(err) => {
                     return Err(err.into());
                 }
-                return Ok(signature);
-            }
-            Err(err) => {
-                return Err(err.into());
             }
         }
-    }
 
-    dbg!("User input-tokens ATA balance={}", balance);
-    if in_token_client.is_native() && balance < (sell_transaction.amount as u64) {
-        let transfer_amt = (sell_transaction.amount as u64) - balance;
-        let blockhash = client.get_latest_blockhash().await?;
-        let transfer_instruction = solana_sdk::system_instruction::transfer(
-            &user,
-            &user_in_token_account,
-            transfer_amt
-        );
-        let sync_instruction = spl_token::instruction::sync_native(
-            &spl_token::ID,
-            &user_in_token_account
-        )?;
-        let tx = Transaction::new_signed_with_payer(
-            &[transfer_instruction, sync_instruction],
-            Some(&user),
-            &[&keypair_arc],
-            blockhash
-        );
-        client.send_and_confirm_transaction(&tx).await.unwrap();
-    }
-
-    let user_out_token_account = out_token_client.get_associated_token_address(&user);
-    dbg!("User's output-tokens ATA={}", user_out_token_account);
-    match out_token_client.get_account_info(&user_out_token_account).await {
-        Ok(_) => {
-            dbg!("User's ATA for output tokens exists. Skipping creation.");
-        }
-        Err(TokenError::AccountNotFound) | Err(TokenError::AccountInvalidOwner) => {
-            dbg!("User's output-tokens ATA does not exist. Creating..");
-            out_token_client.create_associated_token_account(&user).await?;
-        }
-        Err(err) => {
-            // Changed variable name to 'err'
-            return Err(err.into()); // Return the error to handle it properly
+        dbg!("User input-tokens ATA balance={}", balance);
+        if in_token_client.is_native() && balance < (sell_transaction.amount as u64) {
+            let transfer_amt = (sell_transaction.amount as u64) - balance;
+            let blockhash = client.get_latest_blockhash().await?;
+            let transfer_instruction = solana_sdk::system_instruction::transfer(
+                &user,
+                &user_in_token_account,
+                transfer_amt
+            );
+            let sync_instruction = spl_token::instruction::sync_native(
+                &spl_token::ID,
+                &user_in_token_account
+            )?;
+            let tx = Transaction::new_signed_with_payer(
+                &[transfer_instruction, sync_instruction],
+                Some(&user),
+                &[&keypair_arc],
+                blockhash
+            );
+            client.send_and_confirm_transaction(&tx).await.unwrap();
         }
     }
 
-    let mut instructions = vec![];
+    // The wSOL account may have been closed by another sell or the sweeper at any point, so it
+    // is (re)created in the swap transaction itself
+    let mut instructions = vec![
+        create_associated_token_account_idempotent(&user, &user, &out_token, &spl_token::ID)
+    ];
 
     let swap_amount_in = sell_transaction.amount;
 
-    let min_amount_out = 0;
+    let slippage = sell_transaction.slippage.unwrap_or(ctx.config.trading.sell_slippage);
+    let (expected_out, min_amount_out) = get_swap_quote(
+        &client,
+        &pool_info.id,
+        &mint,
+        swap_amount_in,
+        &slippage
+    ).await?;
+    println!("Expected SOL out: {}, minimum: {}", expected_out, min_amount_out);
+
+    if min_amount_out == 0 {
+        return Err(
+            format!(
+                "Refusing to sell {} tokens of {}: the pool quotes no SOL out",
+                swap_amount_in,
+                sell_transaction.mint
+            ).into()
+        );
+    }
 
     dbg!("Initializing swap with input tokens as pool base token");
     let swap_instruction = amm::swap_base_in(
@@ -214,9 +254,53 @@ pub async fn sell_swap(
     )?;
     instructions.push(swap_instruction);
 
-    let max_retries = 3;
+    if close_token_account {
+        // The swap empties the token account, so reclaim its rent
+        instructions.push(
+            spl_token::instruction::close_account(
+                &spl_token::ID,
+                &user_in_token_account,
+                &user,
+                &user,
+                &[]
+            )?
+        );
+    }
+
+    if ctx.config.trading.unwrap_wsol {
+        // Closing the wSOL account pays its whole balance, proceeds included, out as SOL
+        instructions.push(
+            spl_token::instruction::close_account(
+                &spl_token::ID,
+                &user_out_token_account,
+                &user,
+                &user,
+                &[]
+            )?
+        );
+    }
+
+    if ctx.config.paper.enabled {
+        let signature = paper::simulate(ctx, wallet, &instructions).await?;
+        store_sell(
+            ctx,
+            wallet,
+            &signature,
+            sell_transaction,
+            (expected_out as f64) / 1_000_000_000.0,
+            paper::network_fee(ctx),
+            false
+        ).await?;
+        return Ok(signature);
+    }
+
+    if ctx.config.trading.simulate_before_send {
+        simulation::preflight(ctx, wallet, &instructions).await?;
+    }
+
+    let max_retries = ctx.config.trading.max_retries;
     let retry_delay = tokio::time::Duration::from_secs(1);
-    let mut retry_count: i32 = 0;
+    let mut retry_count = 0;
 
     loop {
         // Create the SmartTransactionConfig
@@ -236,7 +320,13 @@ pub async fn sell_swap(
             },
         };
 
-        match helius.send_smart_transaction_with_tip(config, Some(600000), Some("NY")).await {
+        match
+            helius.send_smart_transaction_with_tip(
+                config,
+                Some(ctx.config.jito.tip_lamports),
+                Some(ctx.config.jito.region())
+            ).await
+        {
             Ok(signature) => {
                 dbg!("Transaction sent successfully: {}", &signature);
                 let mut confirmed = false;
@@ -245,12 +335,20 @@ pub async fn sell_swap(
                     match
                         client.get_transaction(&signature, UiTransactionEncoding::JsonParsed).await
                     {
-                        Ok(_confirmed_transaction) => {
+                        Ok(confirmed_transaction) => {
+                            if
+                                let Some(err) = confirmed_transaction.transaction.meta
+                                    .as_ref()
+                                    .and_then(|meta| meta.err.clone())
+                            {
+                                return Err(sell_failure(&signature, err, min_amount_out).into());
+                            }
                             if
                                 let Err(err) = confirm_sell(
+                                    ctx,
+                                    wallet,
                                     &signature,
                                     sell_transaction,
-
                                     false
                                 ).await
                             {
@@ -275,20 +373,29 @@ pub async fn sell_swap(
                 if confirmed {
                     return Ok(signature);
                 } else {
-                    return Err("Transaction not confirmed after max retries".into());
+                    return Err(TradeFailure::NotConfirmed(max_retries).into());
                 }
             }
             Err(e) => {
                 match
-                    mongo_handler.is_token_sold("solsniper", "tokens", &sell_transaction.mint).await
+                    mongo_handler.is_token_sold(
+                        &ctx.config.mongo.trades_db,
+                        "tokens",
+                        &sell_transaction.mint
+                    ).await
                 {
                     Ok(true) => {
-                        let signature = find_sell_signature(&sell_transaction.mint).await?;
+                        let signature = find_sell_signature(
+                            &client,
+                            &user,
+                            &sell_transaction.mint
+                        ).await?;
                         if
                             let Err(err) = confirm_sell(
+                                ctx,
+                                wallet,
                                 &signature,
                                 sell_transaction,
-
                                 false
                             ).await
                         {
@@ -314,13 +421,26 @@ pub async fn sell_swap(
     }
 }
 
-pub async fn find_sell_signature(mint_address: &str) -> Result<Signature, Box<dyn Error>> {
-    let rpc_endpoint = std::env
-        ::var("RPC_URL")
-        .expect("You must set the RPC_URL environment variable!");
-    let client = RpcClient::new(rpc_endpoint);
-    let wallet = "4VZscJb8n2iiV4VyUSeaXPFw53mqqwH55BX6JRFaveia";
+/// Describes a sell that landed but failed, calling out the price floor when the pool
+/// rejected it for slippage.
+fn sell_failure(signature: &Signature, err: TransactionError, min_amount_out: u64) -> TradeFailure {
+    match err {
+        TransactionError::InstructionError(_, InstructionError::Custom(code)) if
+            code == simulation::RAYDIUM_EXCEEDED_SLIPPAGE
+        =>
+            TradeFailure::SellSlippageExceeded {
+                signature: *signature,
+                min_amount_out,
+            },
+        err => TradeFailure::SellFailed { signature: *signature, err },
+    }
+}
 
+pub async fn find_sell_signature(
+    client: &RpcClient,
+    wallet: &Pubkey,
+    mint_address: &str
+) -> Result<Signature, Box<dyn Error>> {
     let config = GetConfirmedSignaturesForAddress2Config {
         before: None,
         until: None,
@@ -329,7 +449,7 @@ pub async fn find_sell_signature(mint_address: &str) -> Result<Signature, Box<dy
     };
     // Fetch the confirmed signatures for the address
     let confirmed_signatures = client.get_signatures_for_address_with_config(
-        &Pubkey::from_str(wallet).unwrap(),
+        wallet,
         config
     ).await?;
 
@@ -1,23 +1,15 @@
 use solana_sdk::pubkey::Pubkey;
 use serde::{ Deserialize, Serialize };
-use solana_transaction_status::EncodedConfirmedTransactionWithStatusMeta;
-use solana_client::{
-    nonblocking::rpc_client::RpcClient,
-    rpc_filter::{ RpcFilterType, Memcmp, MemcmpEncodedBytes },
-    rpc_config::{ RpcProgramAccountsConfig, RpcAccountInfoConfig },
+use solana_transaction_status::option_serializer::OptionSerializer;
+use solana_transaction_status::{
+    EncodedConfirmedTransactionWithStatusMeta,
+    UiTransactionStatusMeta,
+    UiTransactionTokenBalance,
 };
-use solana_sdk::commitment_config::CommitmentConfig;
-use solana_account_decoder::UiAccountEncoding;
-use std::str::FromStr;
-use std::sync::Arc;
+use std::collections::{ BTreeMap, BTreeSet };
 use std::error::Error;
 
-#[derive(Serialize, Deserialize)]
-struct MinimalMarketLayoutV3 {
-    event_queue: Pubkey,
-    bids: Pubkey,
-    asks: Pubkey,
-}
+const WSOL_MINT: &str = "So11111111111111111111111111111111111111112";
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct LiquidityStateV4 {
@@ -36,308 +28,206 @@ pub struct LiquidityStateV4 {
     pub lp_vault: Pubkey,
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-pub struct LiquidityPoolKeysV4 {
-    pub id: Pubkey,
-    pub base_mint: Pubkey,
-    pub quote_mint: Pubkey,
-    pub lp_mint: Pubkey,
-    pub base_decimals: u8,
-    pub quote_decimals: u8,
-    pub lp_decimals: u8,
-    pub version: u8,
-    pub program_id: Pubkey,
-    pub authority: Pubkey,
-    pub open_orders: Pubkey,
-    pub target_orders: Pubkey,
-    pub base_vault: Pubkey,
-    pub quote_vault: Pubkey,
-    pub market_version: u8,
-    pub market_program_id: Pubkey,
-    pub market_id: Pubkey,
-    pub market_authority: Pubkey,
-    pub market_base_vault: Pubkey,
-    pub market_quote_vault: Pubkey,
-    pub market_bids: Pubkey,
-    pub market_asks: Pubkey,
-    pub market_event_queue: Pubkey,
-    pub withdraw_queue: Pubkey,
-    pub lp_vault: Pubkey,
-    pub lookup_table_account: Pubkey,
-}
-
-pub async fn get_program_account(
-    client: Arc<RpcClient>,
-    mint: &Pubkey
-) -> Result<Option<(Pubkey, solana_sdk::account::Account)>, Box<dyn Error>> {
-    const INPUT_MINT_OFFSET: usize = 400;
-    const OUTPUT_MINT_OFFSET: usize = 432;
-    const PROGRAM_ID: &str = "675kPX9MHTjS2zt1qfr1NYHuzeLXfQM9H24wFSUt1Mp8";
-
-    dbg!(&mint);
-
-    // Define the common filters
-    let common_filters = vec![RpcFilterType::DataSize(752)];
-
-    // Define the filters for "sol-token"
-    let sol_token_filters = common_filters
-        .iter()
-        .chain(
-            vec![
-                RpcFilterType::Memcmp(
-                    Memcmp::new(OUTPUT_MINT_OFFSET, MemcmpEncodedBytes::Base58(mint.to_string()))
-                ),
-                RpcFilterType::Memcmp(
-                    Memcmp::new(
-                        INPUT_MINT_OFFSET,
-                        MemcmpEncodedBytes::Base58(
-                            "So11111111111111111111111111111111111111112".to_string()
-                        )
-                    )
-                )
-            ].iter()
-        )
-        .cloned()
-        .collect::<Vec<_>>();
+/// SOL the sell paid the wallet, in SOL. `tip_lamports` is the Jito tip the bot sent with it.
+pub fn calculate_sol_amount_received(
+    tx: &EncodedConfirmedTransactionWithStatusMeta,
+    owner: &Pubkey,
+    tip_lamports: u64
+) -> Result<f64, Box<dyn Error>> {
+    let meta = tx.transaction.meta.as_ref().ok_or("No meta found in the transaction")?;
+    let lamports = sell_proceeds(meta, &owner.to_string(), tip_lamports)?;
 
-    // Define the filters for "token-sol"
-    let token_sol_filters = common_filters
-        .iter()
-        .chain(
-            vec![
-                RpcFilterType::Memcmp(
-                    Memcmp::new(INPUT_MINT_OFFSET, MemcmpEncodedBytes::Base58(mint.to_string()))
-                ),
-                RpcFilterType::Memcmp(
-                    Memcmp::new(
-                        OUTPUT_MINT_OFFSET,
-                        MemcmpEncodedBytes::Base58(
-                            "So11111111111111111111111111111111111111112".to_string()
-                        )
-                    )
-                )
-            ].iter()
-        )
-        .cloned()
-        .collect::<Vec<_>>();
+    // Convert lamports to SOL (1 SOL = 1_000_000_000 lamports)
+    Ok((lamports.max(0) as f64) / 1_000_000_000.0)
+}
 
-    // Function to fetch accounts based on filters
-    async fn fetch_accounts(
-        client: Arc<RpcClient>,
-        filters: Vec<RpcFilterType>
-    ) -> Result<Option<(Pubkey, solana_sdk::account::Account)>, Box<dyn Error>> {
-        let accounts = client.get_program_accounts_with_config(
-            &Pubkey::from_str(PROGRAM_ID)?,
-            RpcProgramAccountsConfig {
-                filters: Some(filters),
-                account_config: RpcAccountInfoConfig {
-                    encoding: Some(UiAccountEncoding::Base64),
-                    ..Default::default()
-                },
-                ..Default::default()
-            }
-        ).await;
+/// Lamports a sell paid `owner`, read from the owner's own accounts only.
+///
+/// That is the fee payer's native delta with the fee and the tip added back, plus whatever
+/// moved into or out of the owner's token accounts: wSOL left in them is proceeds, while the
+/// rent a closed token account hands back to the wallet is not. Balances of the pool, the
+/// bonding curve, fee recipients and tip accounts are never looked at.
+fn sell_proceeds(
+    meta: &UiTransactionStatusMeta,
+    owner: &str,
+    tip_lamports: u64
+) -> Result<i128, Box<dyn Error>> {
+    let pre_balances = &meta.pre_balances;
+    let post_balances = &meta.post_balances;
 
-        match accounts {
-            Ok(accounts) => Ok(accounts.into_iter().next()),
-            Err(e) => Err(Box::new(e)),
-        }
+    if pre_balances.len() != post_balances.len() {
+        return Err("Pre and post balances length mismatch".into());
     }
-
-    // Try fetching accounts with " token_sol_filters" filters first
-    if let Some(account) = fetch_accounts(client.clone(), token_sol_filters).await? {
-        return Ok(Some(account));
+    let (Some(pre_native), Some(post_native)) = (pre_balances.first(), post_balances.first()) else {
+        return Err("No fee payer balance in the transaction".into());
+    };
+
+    let native =
+        (*post_native as i128) -
+        (*pre_native as i128) +
+        (meta.fee as i128) +
+        (tip_lamports as i128);
+
+    let pre_tokens = owned_token_balances(&meta.pre_token_balances, owner);
+    let post_tokens = owned_token_balances(&meta.post_token_balances, owner);
+    let accounts: BTreeSet<usize> = pre_tokens.keys().chain(post_tokens.keys()).copied().collect();
+
+    let mut wsol = 0i128;
+    let mut rent = 0i128;
+    for index in accounts {
+        let lamports = |balances: &[u64]| balances.get(index).copied().unwrap_or_default() as i128;
+        let wsol_pre = pre_tokens.get(&index).copied().flatten().unwrap_or_default() as i128;
+        let wsol_post = post_tokens.get(&index).copied().flatten().unwrap_or_default() as i128;
+
+        wsol += wsol_post - wsol_pre;
+        // Whatever else the account's lamports did is rent: refunded to the wallet when it is
+        // closed, paid by the wallet when it is created
+        rent += lamports(post_balances) - wsol_post - (lamports(pre_balances) - wsol_pre);
     }
 
-    // If no account found, try fetching with "token-sol" filters
-    fetch_accounts(client, sol_token_filters).await
+    Ok(native + rent + wsol)
 }
 
-async fn get_minimal_market_v3(client: &RpcClient, market_id: Pubkey) -> MinimalMarketLayoutV3 {
-    let market_info = client
-        .get_account_with_commitment(&market_id, CommitmentConfig::finalized()).await
-        .unwrap();
+/// Token accounts of `owner` by account index, with their raw wSOL amount if they hold wSOL.
+fn owned_token_balances(
+    balances: &OptionSerializer<Vec<UiTransactionTokenBalance>>,
+    owner: &str
+) -> BTreeMap<usize, Option<u64>> {
+    let OptionSerializer::Some(balances) = balances else {
+        return BTreeMap::new();
+    };
 
-    let minimal_market_layout_v3: MinimalMarketLayoutV3 = bincode
-        ::deserialize(&market_info.value.unwrap().data)
-        .unwrap();
-    minimal_market_layout_v3
-}
-
-fn create_pool_keys(
-    id: Pubkey,
-    pool_state: MyAccountData,
-    minimal_market_layout_v3: MinimalMarketLayoutV3
-) -> LiquidityPoolKeysV4 {
-    LiquidityPoolKeysV4 {
-        id,
-        base_mint: pool_state.base_mint,
-        quote_mint: pool_state.quote_mint,
-        lp_mint: pool_state.lp_mint,
-        base_decimals: pool_state.base_decimal as u8,
-        quote_decimals: pool_state.quote_decimal as u8,
-        lp_decimals: 5,
-        version: 4,
-        program_id: Pubkey::from_str("675kPX9MHTjS2zt1qfr1NYHuzeLXfQM9H24wFSUt1Mp8").unwrap(),
-        authority: Pubkey::from_str("5Q544fKrFoe6tsEbD7S8EmxGTJYAKtTVhAW5Q5pge4j1").unwrap(),
-        open_orders: pool_state.open_orders,
-        target_orders: pool_state.target_orders,
-        base_vault: pool_state.base_vault,
-        quote_vault: pool_state.quote_vault,
-        market_version: 3,
-        market_program_id: pool_state.market_program_id,
-        market_id: pool_state.market_id,
-        market_authority: get_associated_authority(
-            &pool_state.market_program_id,
-            &pool_state.market_id
-        ).unwrap(),
-        market_base_vault: pool_state.base_vault,
-        market_quote_vault: pool_state.quote_vault,
-        market_bids: minimal_market_layout_v3.bids,
-        market_asks: minimal_market_layout_v3.asks,
-        market_event_queue: minimal_market_layout_v3.event_queue,
-        withdraw_queue: pool_state.withdraw_queue,
-        lp_vault: pool_state.lp_vault,
-        lookup_table_account: Pubkey::default(),
-    }
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-struct MyAccountData {
-    status: u64,
-    nonce: u64,
-    max_order: u64,
-    depth: u64,
-    base_decimal: u64,
-    quote_decimal: u64,
-    state: u64,
-    reset_flag: u64,
-    min_size: u64,
-    vol_max_cut_ratio: u64,
-    amount_wave_ratio: u64,
-    base_lot_size: u64,
-    quote_lot_size: u64,
-    min_price_multiplier: u64,
-    max_price_multiplier: u64,
-    system_decimal_value: u64,
-    min_separate_numerator: u64,
-    min_separate_denominator: u64,
-    trade_fee_numerator: u64,
-    trade_fee_denominator: u64,
-    pnl_numerator: u64,
-    pnl_denominator: u64,
-    swap_fee_numerator: u64,
-    swap_fee_denominator: u64,
-    base_need_take_pnl: u64,
-    quote_need_take_pnl: u64,
-    quote_total_pnl: u64,
-    base_total_pnl: u64,
-    pool_open_time: u64,
-    punish_pc_amount: u64,
-    punish_coin_amount: u64,
-    orderbook_to_init_time: u64,
-    swap_base_in_amount: u128,
-    swap_quote_out_amount: u128,
-    swap_base2quote_fee: u64,
-    swap_quote_in_amount: u128,
-    swap_base_out_amount: u128,
-    swap_quote2base_fee: u64,
-    base_vault: Pubkey,
-    quote_vault: Pubkey,
-    base_mint: Pubkey,
-    quote_mint: Pubkey,
-    lp_mint: Pubkey,
-    open_orders: Pubkey,
-    market_id: Pubkey,
-    market_program_id: Pubkey,
-    target_orders: Pubkey,
-    withdraw_queue: Pubkey,
-    lp_vault: Pubkey,
-    owner: Pubkey,
-    lp_reserve: u64,
-    padding: [u64; 3],
+    balances
+        .iter()
+        .filter(|balance| matches!(&balance.owner, OptionSerializer::Some(o) if o == owner))
+        .map(|balance| {
+            let wsol = (balance.mint == WSOL_MINT)
+                .then(|| balance.ui_token_amount.amount.parse::<u64>().ok())
+                .flatten();
+            (balance.account_index as usize, wsol)
+        })
+        .collect()
 }
 
-pub async fn get_liquidity_pool(
-    client: Arc<RpcClient>,
-    mint: &Pubkey
-) -> Result<Option<LiquidityPoolKeysV4>, Box<dyn Error>> {
-    match get_program_account(client.clone(), mint).await {
-        Ok(Some(account)) => {
-            let pool_state: MyAccountData = bincode
-                ::deserialize(&account.1.data)
-                .expect("Failed to deserialize data");
-
-            let minimal_market_layout_v3 = get_minimal_market_v3(
-                &client,
-                pool_state.market_id
-            ).await;
-            let pool_keys = create_pool_keys(account.0, pool_state, minimal_market_layout_v3);
-
-            Ok(Some(pool_keys))
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use solana_account_decoder::parse_token::UiTokenAmount;
+
+    const OWNER: &str = "7xKXtg2CW87d97TXJSDpbD5jBkheTqA83TZRuJosgAsU";
+    const MINT: &str = "4k3Dyjzvzp8eMZWUXbBCjEvwSkkk59S5iCNLY3QrkX6R";
+    const FEE: u64 = 5_000;
+    const TIP: u64 = 10_000;
+    const RENT: u64 = 2_039_280;
+    const WALLET: u64 = 1_000_000_000;
+
+    fn token_balance(index: u8, mint: &str, amount: u64) -> UiTransactionTokenBalance {
+        UiTransactionTokenBalance {
+            account_index: index,
+            mint: mint.to_string(),
+            ui_token_amount: UiTokenAmount {
+                ui_amount: None,
+                decimals: 6,
+                amount: amount.to_string(),
+                ui_amount_string: String::new(),
+            },
+            owner: OptionSerializer::Some(OWNER.to_string()),
+            program_id: OptionSerializer::Some(spl_token::id().to_string()),
         }
-        Ok(None) => { Ok(None) }
-        Err(e) => { Err(e) }
     }
-}
-
-pub fn get_associated_authority(
-    program_id: &Pubkey,
-    market_id: &Pubkey
-) -> std::result::Result<Pubkey, String> {
-    let market_id_bytes = market_id.to_bytes();
-    let seeds = &[&market_id_bytes[..]];
-
-    for nonce in 0..100u8 {
-        let nonce_bytes = [nonce];
-        let padding = [0u8; 7];
 
-        let seeds_with_nonce = [
-            seeds[0], // Market ID bytes
-            &nonce_bytes, // Nonce bytes
-            &padding, // Padding bytes
-        ];
-
-        match Pubkey::create_program_address(&seeds_with_nonce, program_id) {
-            Ok(public_key) => {
-                return Ok(public_key);
-            }
-            Err(_) => {
-                continue;
-            }
+    fn meta(
+        balances: Vec<(u64, u64)>,
+        pre_tokens: Vec<UiTransactionTokenBalance>,
+        post_tokens: Vec<UiTransactionTokenBalance>
+    ) -> UiTransactionStatusMeta {
+        UiTransactionStatusMeta {
+            err: None,
+            status: Ok(()),
+            fee: FEE,
+            pre_balances: balances.iter().map(|(pre, _)| *pre).collect(),
+            post_balances: balances.iter().map(|(_, post)| *post).collect(),
+            inner_instructions: OptionSerializer::None,
+            log_messages: OptionSerializer::None,
+            pre_token_balances: OptionSerializer::Some(pre_tokens),
+            post_token_balances: OptionSerializer::Some(post_tokens),
+            rewards: OptionSerializer::None,
+            loaded_addresses: OptionSerializer::Skip,
+            return_data: OptionSerializer::Skip,
+            compute_units_consumed: OptionSerializer::Skip,
         }
     }
 
-    Err("Unable to find a valid program address".into())
-}
-
-pub async fn calculate_sol_amount_received(
-    tx: &EncodedConfirmedTransactionWithStatusMeta,
-    _rpc_client: &Arc<RpcClient>,
-    _mint: &Pubkey
-) -> Result<f64, Box<dyn std::error::Error>> {
-    let meta = tx.transaction.meta.as_ref().ok_or("No meta found in the transaction")?;
-
-    // Get the pre and post balances from the meta
-    let pre_balances = &meta.pre_balances;
-    let post_balances = &meta.post_balances;
-
-    if pre_balances.len() != post_balances.len() {
-        return Err("Pre and post balances length mismatch".into());
+    #[test]
+    fn plain_sell_counts_only_the_wallet() {
+        // pump.fun sell of 1M tokens for 0.5 SOL; the curve, fee recipient and tip account
+        // move too but are not the wallet's
+        let meta = meta(
+            vec![
+                (WALLET, WALLET + 500_000_000 - FEE - TIP),
+                (RENT, RENT),
+                (80_000_000_000, 79_495_000_000),
+                (1_000_000, 6_000_000),
+                (0, TIP)
+            ],
+            vec![token_balance(1, MINT, 1_000_000)],
+            vec![token_balance(1, MINT, 0)]
+        );
+
+        assert_eq!(sell_proceeds(&meta, OWNER, TIP).unwrap(), 500_000_000);
     }
 
-    // Iterate through balances to find the difference
-    let mut sol_amount_received: f64 = 0.0;
-
-    for (pre_balance, post_balance) in pre_balances.iter().zip(post_balances.iter()) {
-        if post_balance > pre_balance {
-            let difference = post_balance - pre_balance;
-            sol_amount_received += difference as f64;
-        }
+    #[test]
+    fn closed_token_account_rent_is_not_proceeds() {
+        let meta = meta(
+            vec![
+                (WALLET, WALLET + 500_000_000 + RENT - FEE - TIP),
+                (RENT, 0),
+                (80_000_000_000, 79_495_000_000),
+                (1_000_000, 6_000_000),
+                (0, TIP)
+            ],
+            vec![token_balance(1, MINT, 1_000_000)],
+            vec![]
+        );
+
+        assert_eq!(sell_proceeds(&meta, OWNER, TIP).unwrap(), 500_000_000);
     }
 
-    // Convert lamports to SOL (1 SOL = 1_000_000_000 lamports)
-    let sol_amount = sol_amount_received / 1_000_000_000.0;
+    #[test]
+    fn unwrapped_wsol_is_proceeds() {
+        // Raydium sell into an existing wSOL account that is closed in the same transaction,
+        // unwrapping 0.7 SOL and refunding its rent
+        let meta = meta(
+            vec![
+                (WALLET, WALLET + 700_000_000 + RENT - FEE - TIP),
+                (RENT, RENT),
+                (RENT, 0),
+                (90_000_000_000, 89_300_000_000),
+                (0, TIP)
+            ],
+            vec![token_balance(1, MINT, 1_000_000), token_balance(2, WSOL_MINT, 0)],
+            vec![token_balance(1, MINT, 0)]
+        );
+
+        assert_eq!(sell_proceeds(&meta, OWNER, TIP).unwrap(), 700_000_000);
+    }
 
-    Ok(sol_amount)
+    #[test]
+    fn wsol_left_wrapped_is_proceeds() {
+        // wSOL account created by the sell and left open: its rent is a cost, not a loss
+        let meta = meta(
+            vec![
+                (WALLET, WALLET - RENT - FEE - TIP),
+                (RENT, RENT),
+                (0, RENT + 700_000_000),
+                (90_000_000_000, 89_300_000_000),
+                (0, TIP)
+            ],
+            vec![token_balance(1, MINT, 1_000_000)],
+            vec![token_balance(1, MINT, 0), token_balance(2, WSOL_MINT, 700_000_000)]
+        );
+
+        assert_eq!(sell_proceeds(&meta, OWNER, TIP).unwrap(), 700_000_000);
+    }
 }
@@ -0,0 +1,39 @@
+/// How long in-flight trades get to finish confirmation and persistence after a stop signal.
+pub const DEFAULT_SHUTDOWN_DEADLINE_SECS: u64 = 120;
+
+/// How long the runtime waits for its remaining tasks once the drain is over. Trades still
+/// running by then are the ones the drain already reported as unresolved.
+pub const RUNTIME_GRACE: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Resolves on the first SIGINT or SIGTERM.
+pub async fn wait_for_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("Failed to listen for SIGINT: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{ signal, SignalKind };
+
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                eprintln!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = interrupt => println!("Received SIGINT"),
+        _ = terminate => println!("Received SIGTERM"),
+    }
+}
@@ -0,0 +1,460 @@
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{ Aead, AeadCore, KeyInit, OsRng };
+use aes_gcm::{ Aes256Gcm, Key, Nonce };
+use serde::{ Deserialize, Serialize };
+use solana_sdk::signature::{ Keypair, Signer };
+use std::str::FromStr;
+use zeroize::Zeroizing;
+
+/// Keystore file format written by `encrypt-keystore`.
+const KEYSTORE_VERSION: u32 = 1;
+
+/// scrypt cost for new keystores: N = 2^15, r = 8, p = 1 (about 32 MiB and a fraction of a second).
+const SCRYPT_LOG_N: u8 = 15;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+const SALT_LEN: usize = 32;
+
+pub const DEFAULT_SIGNER_ENV_VAR: &str = "PRIVATE_KEY";
+pub const DEFAULT_PASSWORD_ENV_VAR: &str = "KEYSTORE_PASSWORD";
+
+#[derive(Debug, thiserror::Error)]
+pub enum SignerError {
+    #[error("Environment variable {var} is not set")] MissingEnv {
+        var: String,
+    },
+    #[error("Failed to read key file {path}: {source}")] Read {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Failed to write key file {path}: {source}")] Write {
+        path: String,
+        source: std::io::Error,
+    },
+    #[error("Key file {path} is malformed: {reason}")] Format {
+        path: String,
+        reason: String,
+    },
+    #[error("Failed to decrypt keystore {path}, wrong password or corrupted file")] Decrypt {
+        path: String,
+    },
+    #[error("{origin} does not hold a valid keypair")] InvalidKey {
+        origin: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SignerBackend {
+    /// Base58 keypair in an environment variable
+    Env,
+    /// Password-encrypted keystore file written by `encrypt-keystore`
+    Keystore,
+    /// JSON byte array as written by `solana-keygen`
+    SolanaCli,
+}
+
+impl FromStr for SignerBackend {
+    type Err = ();
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "env" => Ok(SignerBackend::Env),
+            "keystore" => Ok(SignerBackend::Keystore),
+            "solana_cli" => Ok(SignerBackend::SolanaCli),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Where a wallet's keypair is loaded from.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignerConfig {
+    pub backend: SignerBackend,
+    /// Variable holding the base58 keypair for the `env` backend
+    pub env_var: String,
+    /// Key file for the `keystore` and `solana_cli` backends
+    pub path: Option<String>,
+    /// Variable holding the password for the `keystore` backend
+    pub password_env: String,
+}
+
+impl Default for SignerConfig {
+    fn default() -> Self {
+        Self {
+            backend: SignerBackend::Env,
+            env_var: DEFAULT_SIGNER_ENV_VAR.to_string(),
+            path: None,
+            password_env: DEFAULT_PASSWORD_ENV_VAR.to_string(),
+        }
+    }
+}
+
+impl SignerConfig {
+    /// Where the key comes from, for logs. Never includes key material.
+    pub fn describe(&self) -> String {
+        let path = self.path.as_deref().unwrap_or_default();
+        match self.backend {
+            SignerBackend::Env => format!("env:{}", self.env_var),
+            SignerBackend::Keystore => format!("keystore:{}", path),
+            SignerBackend::SolanaCli => format!("solana_cli:{}", path),
+        }
+    }
+
+    /// Loads the keypair from the configured backend.
+    ///
+    /// Intermediate key material is zeroized once the `Keypair` is built; the `Keypair` itself
+    /// wipes its secret on drop.
+    pub fn load(&self) -> Result<Keypair, SignerError> {
+        let path = self.path.as_deref().unwrap_or_default();
+        match self.backend {
+            SignerBackend::Env => load_env(&self.env_var),
+            SignerBackend::Keystore => load_keystore(path, &self.password_env),
+            SignerBackend::SolanaCli => load_solana_cli(path),
+        }
+    }
+}
+
+/// Base58 keypair in the environment variable `var`.
+fn load_env(var: &str) -> Result<Keypair, SignerError> {
+    let origin = format!("env:{}", var);
+    let encoded = Zeroizing::new(
+        std::env::var(var).map_err(|_| SignerError::MissingEnv { var: var.to_string() })?
+    );
+    let bytes = Zeroizing::new(
+        bs58
+            ::decode(encoded.as_str())
+            .into_vec()
+            .map_err(|_| SignerError::InvalidKey { origin: origin.clone() })?
+    );
+
+    keypair_from_bytes(&bytes, &origin)
+}
+
+/// JSON byte array keypair file as written by `solana-keygen`.
+fn load_solana_cli(path: &str) -> Result<Keypair, SignerError> {
+    let contents = Zeroizing::new(read_file(path)?);
+    let bytes: Zeroizing<Vec<u8>> = Zeroizing::new(
+        serde_json::from_str(&contents).map_err(|e| SignerError::Format {
+            path: path.to_string(),
+            reason: e.to_string(),
+        })?
+    );
+
+    keypair_from_bytes(&bytes, &format!("solana_cli:{}", path))
+}
+
+/// Keystore written by `encrypt-keystore`, unlocked with the password in `password_env`.
+fn load_keystore(path: &str, password_env: &str) -> Result<Keypair, SignerError> {
+    let password = read_password(password_env)?;
+    let contents = read_file(path)?;
+    let keystore: KeystoreFile = serde_json::from_str(&contents).map_err(|e| {
+        SignerError::Format { path: path.to_string(), reason: e.to_string() }
+    })?;
+
+    let keypair = keystore.decrypt(password.as_bytes(), path)?;
+    if keypair.pubkey().to_string() != keystore.pubkey {
+        return Err(SignerError::Format {
+            path: path.to_string(),
+            reason: format!("decrypted key does not match pubkey {}", keystore.pubkey),
+        });
+    }
+
+    Ok(keypair)
+}
+
+/// On-disk keystore: the 64 keypair bytes sealed with AES-256-GCM under an scrypt-derived key.
+/// Binary fields are base58 encoded.
+#[derive(Serialize, Deserialize)]
+struct KeystoreFile {
+    version: u32,
+    /// Public key of the sealed keypair, readable without the password
+    pubkey: String,
+    kdf: ScryptParams,
+    nonce: String,
+    ciphertext: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct ScryptParams {
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: String,
+}
+
+impl KeystoreFile {
+    fn encrypt(keypair: &Keypair, password: &[u8]) -> Result<Self, SignerError> {
+        Self::encrypt_with_cost(keypair, password, SCRYPT_LOG_N)
+    }
+
+    fn encrypt_with_cost(
+        keypair: &Keypair,
+        password: &[u8],
+        log_n: u8
+    ) -> Result<Self, SignerError> {
+        let mut salt = [0u8; SALT_LEN];
+        OsRng.fill_bytes(&mut salt);
+        let kdf = ScryptParams {
+            log_n,
+            r: SCRYPT_R,
+            p: SCRYPT_P,
+            salt: bs58::encode(salt).into_string(),
+        };
+
+        let key = kdf.derive_key(password, &salt, "new keystore")?;
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_slice()));
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let plaintext = Zeroizing::new(keypair.to_bytes());
+        let ciphertext = cipher
+            .encrypt(&nonce, plaintext.as_slice())
+            .map_err(|_| SignerError::InvalidKey { origin: "new keystore".to_string() })?;
+
+        Ok(Self {
+            version: KEYSTORE_VERSION,
+            pubkey: keypair.pubkey().to_string(),
+            kdf,
+            nonce: bs58::encode(nonce).into_string(),
+            ciphertext: bs58::encode(ciphertext).into_string(),
+        })
+    }
+
+    fn decrypt(&self, password: &[u8], path: &str) -> Result<Keypair, SignerError> {
+        if self.version != KEYSTORE_VERSION {
+            return Err(SignerError::Format {
+                path: path.to_string(),
+                reason: format!("unsupported version {}", self.version),
+            });
+        }
+
+        let salt = decode_field(&self.kdf.salt, "kdf.salt", path)?;
+        let nonce = decode_field(&self.nonce, "nonce", path)?;
+        let ciphertext = decode_field(&self.ciphertext, "ciphertext", path)?;
+        if nonce.len() != 12 {
+            return Err(SignerError::Format {
+                path: path.to_string(),
+                reason: "nonce must be 12 bytes".to_string(),
+            });
+        }
+
+        let key = self.kdf.derive_key(password, &salt, path)?;
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key.as_slice()));
+        let plaintext = Zeroizing::new(
+            cipher
+                .decrypt(Nonce::from_slice(&nonce), ciphertext.as_slice())
+                .map_err(|_| SignerError::Decrypt { path: path.to_string() })?
+        );
+
+        keypair_from_bytes(&plaintext, &format!("keystore:{}", path))
+    }
+}
+
+impl ScryptParams {
+    fn derive_key(
+        &self,
+        password: &[u8],
+        salt: &[u8],
+        path: &str
+    ) -> Result<Zeroizing<[u8; 32]>, SignerError> {
+        let params = scrypt::Params
+            ::new(self.log_n, self.r, self.p, 32)
+            .map_err(|e| SignerError::Format {
+                path: path.to_string(),
+                reason: format!("invalid scrypt parameters: {}", e),
+            })?;
+
+        let mut key = Zeroizing::new([0u8; 32]);
+        scrypt::scrypt(password, salt, &params, key.as_mut_slice()).map_err(|e| {
+            SignerError::Format { path: path.to_string(), reason: e.to_string() }
+        })?;
+
+        Ok(key)
+    }
+}
+
+/// `encrypt-keystore <solana-cli-keypair.json> <keystore.json>`: seals a Solana CLI keypair file
+/// with the password in `KEYSTORE_PASSWORD`.
+pub fn encrypt_keystore_command(args: &[String]) -> Result<(), SignerError> {
+    let [source, destination] = args else {
+        return Err(SignerError::Format {
+            path: "arguments".to_string(),
+            reason: "usage: encrypt-keystore <solana-cli-keypair.json> <keystore.json>".to_string(),
+        });
+    };
+
+    let keypair = load_solana_cli(source)?;
+    let password = read_password(DEFAULT_PASSWORD_ENV_VAR)?;
+    let keystore = KeystoreFile::encrypt(&keypair, password.as_bytes())?;
+
+    let contents = serde_json::to_string_pretty(&keystore).unwrap_or_default();
+    std::fs::write(destination, contents).map_err(|source| SignerError::Write {
+        path: destination.clone(),
+        source,
+    })?;
+
+    println!("Wrote keystore for {} to {}", keypair.pubkey(), destination);
+    Ok(())
+}
+
+fn read_password(var: &str) -> Result<Zeroizing<String>, SignerError> {
+    std::env
+        ::var(var)
+        .map(Zeroizing::new)
+        .map_err(|_| SignerError::MissingEnv { var: var.to_string() })
+}
+
+fn read_file(path: &str) -> Result<String, SignerError> {
+    std::fs::read_to_string(path).map_err(|source| SignerError::Read {
+        path: path.to_string(),
+        source,
+    })
+}
+
+fn decode_field(value: &str, field: &str, path: &str) -> Result<Vec<u8>, SignerError> {
+    bs58::decode(value)
+        .into_vec()
+        .map_err(|_| SignerError::Format {
+            path: path.to_string(),
+            reason: format!("{} is not base58", field),
+        })
+}
+
+fn keypair_from_bytes(bytes: &[u8], source: &str) -> Result<Keypair, SignerError> {
+    Keypair::from_bytes(bytes).map_err(|_| SignerError::InvalidKey { origin: source.to_string() })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Cheap scrypt cost so the tests don't spend seconds deriving keys
+    const TEST_LOG_N: u8 = 4;
+
+    fn temp_path(name: &str) -> String {
+        std::env
+            ::temp_dir()
+            .join(format!("signer-{}-{}.json", std::process::id(), name))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    fn tamper(encoded: &str, edit: impl FnOnce(&mut Vec<u8>)) -> String {
+        let mut bytes = bs58::decode(encoded).into_vec().unwrap();
+        edit(&mut bytes);
+        bs58::encode(bytes).into_string()
+    }
+
+    #[test]
+    fn keystore_round_trips_the_keypair() {
+        let keypair = Keypair::new();
+        let keystore = KeystoreFile::encrypt_with_cost(&keypair, b"hunter2", TEST_LOG_N).unwrap();
+
+        let decrypted = keystore.decrypt(b"hunter2", "test").unwrap();
+
+        assert_eq!(decrypted.to_bytes(), keypair.to_bytes());
+        assert_eq!(keystore.pubkey, keypair.pubkey().to_string());
+    }
+
+    #[test]
+    fn wrong_password_is_rejected() {
+        let keystore = KeystoreFile::encrypt_with_cost(
+            &Keypair::new(),
+            b"hunter2",
+            TEST_LOG_N
+        ).unwrap();
+
+        let result = keystore.decrypt(b"hunter3", "test");
+
+        assert!(matches!(result, Err(SignerError::Decrypt { .. })));
+    }
+
+    #[test]
+    fn tampered_or_truncated_ciphertext_is_rejected() {
+        let mut keystore = KeystoreFile::encrypt_with_cost(
+            &Keypair::new(),
+            b"hunter2",
+            TEST_LOG_N
+        ).unwrap();
+        let original = keystore.ciphertext.clone();
+
+        keystore.ciphertext = tamper(&original, |bytes| {
+            bytes[0] ^= 0x01;
+        });
+        assert!(matches!(keystore.decrypt(b"hunter2", "test"), Err(SignerError::Decrypt { .. })));
+
+        keystore.ciphertext = tamper(&original, |bytes| bytes.truncate(bytes.len() - 1));
+        assert!(matches!(keystore.decrypt(b"hunter2", "test"), Err(SignerError::Decrypt { .. })));
+    }
+
+    #[test]
+    fn keystore_backend_loads_the_sealed_keypair() {
+        let keypair = Keypair::new();
+        let keystore = KeystoreFile::encrypt_with_cost(&keypair, b"hunter2", TEST_LOG_N).unwrap();
+        let path = temp_path("keystore");
+        std::fs::write(&path, serde_json::to_string(&keystore).unwrap()).unwrap();
+        std::env::set_var("SIGNER_TEST_KEYSTORE_PASSWORD", "hunter2");
+
+        let loaded = load_keystore(&path, "SIGNER_TEST_KEYSTORE_PASSWORD");
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(loaded.unwrap().to_bytes(), keypair.to_bytes());
+    }
+
+    #[test]
+    fn keystore_backend_rejects_a_mismatched_pubkey() {
+        let mut keystore = KeystoreFile::encrypt_with_cost(
+            &Keypair::new(),
+            b"hunter2",
+            TEST_LOG_N
+        ).unwrap();
+        keystore.pubkey = Keypair::new().pubkey().to_string();
+        let path = temp_path("mismatched");
+        std::fs::write(&path, serde_json::to_string(&keystore).unwrap()).unwrap();
+        std::env::set_var("SIGNER_TEST_MISMATCHED_PASSWORD", "hunter2");
+
+        let loaded = load_keystore(&path, "SIGNER_TEST_MISMATCHED_PASSWORD");
+        std::fs::remove_file(&path).ok();
+
+        assert!(matches!(loaded, Err(SignerError::Format { .. })));
+    }
+
+    #[test]
+    fn solana_cli_backend_loads_a_json_byte_array() {
+        let keypair = Keypair::new();
+        let path = temp_path("solana-cli");
+        let contents = serde_json::to_string(&keypair.to_bytes().to_vec()).unwrap();
+        std::fs::write(&path, contents).unwrap();
+
+        let loaded = load_solana_cli(&path);
+        std::fs::remove_file(&path).ok();
+
+        assert_eq!(loaded.unwrap().to_bytes(), keypair.to_bytes());
+    }
+
+    #[test]
+    fn solana_cli_backend_rejects_malformed_arrays() {
+        let cases = [
+            ("not-json", "[1, 2, 3"),
+            ("not-bytes", "[256, 0, 0]"),
+            ("not-array", "{\"key\": [1, 2, 3]}"),
+        ];
+        for (name, contents) in cases {
+            let path = temp_path(name);
+            std::fs::write(&path, contents).unwrap();
+
+            let loaded = load_solana_cli(&path);
+            std::fs::remove_file(&path).ok();
+
+            assert!(matches!(loaded, Err(SignerError::Format { .. })), "{} was accepted", name);
+        }
+
+        let path = temp_path("short");
+        std::fs::write(&path, "[1, 2, 3]").unwrap();
+        let loaded = load_solana_cli(&path);
+        std::fs::remove_file(&path).ok();
+
+        assert!(matches!(loaded, Err(SignerError::InvalidKey { .. })));
+    }
+}
@@ -0,0 +1,237 @@
+use crate::context::AppContext;
+use crate::wallet::Wallet;
+use solana_client::rpc_config::RpcSimulateTransactionConfig;
+use solana_sdk::commitment_config::CommitmentConfig;
+use solana_sdk::instruction::{ Instruction, InstructionError };
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signer;
+use solana_sdk::system_program;
+use solana_sdk::transaction::{ Transaction, TransactionError };
+use std::str::FromStr;
+
+const RAYDIUM_AMM_PROGRAM: &str = "675kPX9MHTjS2zt1qfr1NYHuzeLXfQM9H24wFSUt1Mp8";
+const PUMP_FUN_PROGRAM: &str = "6EF8rrecthR5Dkzon8Nwu78hRvfCKubJ14M5uBEwF6P";
+
+/// Raydium AMM v4 `ExceededSlippage`
+pub const RAYDIUM_EXCEEDED_SLIPPAGE: u32 = 30;
+
+/// Why a transaction would fail, decoded from its simulation.
+#[derive(Debug, thiserror::Error)]
+pub enum SimulationError {
+    #[error("Simulation failed: slippage exceeded on {program}")] SlippageExceeded {
+        program: &'static str,
+    },
+    #[error("Simulation failed: insufficient funds in {program}")] InsufficientFunds {
+        program: &'static str,
+    },
+    #[error("Simulation failed: bonding curve complete, the token has migrated")]
+    BondingCurveComplete,
+    #[error("Simulation failed: {program} error {code} ({name})")] Program {
+        program: &'static str,
+        code: u32,
+        name: &'static str,
+    },
+    #[error("Simulation failed: {0}")] Transaction(String),
+    #[error("Simulation request failed: {0}")] Rpc(String),
+}
+
+/// Simulates `instructions` signed by `wallet` and returns the decoded reason if they would fail.
+pub async fn preflight(
+    ctx: &AppContext,
+    wallet: &Wallet,
+    instructions: &[Instruction]
+) -> Result<(), SimulationError> {
+    let blockhash = ctx.rpc_client
+        .get_latest_blockhash().await
+        .map_err(|e| SimulationError::Rpc(e.to_string()))?;
+    let transaction = Transaction::new_signed_with_payer(
+        instructions,
+        Some(&wallet.pubkey()),
+        &[&*wallet.keypair],
+        blockhash
+    );
+
+    let config = RpcSimulateTransactionConfig {
+        sig_verify: false,
+        replace_recent_blockhash: true,
+        commitment: Some(CommitmentConfig::processed()),
+        ..RpcSimulateTransactionConfig::default()
+    };
+
+    let response = ctx.rpc_client
+        .simulate_transaction_with_config(&transaction, config).await
+        .map_err(|e| SimulationError::Rpc(e.to_string()))?;
+
+    match response.value.err {
+        Some(err) => {
+            let error = decode(&err, instructions);
+            eprintln!("{}", error);
+            for line in response.value.logs.unwrap_or_default() {
+                eprintln!("  {}", line);
+            }
+            Err(error)
+        }
+        None => Ok(()),
+    }
+}
+
+/// Maps a transaction error to the program that raised it and, for the programs we trade
+/// through, to what the custom error code means.
+pub fn decode(err: &TransactionError, instructions: &[Instruction]) -> SimulationError {
+    match err {
+        TransactionError::InsufficientFundsForFee | TransactionError::InsufficientFundsForRent {
+            ..
+        } => SimulationError::InsufficientFunds { program: "fee payer" },
+        TransactionError::InstructionError(index, InstructionError::Custom(code)) => {
+            let program_id = instructions.get(*index as usize).map(|ix| ix.program_id);
+            decode_custom(program_id, *code)
+        }
+        TransactionError::InstructionError(_, InstructionError::InsufficientFunds) => {
+            SimulationError::InsufficientFunds { program: "system" }
+        }
+        other => SimulationError::Transaction(other.to_string()),
+    }
+}
+
+fn decode_custom(program_id: Option<Pubkey>, code: u32) -> SimulationError {
+    let Some(program_id) = program_id else {
+        return SimulationError::Program { program: "unknown", code, name: "unknown" };
+    };
+
+    if program_id == Pubkey::from_str(RAYDIUM_AMM_PROGRAM).unwrap() {
+        match code {
+            // Also raised for swaps before the pool's open time, but not only for those
+            22 => SimulationError::Program { program: "raydium", code, name: "InvalidStatus" },
+            RAYDIUM_EXCEEDED_SLIPPAGE => SimulationError::SlippageExceeded { program: "raydium" },
+            40 => SimulationError::InsufficientFunds { program: "raydium" },
+            _ => SimulationError::Program { program: "raydium", code, name: "unknown" },
+        }
+    } else if program_id == Pubkey::from_str(PUMP_FUN_PROGRAM).unwrap() {
+        match code {
+            6002 | 6003 => SimulationError::SlippageExceeded { program: "pump_fun" },
+            6005 => SimulationError::BondingCurveComplete,
+            6000 => SimulationError::Program { program: "pump_fun", code, name: "NotAuthorized" },
+            6004 =>
+                SimulationError::Program {
+                    program: "pump_fun",
+                    code,
+                    name: "MintDoesNotMatchBondingCurve",
+                },
+            6007 => SimulationError::Program { program: "pump_fun", code, name: "NotInitialized" },
+            _ => SimulationError::Program { program: "pump_fun", code, name: "unknown" },
+        }
+    } else if program_id == spl_token::id() {
+        match code {
+            1 => SimulationError::InsufficientFunds { program: "spl_token" },
+            0 => SimulationError::Program { program: "spl_token", code, name: "NotRentExempt" },
+            3 => SimulationError::Program { program: "spl_token", code, name: "MintMismatch" },
+            4 => SimulationError::Program { program: "spl_token", code, name: "OwnerMismatch" },
+            17 => SimulationError::Program { program: "spl_token", code, name: "AccountFrozen" },
+            _ => SimulationError::Program { program: "spl_token", code, name: "unknown" },
+        }
+    } else if program_id == system_program::id() {
+        match code {
+            // SystemError::ResultWithNegativeLamports
+            1 => SimulationError::InsufficientFunds { program: "system" },
+            _ => SimulationError::Program { program: "system", code, name: "unknown" },
+        }
+    } else {
+        SimulationError::Program { program: "unknown", code, name: "unknown" }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn raydium() -> Option<Pubkey> {
+        Some(Pubkey::from_str(RAYDIUM_AMM_PROGRAM).unwrap())
+    }
+
+    fn pump_fun() -> Option<Pubkey> {
+        Some(Pubkey::from_str(PUMP_FUN_PROGRAM).unwrap())
+    }
+
+    fn program_name(error: SimulationError) -> Option<&'static str> {
+        match error {
+            SimulationError::Program { name, .. } => Some(name),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn decodes_raydium_codes() {
+        assert_eq!(program_name(decode_custom(raydium(), 22)), Some("InvalidStatus"));
+        let slippage = decode_custom(raydium(), RAYDIUM_EXCEEDED_SLIPPAGE);
+        assert!(matches!(slippage, SimulationError::SlippageExceeded { program: "raydium" }));
+        assert!(
+            matches!(decode_custom(raydium(), 40), SimulationError::InsufficientFunds {
+                program: "raydium",
+            })
+        );
+        assert_eq!(program_name(decode_custom(raydium(), 23)), Some("unknown"));
+    }
+
+    #[test]
+    fn decodes_pump_fun_codes() {
+        for code in [6002, 6003] {
+            assert!(
+                matches!(decode_custom(pump_fun(), code), SimulationError::SlippageExceeded {
+                    program: "pump_fun",
+                })
+            );
+        }
+        assert!(matches!(decode_custom(pump_fun(), 6005), SimulationError::BondingCurveComplete));
+        assert_eq!(program_name(decode_custom(pump_fun(), 6000)), Some("NotAuthorized"));
+        assert_eq!(
+            program_name(decode_custom(pump_fun(), 6004)),
+            Some("MintDoesNotMatchBondingCurve")
+        );
+        assert_eq!(program_name(decode_custom(pump_fun(), 6007)), Some("NotInitialized"));
+        assert_eq!(program_name(decode_custom(pump_fun(), 6001)), Some("unknown"));
+    }
+
+    #[test]
+    fn decodes_spl_token_codes() {
+        let token = Some(spl_token::id());
+
+        assert!(
+            matches!(decode_custom(token, 1), SimulationError::InsufficientFunds {
+                program: "spl_token",
+            })
+        );
+        assert_eq!(program_name(decode_custom(token, 0)), Some("NotRentExempt"));
+        assert_eq!(program_name(decode_custom(token, 3)), Some("MintMismatch"));
+        assert_eq!(program_name(decode_custom(token, 4)), Some("OwnerMismatch"));
+        assert_eq!(program_name(decode_custom(token, 17)), Some("AccountFrozen"));
+        assert_eq!(program_name(decode_custom(token, 2)), Some("unknown"));
+    }
+
+    #[test]
+    fn decodes_system_codes() {
+        let system = Some(system_program::id());
+
+        assert!(
+            matches!(decode_custom(system, 1), SimulationError::InsufficientFunds {
+                program: "system",
+            })
+        );
+        assert_eq!(program_name(decode_custom(system, 0)), Some("unknown"));
+    }
+
+    #[test]
+    fn custom_codes_follow_the_failing_instruction() {
+        let instructions = [
+            Instruction::new_with_bytes(system_program::id(), &[], vec![]),
+            Instruction::new_with_bytes(raydium().unwrap(), &[], vec![]),
+        ];
+        let err = TransactionError::InstructionError(1, InstructionError::Custom(30));
+
+        assert!(
+            matches!(decode(&err, &instructions), SimulationError::SlippageExceeded {
+                program: "raydium",
+            })
+        );
+        assert_eq!(program_name(decode_custom(None, 30)), Some("unknown"));
+    }
+}
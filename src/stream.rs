@@ -0,0 +1,209 @@
+use crate::config::IntakeConfig;
+use crate::context::AppContext;
+use crate::dispatch::TradeDispatcher;
+use redis::aio::MultiplexedConnection;
+use redis::streams::{
+    StreamClaimReply,
+    StreamId,
+    StreamPendingCountReply,
+    StreamReadOptions,
+    StreamReadReply,
+};
+use redis::{ AsyncCommands, RedisResult };
+use std::sync::Arc;
+use std::time::{ Duration, Instant };
+
+const READ_BATCH: usize = 10;
+const READ_BLOCK_MS: usize = 5_000;
+const CLAIM_INTERVAL: Duration = Duration::from_secs(30);
+pub const DEFAULT_CLAIM_IDLE_MS: usize = 300_000;
+
+/// Acknowledges a stream entry once its trade has reached a terminal state.
+pub struct StreamAck {
+    stream: String,
+    group: String,
+    id: String,
+}
+
+impl StreamAck {
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub async fn ack(&self, con: &mut MultiplexedConnection) -> RedisResult<()> {
+        let _: i64 = con.xack(&self.stream, &self.group, &[&self.id]).await?;
+        Ok(())
+    }
+}
+
+/// Durable trade intake that reads the `trading` stream through a consumer group.
+///
+/// Entries stay pending until the dispatcher acks them, so signals published while
+/// the bot is down are picked up on restart, and entries left behind by a crashed
+/// consumer are claimed once they have been idle for `claim_idle_ms`. Entries this consumer
+/// deferred because another consumer held their request are retried the same way.
+pub struct StreamIntake {
+    stream: String,
+    group: String,
+    consumer: String,
+    claim_idle_ms: usize,
+}
+
+impl StreamIntake {
+    pub fn from_config(config: &IntakeConfig) -> Self {
+        Self {
+            stream: config.stream.clone(),
+            group: config.stream_group.clone(),
+            consumer: config.consumer_name(),
+            claim_idle_ms: config.stream_claim_idle_ms,
+        }
+    }
+
+    pub async fn run(
+        &self,
+        ctx: Arc<AppContext>,
+        dispatcher: &TradeDispatcher
+    ) -> RedisResult<()> {
+        // Blocking reads get their own connection so they never stall the shared one
+        let mut con = ctx.redis_client.get_multiplexed_async_connection().await?;
+        self.ensure_group(&mut con).await?;
+
+        // Entries this consumer read before a restart but never acked
+        self.replay_own_pending(&mut con, dispatcher).await?;
+        self.claim_stale(&mut con, dispatcher).await?;
+        let mut last_claim = Instant::now();
+
+        loop {
+            if last_claim.elapsed() >= CLAIM_INTERVAL {
+                self.claim_stale(&mut con, dispatcher).await?;
+                last_claim = Instant::now();
+            }
+
+            let options = StreamReadOptions::default()
+                .group(&self.group, &self.consumer)
+                .count(READ_BATCH)
+                .block(READ_BLOCK_MS);
+            let reply: Option<StreamReadReply> = con.xread_options(
+                &[&self.stream],
+                &[">"],
+                &options
+            ).await?;
+
+            for entry in reply.into_iter().flat_map(|reply| reply.keys).flat_map(|key| key.ids) {
+                self.dispatch_entry(&mut con, dispatcher, entry).await;
+            }
+        }
+    }
+
+    async fn ensure_group(&self, con: &mut MultiplexedConnection) -> RedisResult<()> {
+        let created: RedisResult<()> = con.xgroup_create_mkstream(
+            &self.stream,
+            &self.group,
+            "$"
+        ).await;
+
+        match created {
+            Ok(()) => Ok(()),
+            Err(e) if e.code() == Some("BUSYGROUP") => Ok(()),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn replay_own_pending(
+        &self,
+        con: &mut MultiplexedConnection,
+        dispatcher: &TradeDispatcher
+    ) -> RedisResult<()> {
+        let mut last_id = "0".to_string();
+
+        loop {
+            let options = StreamReadOptions::default()
+                .group(&self.group, &self.consumer)
+                .count(READ_BATCH);
+            let reply: Option<StreamReadReply> = con.xread_options(
+                &[&self.stream],
+                &[&last_id],
+                &options
+            ).await?;
+
+            let entries: Vec<StreamId> = reply
+                .into_iter()
+                .flat_map(|reply| reply.keys)
+                .flat_map(|key| key.ids)
+                .collect();
+            let Some(last) = entries.last() else {
+                return Ok(());
+            };
+            last_id = last.id.clone();
+
+            for entry in entries {
+                self.dispatch_entry(con, dispatcher, entry).await;
+            }
+        }
+    }
+
+    async fn claim_stale(
+        &self,
+        con: &mut MultiplexedConnection,
+        dispatcher: &TradeDispatcher
+    ) -> RedisResult<()> {
+        let pending: StreamPendingCountReply = con.xpending_count(
+            &self.stream,
+            &self.group,
+            "-",
+            "+",
+            100
+        ).await?;
+
+        let stale_ids: Vec<String> = pending.ids
+            .into_iter()
+            .filter(|pending| {
+                pending.consumer != self.consumer || !dispatcher.is_dispatched(&pending.id)
+            })
+            .filter(|pending| pending.last_delivered_ms >= self.claim_idle_ms)
+            .map(|pending| pending.id)
+            .collect();
+
+        if stale_ids.is_empty() {
+            return Ok(());
+        }
+
+        let claimed: StreamClaimReply = con.xclaim(
+            &self.stream,
+            &self.group,
+            &self.consumer,
+            self.claim_idle_ms,
+            stale_ids.as_slice()
+        ).await?;
+
+        for entry in claimed.ids {
+            println!("Claimed stale trade entry {}", entry.id);
+            self.dispatch_entry(con, dispatcher, entry).await;
+        }
+
+        Ok(())
+    }
+
+    async fn dispatch_entry(
+        &self,
+        con: &mut MultiplexedConnection,
+        dispatcher: &TradeDispatcher,
+        entry: StreamId
+    ) {
+        let ack = StreamAck {
+            stream: self.stream.clone(),
+            group: self.group.clone(),
+            id: entry.id.clone(),
+        };
+
+        match entry.get::<String>("payload") {
+            Some(payload) => dispatcher.dispatch(payload, Some(ack)).await,
+            None => {
+                eprintln!("Stream entry {} has no payload field, dropping it", entry.id);
+                if let Err(e) = ack.ack(con).await {
+                    eprintln!("Failed to ack stream entry {}: {}", entry.id, e);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use crate::context::AppContext;
+use crate::wallet::Wallet;
+use solana_account_decoder::UiAccountData;
+use solana_client::rpc_request::TokenAccountsFilter;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::Signer;
+use solana_sdk::transaction::Transaction;
+use std::error::Error;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+const WSOL_MINT: &str = "So11111111111111111111111111111111111111112";
+
+/// Token accounts of a wallet closed by one sweep.
+#[derive(Debug, Default)]
+pub struct SweepReport {
+    pub closed: usize,
+    /// Rent returned to the wallet, in lamports
+    pub reclaimed_lamports: u64,
+    /// wSOL unwrapped into the wallet, in lamports
+    pub unwrapped_lamports: u64,
+    /// Accounts left open because their batch failed
+    pub failed: usize,
+}
+
+/// Sweeps every wallet each `sweeper.interval_secs`, starting right away.
+pub async fn run(ctx: Arc<AppContext>) {
+    let period = Duration::from_secs(ctx.config.sweeper.interval_secs);
+    let mut interval = tokio::time::interval(period);
+
+    loop {
+        interval.tick().await;
+
+        for wallet in ctx.keystore.wallets() {
+            match sweep(&ctx, wallet).await {
+                Ok(report) => log_report(wallet, &report),
+                Err(e) => eprintln!("Sweep of wallet {} failed: {}", wallet.name, e),
+            }
+        }
+    }
+}
+
+/// Closes the wallet's zero-balance token accounts in batches of `sweeper.batch_size`,
+/// returning their rent to the wallet. With `sweeper.unwrap_wsol` its wSOL accounts are closed
+/// too, which consolidates the wrapped SOL back into the wallet. wSOL is left alone while the
+/// wallet has a trade running; Raydium swaps recreate and fund the account themselves anyway.
+pub async fn sweep(ctx: &AppContext, wallet: &Wallet) -> Result<SweepReport, Box<dyn Error>> {
+    let mut report = SweepReport::default();
+
+    // Paper trades never touch the wallet's accounts
+    if ctx.config.paper.enabled {
+        return Ok(report);
+    }
+
+    let owner = wallet.pubkey();
+    let closable = closable_token_accounts(ctx, wallet).await?;
+
+    for batch in closable.chunks(ctx.config.sweeper.batch_size) {
+        let mut instructions = Vec::with_capacity(batch.len());
+        for account in batch {
+            instructions.push(
+                spl_token::instruction::close_account(
+                    &spl_token::ID,
+                    &account.address,
+                    &owner,
+                    &owner,
+                    &[]
+                )?
+            );
+        }
+
+        let blockhash = ctx.rpc_client.get_latest_blockhash().await?;
+        let transaction = Transaction::new_signed_with_payer(
+            &instructions,
+            Some(&owner),
+            &[&*wallet.keypair],
+            blockhash
+        );
+
+        match ctx.rpc_client.send_and_confirm_transaction(&transaction).await {
+            Ok(signature) => {
+                println!("Closed {} token accounts in {}", batch.len(), signature);
+                report.closed += batch.len();
+                for account in batch {
+                    report.reclaimed_lamports += account.lamports.saturating_sub(account.wrapped);
+                    report.unwrapped_lamports += account.wrapped;
+                }
+            }
+            Err(e) => {
+                // Usually an account received tokens since it was listed; the next sweep retries
+                eprintln!("Failed to close {} token accounts: {}", batch.len(), e);
+                report.failed += batch.len();
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+/// A token account the sweep can close.
+struct ClosableAccount {
+    address: Pubkey,
+    /// Everything the close returns to the wallet
+    lamports: u64,
+    /// The part of `lamports` that is wrapped SOL
+    wrapped: u64,
+}
+
+/// The wallet's SPL token accounts holding no tokens and, with `sweeper.unwrap_wsol`, its wSOL
+/// accounts. wSOL accounts are skipped while the wallet is trading.
+async fn closable_token_accounts(
+    ctx: &AppContext,
+    wallet: &Wallet
+) -> Result<Vec<ClosableAccount>, Box<dyn Error>> {
+    let unwrap_wsol = ctx.config.sweeper.unwrap_wsol;
+    let trading = wallet.is_trading();
+
+    let accounts = ctx.rpc_client.get_token_accounts_by_owner(
+        &wallet.pubkey(),
+        TokenAccountsFilter::ProgramId(spl_token::id())
+    ).await?;
+
+    let closable = accounts
+        .into_iter()
+        .filter_map(|keyed| {
+            let UiAccountData::Json(parsed) = &keyed.account.data else {
+                return None;
+            };
+            let info = &parsed.parsed["info"];
+            // Frozen accounts can't be closed
+            if info["state"].as_str() != Some("initialized") {
+                return None;
+            }
+            let is_wsol = info["mint"].as_str() == Some(WSOL_MINT);
+            if is_wsol && trading {
+                return None;
+            }
+            let amount = info["tokenAmount"]["amount"].as_str()?.parse::<u64>().ok()?;
+            // A wSOL account's token amount is the SOL it wraps; closing pays it out
+            let wrapped = if unwrap_wsol && is_wsol {
+                amount
+            } else if amount == 0 {
+                0
+            } else {
+                return None;
+            };
+
+            let address = Pubkey::from_str(&keyed.pubkey).ok()?;
+            Some(ClosableAccount {
+                address,
+                lamports: keyed.account.lamports,
+                wrapped,
+            })
+        })
+        .collect();
+
+    Ok(closable)
+}
+
+fn log_report(wallet: &Wallet, report: &SweepReport) {
+    if report.closed == 0 && report.failed == 0 {
+        return;
+    }
+    println!(
+        "Sweep of wallet {}: closed {} accounts, reclaimed {} SOL, unwrapped {} SOL, {} left open",
+        wallet.name,
+        report.closed,
+        (report.reclaimed_lamports as f64) / 1_000_000_000.0,
+        (report.unwrapped_lamports as f64) / 1_000_000_000.0,
+        report.failed
+    );
+}
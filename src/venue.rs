@@ -0,0 +1,76 @@
+use crate::buy::price::read_bonding_curve;
+use crate::buy::raydium_sdk::LiquidityPoolKeys;
+use crate::buy::utils::{ get_liquidity_pool, get_pool_open_time };
+use crate::confirmation::{ TradeFailure, Venue };
+use crate::context::AppContext;
+use solana_sdk::pubkey::Pubkey;
+use std::error::Error;
+use std::time::{ Duration, SystemTime, UNIX_EPOCH };
+
+/// Where a trade for a mint executes.
+pub enum Route {
+    Raydium(LiquidityPoolKeys),
+    PumpFun,
+}
+
+impl Route {
+    pub fn venue(&self) -> Venue {
+        match self {
+            Route::Raydium(_) => Venue::Raydium,
+            Route::PumpFun => Venue::PumpFun,
+        }
+    }
+}
+
+/// Picks the venue for `mint` from its bonding curve and Raydium pool together.
+///
+/// An active bonding curve always wins, even if a Raydium pool with the same mint exists.
+/// Once the curve is complete the token trades on Raydium; while its pool is missing or not
+/// yet open the check is repeated every `trading.migration_retry_delay_ms`, up to
+/// `trading.migration_retries` times. Only a bonding curve account that doesn't exist counts as
+/// missing; failing to read it fails resolution, since routing an active curve to Raydium
+/// would trade against the wrong venue.
+pub async fn resolve(ctx: &AppContext, mint: &Pubkey) -> Result<Route, Box<dyn Error>> {
+    let retries = ctx.config.trading.migration_retries;
+    let delay = Duration::from_millis(ctx.config.trading.migration_retry_delay_ms);
+
+    for attempt in 0..=retries {
+        let curve = read_bonding_curve(&ctx.rpc_client, mint).await?;
+        if let Some(curve) = &curve {
+            if !curve.state.complete {
+                println!("Routing {} to pump.fun: bonding curve {} is active", mint, curve.address);
+                return Ok(Route::PumpFun);
+            }
+        }
+
+        match get_liquidity_pool(ctx.rpc_client.clone(), mint).await? {
+            Some(pool) => {
+                let open_time = get_pool_open_time(&ctx.rpc_client, &pool.id).await?;
+                if open_time <= now_secs() {
+                    println!("Routing {} to Raydium pool {}", mint, pool.id);
+                    return Ok(Route::Raydium(pool));
+                }
+                println!("Raydium pool {} for {} opens at {}", pool.id, mint, open_time);
+            }
+            None if curve.is_none() => {
+                return Err(TradeFailure::PoolNotFound.into());
+            }
+            None => {
+                println!("Bonding curve for {} is complete, waiting for its Raydium pool", mint);
+            }
+        }
+
+        if attempt < retries {
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    Err(TradeFailure::MigrationPending { mint: *mint, checks: retries + 1 }.into())
+}
+
+fn now_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
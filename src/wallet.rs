@@ -0,0 +1,142 @@
+use crate::config::{ Config, ConfigError, DEFAULT_WALLET_NAME };
+use crate::signer::SignerConfig;
+use solana_sdk::pubkey::Pubkey;
+use solana_sdk::signature::{ Keypair, Signer };
+use std::collections::HashMap;
+use std::sync::atomic::{ AtomicUsize, Ordering };
+use std::sync::Arc;
+
+/// A signing wallet that trades can be routed to.
+pub struct Wallet {
+    pub name: String,
+    pub keypair: Arc<Keypair>,
+    /// The default wallet also owns positions recorded before wallets were tracked
+    pub is_default: bool,
+    /// Trades currently executing with this wallet
+    trades: AtomicUsize,
+}
+
+/// Counts a trade as running on its wallet until dropped.
+pub struct TradeGuard<'a>(&'a AtomicUsize);
+
+impl Drop for TradeGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Wallet {
+    pub fn pubkey(&self) -> Pubkey {
+        self.keypair.pubkey()
+    }
+
+    pub fn address(&self) -> String {
+        self.keypair.pubkey().to_string()
+    }
+
+    /// Marks a trade as running with this wallet until the guard is dropped.
+    pub fn begin_trade(&self) -> TradeGuard<'_> {
+        self.trades.fetch_add(1, Ordering::SeqCst);
+        TradeGuard(&self.trades)
+    }
+
+    /// Whether a buy or sell is executing with this wallet right now.
+    pub fn is_trading(&self) -> bool {
+        self.trades.load(Ordering::SeqCst) > 0
+    }
+
+    /// Redis key counting this wallet's open positions. The default wallet keeps the original key,
+    /// paper positions are counted separately.
+    pub fn buy_counter_key(&self, paper: bool) -> String {
+        let key = if self.is_default {
+            "buy_transaction_count".to_string()
+        } else {
+            format!("buy_transaction_count:{}", self.name)
+        };
+
+        if paper { format!("paper:{}", key) } else { key }
+    }
+}
+
+/// Every configured wallet, plus the `user_name` and `group_title` routing to pick between them.
+pub struct Keystore {
+    wallets: Vec<Arc<Wallet>>,
+    by_user: HashMap<String, Arc<Wallet>>,
+    by_group: HashMap<String, Arc<Wallet>>,
+}
+
+impl Keystore {
+    pub fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let default = Arc::new(Wallet {
+            name: DEFAULT_WALLET_NAME.to_string(),
+            keypair: load_keypair(DEFAULT_WALLET_NAME, &config.signer)?,
+            is_default: true,
+            trades: AtomicUsize::new(0),
+        });
+
+        if let Some(expected) = &config.wallet {
+            if *expected != default.address() {
+                return Err(ConfigError::Invalid {
+                    field: "wallet",
+                    reason: format!(
+                        "{} does not match the signer {} from [signer]",
+                        expected,
+                        default.address()
+                    ),
+                });
+            }
+        }
+
+        let mut keystore = Self {
+            wallets: vec![default],
+            by_user: HashMap::new(),
+            by_group: HashMap::new(),
+        };
+
+        for entry in &config.wallets {
+            let wallet = Arc::new(Wallet {
+                name: entry.name.clone(),
+                keypair: load_keypair(&entry.name, &entry.signer)?,
+                is_default: false,
+                trades: AtomicUsize::new(0),
+            });
+
+            for user in &entry.users {
+                keystore.by_user.insert(user.clone(), wallet.clone());
+            }
+            for group in &entry.groups {
+                keystore.by_group.insert(group.clone(), wallet.clone());
+            }
+            keystore.wallets.push(wallet);
+        }
+
+        Ok(keystore)
+    }
+
+    pub fn default_wallet(&self) -> &Arc<Wallet> {
+        &self.wallets[0]
+    }
+
+    pub fn wallets(&self) -> &[Arc<Wallet>] {
+        &self.wallets
+    }
+
+    /// Picks the wallet mapped to `user_name`, then to `group_title`, falling back to the default.
+    pub fn route(&self, user_name: Option<&str>, group_title: Option<&str>) -> Arc<Wallet> {
+        user_name
+            .and_then(|user| self.by_user.get(user))
+            .or_else(|| group_title.and_then(|group| self.by_group.get(group)))
+            .unwrap_or(self.default_wallet())
+            .clone()
+    }
+}
+
+fn load_keypair(name: &str, signer: &SignerConfig) -> Result<Arc<Keypair>, ConfigError> {
+    let keypair = signer.load().map_err(|source| ConfigError::Signer {
+        wallet: name.to_string(),
+        source,
+    })?;
+    println!("Loaded wallet {} from {}", name, signer.describe());
+
+    Ok(Arc::new(keypair))
+}